@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// 無圧縮AVI (24bit RGBのフレームをそのまま並べる) の書き出し
+// フレームレートは4194304/70224Hzをそのまま分数で書くので, 再生してもずれない
+// AVI 1.0は1GBまでなので, 超えそうになったら "_2.avi" のように次のファイルに分ける
+
+const CYCLES_PER_FRAME: u32 = 70224;
+const CYCLES_PER_SECOND: u32 = 4194304;
+
+const MAX_FILE_SIZE: u64 = 1000 * 1000 * 1000;
+
+// 後で書き直す場所 (ファイルの先頭からのオフセット)
+const RIFF_SIZE: u64 = 4;
+const AVIH_TOTAL_FRAMES: u64 = 48;
+const STRH_LENGTH: u64 = 140;
+const MOVI_SIZE: u64 = 216;
+
+pub struct AviWriter<W = BufWriter<File>> {
+    path: String,
+    part: u32,
+    width: usize,
+    height: usize,
+    open: fn(&str) -> Result<W, String>, // 分けたファイルを開く
+    file: W,
+    offset: u64, // 次に書くフレームの'movi'からのオフセット
+    index: Vec<u32>, // 各フレームの'movi'からのオフセット
+    pub frames: u64, // 全部のファイルで書いたフレーム数
+}
+
+impl AviWriter {
+    pub fn create(path: &str, width: usize, height: usize) -> Result<Self, String> {
+        AviWriter::new(path, width, height, create_file)
+    }
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    // openはファイル (2つ目からは "_2.avi" など) を開く
+    pub fn new(path: &str, width: usize, height: usize, open: fn(&str) -> Result<W, String>) -> Result<Self, String> {
+        let mut file = open(path)?;
+        write_header(&mut file, width, height).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(AviWriter {
+            path: path.to_string(),
+            part: 1,
+            width,
+            height,
+            open,
+            file,
+            offset: 4,
+            index: Vec::new(),
+            frames: 0,
+        })
+    }
+
+    // rgbはwidth*height*3バイト
+    pub fn write_frame(&mut self, rgb: &[u8]) -> Result<(), String> {
+        let size = self.frame_size();
+        if MOVI_SIZE + 4 + self.offset + 8 + size as u64 + 16 * (self.index.len() as u64 + 1) > MAX_FILE_SIZE {
+            self.next_part()?;
+        }
+        // DIBは下の行から, BGRの順に並べる
+        let mut data = Vec::with_capacity(size as usize);
+        for row in rgb.chunks(self.width * 3).rev() {
+            for pixel in row.chunks(3) {
+                data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        self.write(b"00db")?;
+        self.write(&size.to_le_bytes())?;
+        self.write(&data)?;
+        self.index.push(self.offset as u32);
+        self.offset += 8 + size as u64;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // 索引とヘッダの長さを書いて閉じる
+    pub fn finish(mut self) -> Result<W, String> {
+        self.finish_part()?;
+        Ok(self.file)
+    }
+
+    fn frame_size(&self) -> u32 {
+        ((self.width * 3).next_multiple_of(4) * self.height) as u32
+    }
+
+    fn next_part(&mut self) -> Result<(), String> {
+        self.finish_part()?;
+        self.part += 1;
+        let path = self.part_path();
+        self.file = (self.open)(&path)?;
+        write_header(&mut self.file, self.width, self.height).map_err(|e| self.error(e))?;
+        self.offset = 4;
+        self.index.clear();
+        Ok(())
+    }
+
+    fn part_path(&self) -> String {
+        match self.part {
+            1 => self.path.clone(),
+            part => match self.path.strip_suffix(".avi") {
+                Some(base) => format!("{}_{}.avi", base, part),
+                None => format!("{}_{}", self.path, part),
+            },
+        }
+    }
+
+    fn finish_part(&mut self) -> Result<(), String> {
+        let size = self.frame_size();
+        let mut index = Vec::with_capacity(8 + 16 * self.index.len());
+        index.extend_from_slice(b"idx1");
+        index.extend_from_slice(&(16 * self.index.len() as u32).to_le_bytes());
+        for &offset in &self.index {
+            index.extend_from_slice(b"00db");
+            index.extend_from_slice(&0x10u32.to_le_bytes()); // AVIIF_KEYFRAME
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&size.to_le_bytes());
+        }
+        self.write(&index)?;
+
+        let frames = self.index.len() as u32;
+        let end = MOVI_SIZE + 4 + self.offset + index.len() as u64;
+        self.patch(RIFF_SIZE, end as u32 - 8)?;
+        self.patch(AVIH_TOTAL_FRAMES, frames)?;
+        self.patch(STRH_LENGTH, frames)?;
+        self.patch(MOVI_SIZE, self.offset as u32)?;
+        self.file.flush().map_err(|e| self.error(e))
+    }
+
+    fn patch(&mut self, position: u64, value: u32) -> Result<(), String> {
+        self.file.seek(SeekFrom::Start(position)).map_err(|e| self.error(e))?;
+        self.write(&value.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0)).map_err(|e| self.error(e))?;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.file.write_all(data).map_err(|e| self.error(e))
+    }
+
+    fn error(&self, e: std::io::Error) -> String {
+        format!("{}: {}", self.part_path(), e)
+    }
+}
+
+fn create_file(path: &str) -> Result<BufWriter<File>, String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(BufWriter::new(file))
+}
+
+// 長さが決まっていないところは0のままヘッダを書く
+fn write_header(file: &mut impl Write, width: usize, height: usize) -> std::io::Result<()> {
+    let frame_size = ((width * 3).next_multiple_of(4) * height) as u32;
+    let mut header = Vec::new();
+    let u32le = |header: &mut Vec<u8>, value: u32| header.extend_from_slice(&value.to_le_bytes());
+
+    header.extend_from_slice(b"RIFF");
+    u32le(&mut header, 0);
+    header.extend_from_slice(b"AVI LIST");
+    u32le(&mut header, 192);
+    header.extend_from_slice(b"hdrlavih");
+    u32le(&mut header, 56);
+    u32le(&mut header, (CYCLES_PER_FRAME as u64 * 1_000_000 / CYCLES_PER_SECOND as u64) as u32);
+    u32le(&mut header, (frame_size as u64 * CYCLES_PER_SECOND as u64 / CYCLES_PER_FRAME as u64) as u32);
+    u32le(&mut header, 0);
+    u32le(&mut header, 0x10); // AVIF_HASINDEX
+    u32le(&mut header, 0); // フレーム数
+    u32le(&mut header, 0);
+    u32le(&mut header, 1); // ストリーム数
+    u32le(&mut header, frame_size + 8);
+    u32le(&mut header, width as u32);
+    u32le(&mut header, height as u32);
+    header.extend_from_slice(&[0; 16]);
+
+    header.extend_from_slice(b"LIST");
+    u32le(&mut header, 116);
+    header.extend_from_slice(b"strlstrh");
+    u32le(&mut header, 56);
+    header.extend_from_slice(b"vids");
+    u32le(&mut header, 0); // 無圧縮
+    u32le(&mut header, 0);
+    u32le(&mut header, 0); // 優先度, 言語
+    u32le(&mut header, 0);
+    u32le(&mut header, CYCLES_PER_FRAME); // dwScale
+    u32le(&mut header, CYCLES_PER_SECOND); // dwRate
+    u32le(&mut header, 0);
+    u32le(&mut header, 0); // フレーム数
+    u32le(&mut header, frame_size + 8);
+    u32le(&mut header, 0xFFFF_FFFF); // 品質 (既定)
+    u32le(&mut header, 0);
+    header.extend_from_slice(&[0, 0, 0, 0]);
+    header.extend_from_slice(&(width as u16).to_le_bytes());
+    header.extend_from_slice(&(height as u16).to_le_bytes());
+
+    // BITMAPINFOHEADER
+    header.extend_from_slice(b"strf");
+    u32le(&mut header, 40);
+    u32le(&mut header, 40);
+    u32le(&mut header, width as u32);
+    u32le(&mut header, height as u32); // 正なら下の行から
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&24u16.to_le_bytes());
+    u32le(&mut header, 0); // BI_RGB
+    u32le(&mut header, frame_size);
+    header.extend_from_slice(&[0; 16]);
+
+    header.extend_from_slice(b"LIST");
+    u32le(&mut header, 0);
+    header.extend_from_slice(b"movi");
+
+    debug_assert_eq!(header.len() as u64, MOVI_SIZE + 8);
+    file.write_all(&header)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(data: &[u8], position: usize) -> u32 {
+        u32::from_le_bytes(data[position..position + 4].try_into().unwrap())
+    }
+
+    // 書いたフレーム数と各チャンクの長さがfinishでヘッダに書き込まれる
+    #[test]
+    fn test_write_frames() {
+        const FRAMES: usize = 3;
+        let mut avi = AviWriter::new("test.avi", 160, 144, |_| Ok(Cursor::new(Vec::new()))).unwrap();
+        for i in 0..FRAMES {
+            let mut rgb = vec![i as u8; 160 * 144 * 3];
+            // 左上の画素
+            rgb[..3].copy_from_slice(&[0x11, 0x22, 0x33]);
+            avi.write_frame(&rgb).unwrap();
+        }
+        assert_eq!(avi.frames, FRAMES as u64);
+        let data = avi.finish().unwrap().into_inner();
+
+        let frame_size = 160 * 3 * 144;
+        let movi_size = 4 + FRAMES * (8 + frame_size);
+        let movi = MOVI_SIZE as usize + 4;
+        let idx1 = movi + movi_size;
+        assert_eq!(data.len(), idx1 + 8 + 16 * FRAMES);
+
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32_at(&data, RIFF_SIZE as usize), data.len() as u32 - 8);
+        assert_eq!(u32_at(&data, AVIH_TOTAL_FRAMES as usize), FRAMES as u32);
+        assert_eq!(u32_at(&data, STRH_LENGTH as usize), FRAMES as u32);
+        assert_eq!(&data[movi - 8..movi - 4], b"LIST");
+        assert_eq!(u32_at(&data, MOVI_SIZE as usize), movi_size as u32);
+        assert_eq!(&data[movi..movi + 4], b"movi");
+
+        // 各フレームは下の行から, BGRの順
+        let first = movi + 4;
+        assert_eq!(&data[first..first + 4], b"00db");
+        assert_eq!(u32_at(&data, first + 4), frame_size as u32);
+        let top_row = first + 8 + 143 * 160 * 3;
+        assert_eq!(&data[top_row..top_row + 4], [0x33, 0x22, 0x11, 0x00]);
+
+        assert_eq!(&data[idx1..idx1 + 4], b"idx1");
+        assert_eq!(u32_at(&data, idx1 + 4), 16 * FRAMES as u32);
+        for i in 0..FRAMES {
+            let entry = idx1 + 8 + 16 * i;
+            assert_eq!(&data[entry..entry + 4], b"00db");
+            assert_eq!(u32_at(&data, entry + 8), (4 + i * (8 + frame_size)) as u32);
+            assert_eq!(u32_at(&data, entry + 12), frame_size as u32);
+            let chunk = movi + u32_at(&data, entry + 8) as usize;
+            assert_eq!(&data[chunk..chunk + 4], b"00db");
+        }
+    }
+}
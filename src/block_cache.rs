@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::instruction::{ArithmeticTarget, Instruction, JumpTest, LoadType, StackTarget, BYTES};
+use crate::memory_bus::MemoryBus;
+
+// ブロックキャッシュ (--block-cache)
+// 分岐までの一続きの命令をデコードし, よく使う命令はオペランド (レジスタ, 即値, 飛び先) を
+// 解決済みのマイクロオペレーションにしておく. 次からはメモリから読み直さずに実行する
+// ROMはバンク:アドレス, WRAM/HRAMはWRAMのバンク:アドレスで引く (その他の場所はキャッシュしない)
+// RAMのコードは256バイトのページ単位で見張り, 書き換えられたページを含むブロックだけ捨てる
+// サイクル数は変わらない: 命令のフェッチも1バイトごとに1Mサイクル進める
+
+// 1ブロックの最大の命令数
+const MAX_BLOCK_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum R8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum R16 {
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+// Aとの演算
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+// オペランドを解決済みの命令
+#[derive(Clone, Copy, Debug)]
+pub enum MicroOp {
+    Nop,
+    Ld(R8, R8),
+    LdImm(R8, u8),
+    LdFromHl(R8),    // LD r,(HL)
+    LdToHl(R8),      // LD (HL),r
+    LdHlImm(u8),     // LD (HL),d8
+    LdFromMem(u16),  // LD A,(a16), LDH A,(a8)
+    LdToMem(u16),    // LD (a16),A, LDH (a8),A
+    LdFromR16(R16),  // LD A,(BC), LD A,(DE)
+    LdToR16(R16),    // LD (BC),A, LD (DE),A
+    LdFromHlStep(u16), // LD A,(HL+), LD A,(HL-) (HLに足す値)
+    LdToHlStep(u16),   // LD (HL+),A, LD (HL-),A
+    LdImm16(R16, u16),
+    Alu(Alu, R8),
+    AluImm(Alu, u8),
+    AluHl(Alu),      // 演算 A,(HL)
+    Inc(R8),
+    Dec(R8),
+    Inc16(R16),
+    Dec16(R16),
+    Jr(JumpTest, u16), // 飛び先のアドレス
+    Jp(JumpTest, u16),
+    Call(JumpTest, u16),
+    Ret(JumpTest),
+    Push(StackTarget),
+    Pop(StackTarget),
+    Other(Instruction), // それ以外はインタプリタと同じ処理で実行する
+}
+
+#[derive(Clone, Copy)]
+pub struct Op {
+    pub address: u16,
+    pub micro: MicroOp,
+    pub bytes: [u8; 3], // CBプレフィックスを含む命令のバイト列
+    pub length: u8,
+}
+
+pub struct Block {
+    pages: u128, // 含まれるRAMのページ (0x8000からの256バイト単位, ROMなら0)
+    pub ops: Vec<Op>,
+}
+
+pub struct BlockCache {
+    blocks: HashMap<(u16, u16), Rc<Block>>,
+    current: Option<(Rc<Block>, usize)>, // 実行中のブロックと次の命令の位置
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache {
+            blocks: HashMap::new(),
+            current: None,
+        }
+    }
+
+    // セーブステートの読み込みなどでメモリが丸ごと変わったとき
+    pub fn clear(&mut self, bus: &mut MemoryBus) {
+        self.blocks.clear();
+        self.current = None;
+        bus.code_pages = 0;
+        bus.dirty_pages = 0;
+        bus.bank_switched = false;
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // pcの命令を返す (なければブロックを作る), キャッシュできない場所ならNone
+    #[inline]
+    pub fn fetch(&mut self, bus: &mut MemoryBus, pc: u16) -> Option<Op> {
+        // 実行中のブロックの続きならそのまま返す
+        if let Some(index) = self.resume(bus, pc) {
+            if let Some((block, next)) = &mut self.current {
+                *next = index + 1;
+                return Some(block.ops[index]);
+            }
+        }
+        self.lookup(bus, pc)
+    }
+
+    // CPU::step_block用: pcから実行するブロックとその中の位置を返す
+    pub fn fetch_block(&mut self, bus: &mut MemoryBus, pc: u16) -> Option<(Rc<Block>, usize)> {
+        if let Some(index) = self.resume(bus, pc) {
+            return self.current.as_ref().map(|(block, _)| (block.clone(), index));
+        }
+        self.lookup(bus, pc)?;
+        self.current.as_ref().map(|(block, _)| (block.clone(), 0))
+    }
+
+    // 実行中のブロックの続きか, ブロックの先頭に戻ってきた (ループ) ならその位置
+    // 表を引かずに済むので, 短いループではブロックの切り替えが速くなる
+    #[inline]
+    fn resume(&self, bus: &MemoryBus, pc: u16) -> Option<usize> {
+        if bus.dirty_pages != 0 || bus.bank_switched {
+            return None;
+        }
+        let (block, index) = self.current.as_ref()?;
+        if block.ops.get(*index).is_some_and(|op| op.address == pc) {
+            Some(*index)
+        } else if block.ops[0].address == pc {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    // step_blockがブロックのindexの命令の手前まで実行した
+    pub fn set_position(&mut self, block: Rc<Block>, index: usize) {
+        self.current = Some((block, index));
+    }
+
+    fn lookup(&mut self, bus: &mut MemoryBus, pc: u16) -> Option<Op> {
+        if bus.dirty_pages != 0 {
+            // 書き換えられたページにかかるブロックを捨てる (残ったブロックはそのページを含まない)
+            let dirty = std::mem::take(&mut bus.dirty_pages);
+            self.blocks.retain(|_, block| block.pages & dirty == 0);
+            bus.code_pages &= !dirty;
+            self.current = None;
+        }
+        if bus.bank_switched {
+            // 実行中のブロックの続きが別のバンクになっているかもしれない
+            bus.bank_switched = false;
+            self.current = None;
+        }
+
+        let key = (bus.code_bank(pc)?, pc);
+        let block = match self.blocks.get(&key) {
+            Some(block) => block.clone(),
+            None => {
+                let block = Rc::new(compile(bus, pc)?);
+                bus.code_pages |= block.pages;
+                self.blocks.insert(key, block.clone());
+                block
+            }
+        };
+        let op = block.ops[0];
+        self.current = Some((block, 1));
+        Some(op)
+    }
+}
+
+// startから分岐 (かHALT, STOP, EI, DI) までをデコードする
+// メモリ領域の境目はまたがない
+fn compile(bus: &MemoryBus, start: u16) -> Option<Block> {
+    let region_end: u16 = match start {
+        0x0000..=0x3FFF => 0x3FFF,
+        0x4000..=0x7FFF => 0x7FFF,
+        0xC000..=0xCFFF => 0xCFFF,
+        0xD000..=0xDFFF => 0xDFFF,
+        _ => 0xFFFE,
+    };
+    let mut ops = Vec::new();
+    let mut address = start;
+    while ops.len() < MAX_BLOCK_LENGTH {
+        let opcode = bus.peek(address);
+        let length = if opcode == 0xCB { 2 } else { BYTES[opcode as usize] };
+        if address as u32 + length as u32 - 1 > region_end as u32 {
+            break;
+        }
+        let mut bytes = [0; 3];
+        for (i, byte) in bytes.iter_mut().enumerate().take(length as usize) {
+            *byte = bus.peek(address + i as u16);
+        }
+        let instruction = if opcode == 0xCB {
+            Instruction::from_byte(bytes[1], true)
+        } else {
+            Instruction::from_byte(opcode, false)
+        };
+        // 未定義の命令はインタプリタに任せる
+        let instruction = match instruction {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        let next = address.wrapping_add(length);
+        ops.push(Op {
+            address,
+            micro: lower(instruction, bytes, next),
+            bytes,
+            length: length as u8,
+        });
+        address = next;
+        if ends_block(&instruction) {
+            break;
+        }
+    }
+    if ops.is_empty() {
+        return None;
+    }
+    let pages = if start >= 0x8000 {
+        let first = (start - 0x8000) >> 8;
+        let last = (address - 1 - 0x8000) >> 8;
+        (first..=last).fold(0, |pages, page| pages | 1u128 << page)
+    } else {
+        0
+    };
+    Some(Block { pages, ops })
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::JP(_)
+            | Instruction::JPHL
+            | Instruction::JR(_)
+            | Instruction::CALL(_)
+            | Instruction::RST(_)
+            | Instruction::RET(_)
+            | Instruction::RETI
+            | Instruction::HALT
+            | Instruction::STOP
+            | Instruction::DI
+            | Instruction::EI
+    )
+}
+
+fn r8(target: ArithmeticTarget) -> Option<R8> {
+    match target {
+        ArithmeticTarget::A => Some(R8::A),
+        ArithmeticTarget::B => Some(R8::B),
+        ArithmeticTarget::C => Some(R8::C),
+        ArithmeticTarget::D => Some(R8::D),
+        ArithmeticTarget::E => Some(R8::E),
+        ArithmeticTarget::H => Some(R8::H),
+        ArithmeticTarget::L => Some(R8::L),
+        _ => None,
+    }
+}
+
+fn r16(target: ArithmeticTarget) -> Option<R16> {
+    match target {
+        ArithmeticTarget::BC => Some(R16::BC),
+        ArithmeticTarget::DE => Some(R16::DE),
+        ArithmeticTarget::HL => Some(R16::HL),
+        ArithmeticTarget::SP => Some(R16::SP),
+        _ => None,
+    }
+}
+
+// オペランドをレジスタ, 即値, アドレスに解決する (nextは次の命令のアドレス)
+fn lower(instruction: Instruction, bytes: [u8; 3], next: u16) -> MicroOp {
+    use ArithmeticTarget as T;
+    let imm8 = bytes[1];
+    let imm16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let alu = |op: Alu, target: T| match (r8(target), target) {
+        (Some(r), _) => MicroOp::Alu(op, r),
+        (None, T::D8) => MicroOp::AluImm(op, imm8),
+        (None, T::HL_) => MicroOp::AluHl(op),
+        _ => MicroOp::Other(instruction),
+    };
+    match instruction {
+        Instruction::NOP => MicroOp::Nop,
+        Instruction::LD(LoadType::Byte(target, source)) => match (target, source, r8(target), r8(source)) {
+            (_, _, Some(target), Some(source)) => MicroOp::Ld(target, source),
+            (_, T::D8, Some(target), None) => MicroOp::LdImm(target, imm8),
+            (_, T::HL_, Some(target), None) => MicroOp::LdFromHl(target),
+            (T::HL_, _, None, Some(source)) => MicroOp::LdToHl(source),
+            (T::HL_, T::D8, None, None) => MicroOp::LdHlImm(imm8),
+            (T::A, T::D16_, _, _) => MicroOp::LdFromMem(imm16),
+            (T::A, T::FD8_, _, _) => MicroOp::LdFromMem(0xFF00 | imm8 as u16),
+            (T::D16_, T::A, _, _) => MicroOp::LdToMem(imm16),
+            (T::FD8_, T::A, _, _) => MicroOp::LdToMem(0xFF00 | imm8 as u16),
+            (T::A, T::BC_, _, _) => MicroOp::LdFromR16(R16::BC),
+            (T::A, T::DE_, _, _) => MicroOp::LdFromR16(R16::DE),
+            (T::BC_, T::A, _, _) => MicroOp::LdToR16(R16::BC),
+            (T::DE_, T::A, _, _) => MicroOp::LdToR16(R16::DE),
+            (T::A, T::HLi_, _, _) => MicroOp::LdFromHlStep(1),
+            (T::A, T::HLd_, _, _) => MicroOp::LdFromHlStep(0xFFFF),
+            (T::HLi_, T::A, _, _) => MicroOp::LdToHlStep(1),
+            (T::HLd_, T::A, _, _) => MicroOp::LdToHlStep(0xFFFF),
+            _ => MicroOp::Other(instruction),
+        },
+        Instruction::LD(LoadType::WORD(target, T::D16)) => match r16(target) {
+            Some(target) => MicroOp::LdImm16(target, imm16),
+            None => MicroOp::Other(instruction),
+        },
+        Instruction::INC(target) => match (r8(target), r16(target)) {
+            (Some(r), _) => MicroOp::Inc(r),
+            (_, Some(r)) => MicroOp::Inc16(r),
+            _ => MicroOp::Other(instruction),
+        },
+        Instruction::DEC(target) => match (r8(target), r16(target)) {
+            (Some(r), _) => MicroOp::Dec(r),
+            (_, Some(r)) => MicroOp::Dec16(r),
+            _ => MicroOp::Other(instruction),
+        },
+        // ADD SP,e8はADD(SP)なのでOtherになる
+        Instruction::ADD(target) => alu(Alu::Add, target),
+        Instruction::ADC(target) => alu(Alu::Adc, target),
+        Instruction::SUB(target) => alu(Alu::Sub, target),
+        Instruction::SBC(target) => alu(Alu::Sbc, target),
+        Instruction::AND(target) => alu(Alu::And, target),
+        Instruction::XOR(target) => alu(Alu::Xor, target),
+        Instruction::OR(target) => alu(Alu::Or, target),
+        Instruction::CP(target) => alu(Alu::Cp, target),
+        Instruction::JR(test) => MicroOp::Jr(test, next.wrapping_add(imm8 as i8 as u16)),
+        Instruction::JP(test) => MicroOp::Jp(test, imm16),
+        Instruction::CALL(test) => MicroOp::Call(test, imm16),
+        Instruction::RET(test) => MicroOp::Ret(test),
+        Instruction::PUSH(target) => MicroOp::Push(target),
+        Instruction::POP(target) => MicroOp::Pop(target),
+        _ => MicroOp::Other(instruction),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lowered(bytes: [u8; 3]) -> MicroOp {
+        let instruction = Instruction::from_byte(bytes[0], false).unwrap();
+        let next = 0x0200 + BYTES[bytes[0] as usize];
+        lower(instruction, bytes, next)
+    }
+
+    #[test]
+    fn test_lower() {
+        assert!(matches!(lowered([0x78, 0, 0]), MicroOp::Ld(R8::A, R8::B)));
+        assert!(matches!(lowered([0x3E, 0x42, 0]), MicroOp::LdImm(R8::A, 0x42)));
+        assert!(matches!(lowered([0x46, 0, 0]), MicroOp::LdFromHl(R8::B)));
+        assert!(matches!(lowered([0x77, 0, 0]), MicroOp::LdToHl(R8::A)));
+        assert!(matches!(lowered([0x36, 0x99, 0]), MicroOp::LdHlImm(0x99)));
+        assert!(matches!(lowered([0xFA, 0x34, 0x12]), MicroOp::LdFromMem(0x1234)));
+        assert!(matches!(lowered([0xF0, 0x44, 0]), MicroOp::LdFromMem(0xFF44)));
+        assert!(matches!(lowered([0xEA, 0x00, 0xC0]), MicroOp::LdToMem(0xC000)));
+        assert!(matches!(lowered([0xE0, 0x40, 0]), MicroOp::LdToMem(0xFF40)));
+        assert!(matches!(lowered([0x21, 0x00, 0x80]), MicroOp::LdImm16(R16::HL, 0x8000)));
+        assert!(matches!(lowered([0x90, 0, 0]), MicroOp::Alu(Alu::Sub, R8::B)));
+        assert!(matches!(lowered([0xFE, 0x90, 0]), MicroOp::AluImm(Alu::Cp, 0x90)));
+        assert!(matches!(lowered([0xAE, 0, 0]), MicroOp::AluHl(Alu::Xor)));
+        assert!(matches!(lowered([0x2C, 0, 0]), MicroOp::Inc(R8::L)));
+        assert!(matches!(lowered([0x0B, 0, 0]), MicroOp::Dec16(R16::BC)));
+        // JRの飛び先は次の命令からの相対
+        assert!(matches!(lowered([0x20, 0xFC, 0]), MicroOp::Jr(JumpTest::NotZero, 0x01FE)));
+        assert!(matches!(lowered([0xCD, 0x00, 0x40]), MicroOp::Call(JumpTest::Always, 0x4000)));
+        assert!(matches!(lowered([0x1A, 0, 0]), MicroOp::LdFromR16(R16::DE)));
+        assert!(matches!(lowered([0x02, 0, 0]), MicroOp::LdToR16(R16::BC)));
+        assert!(matches!(lowered([0x2A, 0, 0]), MicroOp::LdFromHlStep(1)));
+        assert!(matches!(lowered([0x32, 0, 0]), MicroOp::LdToHlStep(0xFFFF)));
+        assert!(matches!(lowered([0xC0, 0, 0]), MicroOp::Ret(JumpTest::NotZero)));
+        assert!(matches!(lowered([0xF5, 0, 0]), MicroOp::Push(StackTarget::AF)));
+        assert!(matches!(lowered([0xE1, 0, 0]), MicroOp::Pop(StackTarget::HL)));
+        // メモリ上の値の増減, ADD SP,e8, LD (a16),SPなどはインタプリタに任せる
+        assert!(matches!(lowered([0x34, 0, 0]), MicroOp::Other(_)));
+        assert!(matches!(lowered([0xE8, 0x01, 0]), MicroOp::Other(_)));
+        assert!(matches!(lowered([0x08, 0x00, 0xC0]), MicroOp::Other(_)));
+        assert!(matches!(lowered([0xF2, 0, 0]), MicroOp::Other(_)));
+    }
+}
@@ -1,60 +1,491 @@
-use std::{fs::{self, File}, io::Read};
+use std::{fs::{self, File}, io::Read, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::mapper::{self, mbc1::MBC1};
+use crate::mapper::{
+    self, huc1::HuC1, huc3::HuC3, mbc1::MBC1, mbc2::MBC2, mbc3::MBC3, mbc5::MBC5, mmm01::MMM01,
+    pocket_camera::{self, PocketCamera}, rom_only::RomOnly, tama5::TAMA5, Mapper,
+};
+use crate::state::{self, StateReader, StateWriter};
 
 enum RomSize {
     Bank2,
     Bank4,
+    Bank8,
+    Bank16,
+    Bank32,
+    Bank64,
+    Bank128,
+    Bank256,
+    Bank512,
 }
 
 enum RamSize{
     No,
     Unused,
     Bank1,
+    Bank4,
+    Bank16,
+    Bank8,
+}
+
+impl RamSize {
+    fn bytes(&self) -> usize {
+        match self {
+            RamSize::No => 0,
+            RamSize::Unused => 0x800,
+            RamSize::Bank1 => 0x2000,
+            RamSize::Bank4 => 0x2000 * 4,
+            RamSize::Bank16 => 0x2000 * 16,
+            RamSize::Bank8 => 0x2000 * 8,
+        }
+    }
+}
+
+// 0x0147 (カートリッジタイプ) の名前
+fn cartridge_type_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
 }
 
 pub struct Cartridge{
     raw: Vec<u8>,
-    mapper: MBC1,
+    mapper: Box<dyn Mapper>,
     rom_size: RomSize,
     ram_size: RamSize,
+    battery: bool,
+    save_path: PathBuf,
+    ram_dirty: bool,  // 前回の保存以降にRAMへ書き込みがあったか
+    save_pending: bool, // RAM無効化後の自動保存待ち
+    idle_frames: u32,   // RAM有効化のないカートリッジで最後の書き込みから経過したフレーム数
+    has_clock: bool,    // RTCを持つか (MBC3+TIMER, HuC3)
+    emulated_time: Option<u64>, // ムービー用: 実時間の代わりに使うエミュレーション時間 (秒)
+    clock_cycles: u32,  // エミュレーション時間の1秒未満の端数
+}
+
+// ヘッダのロゴ (0x0104-0x0133)
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// 0x0100から始まるヘッダのロゴとチェックサム (0x014D) が正しいか
+fn valid_header(header: &[u8]) -> bool {
+    let checksum = header[0x34..=0x4C].iter().fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1));
+    header[0x04..=0x33] == NINTENDO_LOGO && header[0x4D] == checksum
 }
 
+// 1秒あたりのサイクル数
+const CYCLES_PER_SECOND: u32 = 4194304;
+
+// RAM有効化のないカートリッジは, 書き込みがこのフレーム数途絶えたら保存する
+const AUTOSAVE_IDLE_FRAMES: u32 = 60;
+
 impl Cartridge {
-    pub fn new (filename: &str) -> Self {
-        let mut f = File::open(&filename).expect("no file found");
-        let metadata = fs::metadata(&filename).expect("unable to read metadata");
+    pub fn new (filename: &str) -> Result<Self, String> {
+        Self::open(filename, true)
+    }
+
+    // ムービー用: .savを読み書きせず, 時計は実時間ではなくエミュレーション時間で動かす
+    pub fn new_without_save(filename: &str) -> Result<Self, String> {
+        Self::open(filename, false)
+    }
+
+    fn open(filename: &str, use_save: bool) -> Result<Self, String> {
+        let mut f = File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        let metadata = fs::metadata(filename).map_err(|e| format!("{}: {}", filename, e))?;
         let mut raw = vec![0; metadata.len() as usize];
-        f.read(&mut raw).expect("buffer overflow");
+        f.read(&mut raw).map_err(|e| format!("{}: {}", filename, e))?;
+        Self::from_raw(raw, filename, use_save)
+    }
+
+    // テスト用: ファイルを使わずにROMのバイト列から作る
+    #[cfg(test)]
+    pub fn from_bytes(raw: Vec<u8>) -> Result<Self, String> {
+        Self::from_raw(raw, "test.gb", false)
+    }
+
+    fn from_raw(raw: Vec<u8>, filename: &str, use_save: bool) -> Result<Self, String> {
+        if raw.len() < 0x8000 {
+            return Err(format!("{}: rom is too small ({} bytes)", filename, raw.len()));
+        }
 
         println!("{:02X?}", &raw[0x0104..=0x133]);
 
-        Cartridge {
-            raw: raw.clone(), 
-            mapper: match raw[0x0147] {
-                // 0x00 => CartridgeType::RomOnly,
-                0x01 => MBC1::new(),
-                _ => panic!("unsupported crtridge type."),
-            },
+        // MMM01はメニューのヘッダがROM末尾の32KBにある
+        // 普通のROMではそこはただのデータなので, ロゴとチェックサムが正しいときだけ見る
+        let mut cartridge_type = raw[0x0147];
+        let mut ram_size_code = raw[0x0149];
+        let menu = raw.len() - 0x8000;
+        if matches!(raw[menu + 0x0147], 0x0B..=0x0D) && valid_header(&raw[menu + 0x0100..menu + 0x0150]) {
+            cartridge_type = raw[menu + 0x0147];
+            ram_size_code = raw[menu + 0x0149];
+        }
+
+        let ram_size = match ram_size_code {
+            0x00 => RamSize::No,
+            0x01 => RamSize::Unused,
+            0x02 => RamSize::Bank1,
+            0x03 => RamSize::Bank4,
+            0x04 => RamSize::Bank16,
+            0x05 => RamSize::Bank8,
+            n => return Err(format!("unsupported ram size 0x{:02X}", n)),
+        };
+        let ram_bytes = ram_size.bytes();
+        let emulated_time = if use_save { None } else { Some(0) };
+        let now = current_time(emulated_time);
+
+        let mapper: Box<dyn Mapper> = match cartridge_type {
+            0x00 | 0x08 | 0x09 => Box::new(RomOnly::new(ram_bytes)),
+            0x01..=0x03 => Box::new(MBC1::new(ram_bytes)),
+            0x05 | 0x06 => Box::new(MBC2::new()),
+            0x0B..=0x0D => Box::new(MMM01::new(ram_bytes)),
+            0x0F | 0x10 => Box::new(MBC3::new(ram_bytes, true, now)),
+            0x11..=0x13 => Box::new(MBC3::new(ram_bytes, false, now)),
+            0x19..=0x1E => Box::new(MBC5::new(ram_bytes)),
+            0xFC => Box::new(PocketCamera::new(ram_bytes)),
+            0xFD => Box::new(TAMA5::new()),
+            0xFE => Box::new(HuC3::new(ram_bytes, now)),
+            0xFF => Box::new(HuC1::new(ram_bytes)),
+            n => {
+                return Err(format!(
+                    "unsupported mapper 0x{:02X} ({})",
+                    n,
+                    cartridge_type_name(n)
+                ))
+            }
+        };
+
+        let mut cartridge = Cartridge {
+            raw: raw.clone(),
+            mapper,
             rom_size: match raw[0x0148] {
                 0x00 => RomSize::Bank2,
                 0x01 => RomSize::Bank4,
-                _ => panic!("unsupported rom size."),
+                0x02 => RomSize::Bank8,
+                0x03 => RomSize::Bank16,
+                0x04 => RomSize::Bank32,
+                0x05 => RomSize::Bank64,
+                0x06 => RomSize::Bank128,
+                0x07 => RomSize::Bank256,
+                0x08 => RomSize::Bank512,
+                n => return Err(format!("unsupported rom size 0x{:02X}", n)),
             },
-            ram_size: match raw[0x0149] {
-                0x00 => RamSize::No,
-                0x01 => RamSize::Unused,
-                0x02 => RamSize::Bank1,
-                _ => panic!("unsupported ram size."),
-            }
-        }
+            ram_size,
+            // Pocket Camera, TAMA5, HuC1, HuC3もバッテリーを持つ
+            battery: use_save && matches!(
+                cartridge_type,
+                0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFC | 0xFD | 0xFE | 0xFF
+            ),
+            save_path: Path::new(filename).with_extension("sav"),
+            ram_dirty: false,
+            save_pending: false,
+            idle_frames: 0,
+            has_clock: matches!(cartridge_type, 0x0F | 0x10 | 0xFE),
+            emulated_time,
+            clock_cycles: 0,
+        };
+        cartridge.load_save();
+        Ok(cartridge)
+    }
+
+    // Pocket Cameraのセンサーとして使う画像を読み込む
+    pub fn set_camera_image(&mut self, path: &str) -> Result<(), String> {
+        let pixels = pocket_camera::load_image(path)?;
+        self.mapper.set_camera_image(pixels);
+        Ok(())
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
         self.mapper.read_byte(&self.raw, addr)
     }
 
+    // addrに見えているROMのバンク番号
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        self.mapper.mapped_bank(&self.raw, addr)
+    }
+
+    // addrに見えているROMのファイル内のオフセット
+    pub fn rom_offset(&self, addr: u16) -> usize {
+        mapper::rom_offset(&self.raw, self.rom_bank(addr), addr)
+    }
+
+    pub fn rom_len(&self) -> usize {
+        self.raw.len()
+    }
+
+    // ヘッダのタイトル (0x0134-0x0143, 0か表示できない文字まで)
+    pub fn title(&self) -> String {
+        (0x0134..=0x0143)
+            .map(|addr| self.raw.get(addr).copied().unwrap_or(0))
+            .take_while(|&byte| byte == b' ' || byte.is_ascii_graphic())
+            .map(char::from)
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
     pub fn write_byte(&mut self, addr: u16, value: u8) {
+        let was_enabled = self.mapper.ram_enabled();
+        // RAMが無効化されたらゲーム側の書き込みが一区切りついたとみなす
+        if was_enabled && self.ram_dirty && self.mapper.disables_ram(addr, value) {
+            self.save_pending = true;
+        }
+        if self.has_clock && matches!(addr, 0x6000..=0x7FFF | 0xA000..=0xBFFF) {
+            self.mapper.set_time(self.now());
+        }
         self.mapper.write_byte(&mut self.raw, addr, value);
+        if was_enabled && (0xA000..=0xBFFF).contains(&addr) {
+            self.ram_dirty = true;
+            self.idle_frames = 0;
+        }
+    }
+
+    // .savファイルの読み込み (RAMの生データ + 時計のフッター)
+    fn load_save(&mut self) {
+        if !self.battery {
+            return;
+        }
+        let data = match fs::read(&self.save_path) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let ram = self.mapper.ram_mut();
+        let len = ram.len().min(data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+
+        let now = self.now();
+        self.mapper.load_footer(&data[len..], now);
+        println!("loaded {}", self.save_path.display());
     }
-}
\ No newline at end of file
+
+    pub fn save(&mut self) {
+        if !self.battery {
+            return;
+        }
+        let mut data = self.mapper.ram().to_vec();
+        let now = self.now();
+        data.extend(self.mapper.save_footer(now));
+        if data.is_empty() {
+            return;
+        }
+        match fs::write(&self.save_path, &data) {
+            Ok(_) => {
+                self.ram_dirty = false;
+                self.save_pending = false;
+                self.idle_frames = 0;
+            }
+            Err(e) => println!("failed to write {}: {}", self.save_path.display(), e),
+        }
+    }
+
+    // RAM無効化の書き込みがあった場合のみ保存する (1フレームに1回呼ぶ)
+    // RAM有効化のないカートリッジは書き込みが途絶えてから保存する
+    pub fn autosave(&mut self) {
+        if self.ram_dirty && !self.save_pending && !self.mapper.has_ram_gate() {
+            self.idle_frames += 1;
+            if self.idle_frames >= AUTOSAVE_IDLE_FRAMES {
+                self.save_pending = true;
+            }
+        }
+        if self.save_pending {
+            self.save();
+        }
+    }
+
+    // エミュレーション時間で時計を動かしている場合は経過サイクルで進める
+    pub fn tick(&mut self, cycles: u16) {
+        if let Some(seconds) = &mut self.emulated_time {
+            self.clock_cycles += cycles as u32;
+            if self.clock_cycles >= CYCLES_PER_SECOND {
+                self.clock_cycles -= CYCLES_PER_SECOND;
+                *seconds += 1;
+            }
+        }
+    }
+
+    // RTC用の現在時刻
+    fn now(&self) -> u64 {
+        current_time(self.emulated_time)
+    }
+
+    // セーブステートでROMを見分けるためのチェックサム
+    pub fn checksum(&self) -> u32 {
+        state::rom_checksum(&self.raw)
+    }
+
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        self.mapper.save_state(w);
+        w.bytes(self.mapper.ram());
+        let now = self.now();
+        let footer = self.mapper.save_footer(now);
+        w.bytes(&footer);
+        w.u32(self.clock_cycles);
+        w.u64(self.emulated_time.unwrap_or(u64::MAX));
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.mapper.load_state(r)?;
+        r.bytes_into(self.mapper.ram_mut())?;
+        let footer = r.bytes()?;
+        self.clock_cycles = r.u32()?;
+        // エミュレーション時間はフッターの読み込み (経過時間の同期) より先に戻す
+        let time = r.u64()?;
+        if self.emulated_time.is_some() && time != u64::MAX {
+            self.emulated_time = Some(time);
+        }
+        let now = self.now();
+        self.mapper.load_footer(footer, now);
+        Ok(())
+    }
+}
+
+// UNIX時間 (秒), エミュレーション時間があればそちらを使う
+fn current_time(emulated_time: Option<u64>) -> u64 {
+    if let Some(seconds) = emulated_time {
+        return seconds;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::mbc3::RTC_FOOTER_SIZE;
+
+    // カートリッジタイプとRAMサイズだけを設定した32KBのROM
+    fn rom(cartridge_type: u8, ram_size: u8) -> Vec<u8> {
+        let mut raw = vec![0; 0x8000];
+        raw[0x0147] = cartridge_type;
+        raw[0x0149] = ram_size;
+        raw
+    }
+
+    // ヘッダのロゴとチェックサムを書き込む
+    fn set_header(header: &mut [u8]) {
+        header[0x04..=0x33].copy_from_slice(&NINTENDO_LOGO);
+        header[0x4D] = header[0x34..=0x4C].iter().fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1));
+    }
+
+    // MMM01のメニューは末尾32KBのヘッダが正しいときだけ認識する
+    #[test]
+    fn test_mmm01_detection() {
+        // MBC5+RAM+BATTERY, 64KB, RAM 32KB
+        let mut raw = vec![0; 0x10000];
+        raw[0x0147] = 0x1B;
+        raw[0x0148] = 0x01;
+        raw[0x0149] = 0x03;
+        set_header(&mut raw[0x0100..0x0150]);
+        // 末尾32KBのデータがたまたまMMM01+RAMに見える
+        raw[0x8147] = 0x0C;
+        raw[0x8149] = 0x02;
+        let cartridge = Cartridge::from_bytes(raw.clone()).unwrap();
+        assert_eq!(cartridge.mapper.ram().len(), 0x8000);
+
+        // メニューのヘッダが正しければMMM01
+        set_header(&mut raw[0x8100..0x8150]);
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        assert_eq!(cartridge.mapper.ram().len(), 0x2000);
+        assert_eq!(cartridge.read_byte(0x0147), 0x0C);
+    }
+
+    // .savはROMと同じ名前で作られるので, テストごとに別の名前にする
+    fn temp_rom_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}.gb", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let path = temp_rom_path("mbc3_save");
+        let mut cartridge = Cartridge::from_raw(rom(0x10, 0x03), &path, true).unwrap();
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0x4000, 0x01);
+        cartridge.write_byte(0xA123, 0x5A);
+        // 時計を止めてから設定する (保存と読み込みの間に進まないように)
+        let rtc = [(0x0C, 0x40), (0x08, 30), (0x09, 15), (0x0A, 7), (0x0B, 0x23), (0x0C, 0x41)];
+        for (register, value) in rtc {
+            cartridge.write_byte(0x4000, register);
+            cartridge.write_byte(0xA000, value);
+        }
+        // RAMの無効化で保存される
+        cartridge.write_byte(0x0000, 0x00);
+        cartridge.autosave();
+        let data = fs::read(&cartridge.save_path).unwrap();
+        assert_eq!(data.len(), 0x8000 + RTC_FOOTER_SIZE);
+        assert_eq!(data[0x2123], 0x5A);
+
+        let mut loaded = Cartridge::from_raw(rom(0x10, 0x03), &path, true).unwrap();
+        fs::remove_file(&loaded.save_path).unwrap();
+        loaded.write_byte(0x0000, 0x0A);
+        loaded.write_byte(0x4000, 0x01);
+        assert_eq!(loaded.read_byte(0xA123), 0x5A);
+        loaded.write_byte(0x6000, 0x00);
+        loaded.write_byte(0x6000, 0x01);
+        for (register, value) in &rtc[1..] {
+            loaded.write_byte(0x4000, *register);
+            assert_eq!(loaded.read_byte(0xA000), *value, "RTC register {:02X}", register);
+        }
+    }
+
+    #[test]
+    fn test_autosave() {
+        // MBC2: A8が1の書き込みはバンク切り替えなので保存しない
+        let path = temp_rom_path("mbc2_autosave");
+        let mut cartridge = Cartridge::from_raw(rom(0x06, 0x00), &path, true).unwrap();
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x05);
+        cartridge.write_byte(0x2100, 0x00);
+        cartridge.autosave();
+        assert!(!cartridge.save_path.exists());
+        // 0x2000-0x3FFFでもA8が0ならRAMの無効化
+        cartridge.write_byte(0x2000, 0x00);
+        cartridge.autosave();
+        assert_eq!(fs::read(&cartridge.save_path).unwrap()[0], 0x05);
+        fs::remove_file(&cartridge.save_path).unwrap();
+
+        // ROM+RAM+BATTERY: 書き込みが途絶えてから保存する
+        let path = temp_rom_path("rom_ram_autosave");
+        let mut cartridge = Cartridge::from_raw(rom(0x09, 0x02), &path, true).unwrap();
+        cartridge.write_byte(0xA000, 0x42);
+        for _ in 1..AUTOSAVE_IDLE_FRAMES {
+            cartridge.autosave();
+        }
+        assert!(!cartridge.save_path.exists());
+        cartridge.autosave();
+        assert_eq!(fs::read(&cartridge.save_path).unwrap()[0], 0x42);
+        fs::remove_file(&cartridge.save_path).unwrap();
+    }
+}
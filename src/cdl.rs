@@ -0,0 +1,188 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+
+// コード/データのログ (--cdl FILE)
+// ROMの1バイトごとに使われ方をビットで記録する (FCEUX/BizHawkのCDLと同じくROMと同じ大きさ)
+// 既存のファイルがあれば続きから記録する
+
+pub const CODE: u8 = 0x01; // 命令の先頭 (CBプレフィックスの命令は2バイトとも)
+pub const OPERAND: u8 = 0x02; // 命令のオペランド
+pub const DATA: u8 = 0x04; // 命令以外で読まれた
+pub const GRAPHICS: u8 = 0x08; // VRAMのタイルデータに転送された
+
+pub struct CodeDataLog {
+    path: String,
+    flags: RefCell<Vec<u8>>,
+    instruction: Cell<(u16, u16)>, // 実行中の命令 (アドレス, バイト数): データとして数えない
+    last_read: Cell<Option<(usize, u8)>>, // 最後にデータとして読んだ (ROMのオフセット, 値)
+}
+
+impl CodeDataLog {
+    pub fn open(path: &str, rom_size: usize) -> Result<Self, String> {
+        let flags = match fs::read(path) {
+            Ok(data) if data.len() == rom_size => data,
+            Ok(data) => {
+                return Err(format!("{}: has {} bytes, but the rom has {}", path, data.len(), rom_size));
+            }
+            Err(_) => vec![0; rom_size],
+        };
+        Ok(CodeDataLog {
+            path: path.to_string(),
+            flags: RefCell::new(flags),
+            instruction: Cell::new((0, 0)),
+            last_read: Cell::new(None),
+        })
+    }
+
+    fn mark(&self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.borrow_mut().get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+
+    // 命令を実行する前に呼ぶ (offsetは命令の各バイトのROMのオフセット, ROM外ならNone)
+    pub fn log_instruction(&self, address: u16, offsets: &[Option<usize>], prefixed: bool) {
+        for (i, offset) in offsets.iter().enumerate() {
+            if let Some(offset) = offset {
+                let flag = if i == 0 || (prefixed && i == 1) { CODE } else { OPERAND };
+                self.mark(*offset, flag);
+            }
+        }
+        self.instruction.set((address, offsets.len() as u16));
+    }
+
+    pub fn log_read(&self, address: u16, offset: usize, value: u8) {
+        let (start, length) = self.instruction.get();
+        if address.wrapping_sub(start) < length {
+            return;
+        }
+        self.mark(offset, DATA);
+        self.last_read.set(Some((offset, value)));
+    }
+
+    // VRAMのタイルデータへの書き込み: 直前にROMから読んだ値と同じならグラフィックとみなす
+    pub fn log_tile_write(&self, value: u8) {
+        if let Some((offset, last)) = self.last_read.take() {
+            if last == value {
+                self.mark(offset, GRAPHICS);
+            }
+        }
+    }
+
+    // HDMAでROMからVRAMに転送したバイト
+    pub fn log_graphics(&self, offset: usize) {
+        self.mark(offset, DATA | GRAPHICS);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        fs::write(&self.path, &*self.flags.borrow()).map_err(|e| format!("{}: {}", self.path, e))?;
+        let summary_path = format!("{}.txt", self.path);
+        fs::write(&summary_path, self.summary()).map_err(|e| format!("{}: {}", summary_path, e))
+    }
+
+    // バンクごとの集計
+    pub fn summary(&self) -> String {
+        let flags = self.flags.borrow();
+        let mut text = String::from("bank   code  operand  data  graphics  unused  coverage\n");
+        for (bank, chunk) in flags.chunks(0x4000).enumerate() {
+            let count = |flag: u8| chunk.iter().filter(|&&f| f & flag != 0).count();
+            let unused = chunk.iter().filter(|&&f| f == 0).count();
+            text += &format!(
+                "{:02X}   {:6} {:8} {:5} {:9} {:7} {:8.1}%\n",
+                bank,
+                count(CODE),
+                count(OPERAND),
+                count(DATA),
+                count(GRAPHICS),
+                unused,
+                (chunk.len() - unused) as f64 * 100.0 / chunk.len() as f64
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn flags(cdl: &CodeDataLog, range: std::ops::Range<usize>) -> Vec<u8> {
+        cdl.flags.borrow()[range].to_vec()
+    }
+
+    #[test]
+    fn test_flags() {
+        let cdl = CodeDataLog::open(&temp_path("missing.cdl"), 0x8000).unwrap();
+        // LD A,($4567): 先頭だけがコード
+        cdl.log_instruction(0x0150, &[Some(0x0150), Some(0x0151), Some(0x0152)], false);
+        assert_eq!(flags(&cdl, 0x0150..0x0153), [CODE, OPERAND, OPERAND]);
+        // 実行中の命令のオペランドはデータとして数えない
+        cdl.log_read(0x0151, 0x0151, 0x67);
+        assert_eq!(flags(&cdl, 0x0151..0x0152), [OPERAND]);
+        cdl.log_read(0x4567, 0x4567, 0x12);
+        assert_eq!(flags(&cdl, 0x4567..0x4568), [DATA]);
+        // 直前に読んだ値と同じ値のタイルへの書き込みだけがグラフィック
+        cdl.log_tile_write(0x13);
+        assert_eq!(flags(&cdl, 0x4567..0x4568), [DATA]);
+        cdl.log_read(0x4568, 0x4568, 0x34);
+        cdl.log_tile_write(0x34);
+        cdl.log_tile_write(0x34);
+        assert_eq!(flags(&cdl, 0x4568..0x4569), [DATA | GRAPHICS]);
+
+        // CBプレフィックスの命令は2バイトともコード, ROM外のバイトは記録しない
+        cdl.log_instruction(0x0200, &[Some(0x0200), Some(0x0201)], true);
+        cdl.log_instruction(0xC000, &[None, None, None], false);
+        assert_eq!(flags(&cdl, 0x0200..0x0202), [CODE, CODE]);
+        cdl.log_graphics(0x7FFF);
+        cdl.log_graphics(0x8000);
+        assert_eq!(flags(&cdl, 0x7FFF..0x8000), [DATA | GRAPHICS]);
+        // 範囲外を指しても何もしない
+        assert_eq!(cdl.flags.borrow().len(), 0x8000);
+    }
+
+    #[test]
+    fn test_summary() {
+        let cdl = CodeDataLog::open(&temp_path("summary.cdl"), 0x8000).unwrap();
+        // バンク0: 3バイトの命令を0x400個 (0x0C00バイト), 0x400バイトのデータ
+        for i in 0..0x400 {
+            let offset = i * 3;
+            cdl.log_instruction(offset as u16, &[Some(offset), Some(offset + 1), Some(offset + 2)], false);
+        }
+        for offset in 0x2000..0x2400 {
+            cdl.log_read(offset as u16, offset, 0);
+        }
+        // バンク1: 0x1000バイトのグラフィック
+        for offset in 0x4000..0x5000 {
+            cdl.log_graphics(offset);
+        }
+        assert_eq!(
+            cdl.summary(),
+            "bank   code  operand  data  graphics  unused  coverage\n\
+             00     1024     2048  1024         0   12288     25.0%\n\
+             01        0        0  4096      4096   12288     25.0%\n"
+        );
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let path = temp_path("resume.cdl");
+        let cdl = CodeDataLog::open(&path, 0x8000).unwrap();
+        cdl.log_instruction(0x0100, &[Some(0x0100)], false);
+        cdl.save().unwrap();
+        let summary = std::fs::read_to_string(format!("{}.txt", path)).unwrap();
+        assert!(summary.starts_with("bank"), "{}", summary);
+
+        // 既存のファイルの続きから記録する
+        let cdl = CodeDataLog::open(&path, 0x8000).unwrap();
+        assert_eq!(flags(&cdl, 0x0100..0x0101), [CODE]);
+        // ROMと大きさが違うファイルは使わない
+        assert!(CodeDataLog::open(&path, 0x10000).is_err());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.txt", path)).unwrap();
+    }
+}
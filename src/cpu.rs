@@ -4,8 +4,11 @@ use instruction::{ArithmeticTarget, Instruction, JumpTest, LoadType, StackTarget
 use memory_bus::MemoryBus;
 
 use crate::{
+    block_cache::{Alu, BlockCache, MicroOp, Op, R16, R8},
     cartridge::{self, Cartridge},
-    instruction::{self, BYTES, CYCLE, CYCLE_2, CYCLE_PREFIXED}, memory_bus,
+    instruction::{self, BYTES}, memory_bus,
+    model::Model,
+    state::{self, StateReader, StateWriter},
 };
 
 pub struct Registers {
@@ -59,24 +62,67 @@ pub(crate) struct FlagsRegister {
 }
 
 impl Registers {
+    // 電源投入直後 (ブートROMを実行する場合)
     fn new() -> Self {
         Registers {
-            a: 0x01,
+            a: 0x00,
             b: 0x00,
-            c: 0x13,
+            c: 0x00,
             d: 0x00,
-            e: 0xD8,
-            f: FlagsRegister {
-                zero: true,
-                subtract: false,
-                half_carry: false,
-                carry: false,
-            },
-            h: 0x01,
-            l: 0x4D,
+            e: 0x00,
+            f: FlagsRegister::from(0x00),
+            h: 0x00,
+            l: 0x00,
         }
     }
 
+    // ブートROM終了直後の値 (モデルとカートリッジヘッダによって変わる)
+    fn post_boot(model: Model, cartridge: &Cartridge) -> Self {
+        let header_checksum = cartridge.read_byte(0x014D);
+        let cgb_mode = cartridge.read_byte(0x0143) & 0x80 != 0;
+        let mut registers = Registers::new();
+        let (af, bc, de, hl) = match model {
+            Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            // ヘッダチェックサムが0でなければHとCが立つ
+            Model::DMG | Model::MGB => {
+                let a = if model == Model::MGB { 0xFF } else { 0x01 };
+                let f = if header_checksum == 0 { 0x80 } else { 0xB0 };
+                (a << 8 | f, 0x0013, 0x00D8, 0x014D)
+            }
+            Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::CGB | Model::AGB if cgb_mode => {
+                let (f, b) = if model == Model::AGB { (0x00, 0x01) } else { (0x80, 0x00) };
+                (0x1100 | f, b << 8, 0xFF56, 0x000D)
+            }
+            // CGBでのDMG互換モード: Bは任天堂ライセンスのタイトルのチェックサム
+            Model::CGB | Model::AGB => {
+                let licensee = cartridge.read_byte(0x014B);
+                let nintendo = licensee == 0x01
+                    || (licensee == 0x33
+                        && cartridge.read_byte(0x0144) == b'0'
+                        && cartridge.read_byte(0x0145) == b'1');
+                let mut b: u16 = 0;
+                if nintendo {
+                    for addr in 0x0134..=0x0143 {
+                        b = (b + cartridge.read_byte(addr) as u16) & 0xFF;
+                    }
+                }
+                let f = if model == Model::AGB { 0x00 } else { 0x80 };
+                if model == Model::AGB {
+                    b = (b + 1) & 0xFF;
+                }
+                let hl = if b == 0x43 || b == 0x58 { 0x991A } else { 0x007C };
+                (0x1100 | f, b << 8, 0x0008, hl)
+            }
+        };
+        registers.set_af(af);
+        registers.set_bc(bc);
+        registers.set_de(de);
+        registers.set_hl(hl);
+        registers
+    }
+
     fn get_af(&self) -> u16 {
         (self.a as u16) << 8 | u8::from(self.f) as u16
     }
@@ -121,25 +167,146 @@ pub struct CPU {
     pub sp: u16,
     pub bus: MemoryBus,
     pub is_halted: bool,
-    cycle2_flag: bool,
-    jp_flag: bool,
     pub ime: bool,
+    cycles: u16, // 実行中の命令で進めたサイクル数
+    pub blocks: Option<BlockCache>, // Someならデコード済みの命令をキャッシュして実行する
+    fetched: [u8; 3], // ブロックキャッシュから取り出した実行中の命令のバイト列
+    fetched_len: u8,
+    fetched_pos: u8,
+    batching: bool, // ブロックキャッシュで実行中: メモリアクセスまで他の部品を進めずにサイクルを溜める
+    deferred: u16, // 溜めていて他の部品にまだ渡していないサイクル数
+    #[cfg(test)]
+    pub bus_log: Option<Vec<Option<(u16, u8, bool)>>>, // テスト用: Mサイクルごとのバスアクセス (アドレス, 値, 書き込みか)
 }
 
 impl CPU {
-    pub fn new(cartridge: Cartridge) -> Self {
+    pub fn new(cartridge: Cartridge, model: Model, boot_rom: Option<Vec<u8>>) -> Self {
+        // ブートROMがなければ実行後の状態から始める
+        let (registers, pc, sp) = match boot_rom {
+            Some(_) => (Registers::new(), 0x0000, 0x0000),
+            None => (Registers::post_boot(model, &cartridge), 0x0100, 0xFFFE),
+        };
         CPU {
-            registers: Registers::new(),
-            pc: 0x0100,
-            sp: 0xFFFE,
-            bus: MemoryBus::new(cartridge),
+            registers,
+            pc,
+            sp,
+            bus: MemoryBus::new(cartridge, model, boot_rom),
             is_halted: false,
-            cycle2_flag: false,
-            jp_flag: false,
             ime: false,
+            cycles: 0,
+            blocks: None,
+            fetched: [0; 3],
+            fetched_len: 0,
+            fetched_pos: 0,
+            batching: false,
+            deferred: 0,
+            #[cfg(test)]
+            bus_log: None,
         }
     }
 
+    // マシン全体の状態をセーブステートとして書き出す
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        state::write_header(&mut w, self.bus.catridge.checksum(), self.bus.model as u8);
+        let r = &self.registers;
+        for value in [r.a, r.b, r.c, r.d, r.e, u8::from(r.f), r.h, r.l] {
+            w.u8(value);
+        }
+        w.u16(self.pc);
+        w.u16(self.sp);
+        w.bool(self.ime);
+        w.bool(self.is_halted);
+        self.bus.save_state(&mut w);
+        w.data
+    }
+
+    // 読み込みに失敗したら元の状態に戻す
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data);
+        state::read_header(&mut r, self.bus.catridge.checksum(), self.bus.model as u8)?;
+        let backup = self.save_state();
+        if let Err(e) = self.load_state_body(&mut r) {
+            let mut r = StateReader::new(&backup);
+            state::read_header(&mut r, self.bus.catridge.checksum(), self.bus.model as u8)?;
+            self.load_state_body(&mut r)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_state_body(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.registers.a = r.u8()?;
+        self.registers.b = r.u8()?;
+        self.registers.c = r.u8()?;
+        self.registers.d = r.u8()?;
+        self.registers.e = r.u8()?;
+        self.registers.f = FlagsRegister::from(r.u8()?);
+        self.registers.h = r.u8()?;
+        self.registers.l = r.u8()?;
+        self.pc = r.u16()?;
+        self.sp = r.u16()?;
+        self.ime = r.bool()?;
+        self.is_halted = r.bool()?;
+        if let Some(blocks) = &mut self.blocks {
+            blocks.clear(&mut self.bus);
+        }
+        self.bus.load_state(r)
+    }
+
+    // デバッガ用: 名前でレジスタとフラグ (ZF, NF, HF, CF) を読み書きする
+    pub fn register(&self, name: &str) -> Option<u16> {
+        let r = &self.registers;
+        let value = match name.to_ascii_uppercase().as_str() {
+            "A" => r.a as u16,
+            "B" => r.b as u16,
+            "C" => r.c as u16,
+            "D" => r.d as u16,
+            "E" => r.e as u16,
+            "F" => u8::from(r.f) as u16,
+            "H" => r.h as u16,
+            "L" => r.l as u16,
+            "AF" => r.get_af(),
+            "BC" => r.get_bc(),
+            "DE" => r.get_de(),
+            "HL" => r.get_hl(),
+            "SP" => self.sp,
+            "PC" => self.pc,
+            "ZF" => r.f.zero as u16,
+            "NF" => r.f.subtract as u16,
+            "HF" => r.f.half_carry as u16,
+            "CF" => r.f.carry as u16,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn set_register(&mut self, name: &str, value: u16) -> bool {
+        let r = &mut self.registers;
+        match name.to_ascii_uppercase().as_str() {
+            "A" => r.a = value as u8,
+            "B" => r.b = value as u8,
+            "C" => r.c = value as u8,
+            "D" => r.d = value as u8,
+            "E" => r.e = value as u8,
+            "F" => r.f = FlagsRegister::from(value as u8),
+            "H" => r.h = value as u8,
+            "L" => r.l = value as u8,
+            "AF" => r.set_af(value),
+            "BC" => r.set_bc(value),
+            "DE" => r.set_de(value),
+            "HL" => r.set_hl(value),
+            "SP" => self.sp = value,
+            "PC" => self.pc = value,
+            "ZF" => r.f.zero = value != 0,
+            "NF" => r.f.subtract = value != 0,
+            "HF" => r.f.half_carry = value != 0,
+            "CF" => r.f.carry = value != 0,
+            _ => return false,
+        }
+        true
+    }
+
     fn execute(&mut self, instruction: Instruction) {
         match instruction {
             Instruction::NOP => {},
@@ -183,7 +350,12 @@ impl CPU {
             Instruction::RST(address) => self.rst(address),
             Instruction::RET(test) => self.ret(test),
             Instruction::RETI => self.reti(),
-            Instruction::STOP => {}, //TODO
+            Instruction::STOP => {
+                // 2バイト命令として次のバイトを読み飛ばす
+                self.pc = self.pc.wrapping_add(1);
+                self.flush();
+                self.bus.stop();
+            }
             Instruction::HALT => {}, //TODO
             Instruction::DI => self.di(),
             Instruction::EI => self.ei(),
@@ -208,19 +380,25 @@ impl CPU {
             }
             LoadType::WORD(target, source) => {
                 let source_value = self.read_registers_arithmeticTarget(source);
-                match target {
-                    ArithmeticTarget::SPA => {
-                        let r = (self.sp & 0x00FF) as u8;
-                        let value = self.read_next_byte() as i8;
-                        let (new_value, did_overflow) = self.sp.overflowing_add(value as u16);
-                        self.registers.f.zero = false;
-                        self.registers.f.subtract = false;
-                        self.registers.f.carry = did_overflow;
-                        self.registers.f.half_carry = (r & 0xF) + (value as u8 & 0xF) > 0xF;
+                match (target, source) {
+                    // LD HL,SP+e8
+                    (_, ArithmeticTarget::SPA) => {
+                        let new_value = self.sp_plus_e8();
+                        self.tick();
                         self.change_registers_arithmeticTarget(target, new_value);
                         // self.pc.wrapping_add(2)
                     }
+                    // LD (a16),SP は2バイト書き込む
+                    (ArithmeticTarget::D16_, _) => {
+                        let address = self.fetch_word();
+                        self.write(address, source_value as u8);
+                        self.write(address.wrapping_add(1), (source_value >> 8) as u8);
+                    }
                     _ => {
+                        // LD SP,HLは16bitの転送に1サイクルかかる
+                        if target == ArithmeticTarget::SP && source == ArithmeticTarget::HL {
+                            self.tick();
+                        }
                         self.change_registers_arithmeticTarget(target, source_value);
                         // self.pc.wrapping_add(3)
                     }
@@ -248,6 +426,7 @@ impl CPU {
             }
             false => {
                 let (new_value, did_overflow) = value.overflowing_add(1);
+                self.tick();
                 self.change_registers_arithmeticTarget(target, new_value);
             }
         };
@@ -270,6 +449,7 @@ impl CPU {
             }
             false => {
                 let (new_value, did_overflow) = value.overflowing_sub(1);
+                self.tick();
                 self.change_registers_arithmeticTarget(target, new_value);
             }
         };
@@ -279,30 +459,14 @@ impl CPU {
     fn add(&mut self, target: ArithmeticTarget) {
         match target {
             ArithmeticTarget::SP => {
-                let r = (self.sp & 0x00FF) as u8;
-                let value = self.read_next_byte() as i8;
-                let (new_value, did_overflow) = self.sp.overflowing_add(value as u16);
-                self.change_flag(
-                    false,
-                    false,
-                    (r & 0xF) + (value as u8 & 0xF) > 0xF,
-                    did_overflow,
-                );
-
-                self.sp = new_value as u16;
+                self.sp = self.sp_plus_e8();
+                self.tick();
+                self.tick();
                 // self.pc.wrapping_add(2)
             }
             _ => {
                 let value = self.read_registers_arithmeticTarget(target) as u8;
-                let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
-                self.change_flag(
-                    new_value == 0,
-                    false,
-                    (self.registers.a & 0xF) + (value & 0xF) > 0xF,
-                    did_overflow,
-                );
-
-                self.registers.a = new_value;
+                self.add_value(value);
 
                 // match target {
                 //     ArithmeticTarget::D8 => self.pc.wrapping_add(2),
@@ -312,15 +476,32 @@ impl CPU {
         }
     }
 
+    fn add_value(&mut self, value: u8) {
+        let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
+        self.change_flag(
+            new_value == 0,
+            false,
+            (self.registers.a & 0xF) + (value & 0xF) > 0xF,
+            did_overflow,
+        );
+
+        self.registers.a = new_value;
+    }
+
     fn adc(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.adc_value(value);
+    }
+
+    fn adc_value(&mut self, value: u8) {
         let carry_inc: u8 = if self.registers.f.carry { 1 } else { 0 };
-        let (new_value, did_overflow) = self.registers.a.overflowing_add(value.wrapping_add(carry_inc));
+        let result = self.registers.a as u16 + value as u16 + carry_inc as u16;
+        let new_value = result as u8;
         self.change_flag(
             new_value == 0,
             false,
             (self.registers.a & 0xF) + (value & 0xF) + carry_inc > 0xF,
-            did_overflow,
+            result > 0xFF,
         );
 
         self.registers.a = new_value;
@@ -334,6 +515,7 @@ impl CPU {
     fn addhl(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target);
         let (new_value, did_overflow) = self.registers.get_hl().overflowing_add(value);
+        self.tick();
         self.registers.f.subtract = false;
         self.registers.f.carry = did_overflow;
         self.registers.f.half_carry =
@@ -344,11 +526,15 @@ impl CPU {
 
     fn sub(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.sub_value(value);
+    }
+
+    fn sub_value(&mut self, value: u8) {
         let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
         self.change_flag(
             new_value == 0,
-            false,
-            (self.registers.a & 0x0F) < 0x0F + (value & 0x0F),
+            true,
+            (self.registers.a & 0x0F) < (value & 0x0F),
             did_overflow,
         );
 
@@ -361,13 +547,18 @@ impl CPU {
 
     fn sbc(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.sbc_value(value);
+    }
+
+    fn sbc_value(&mut self, value: u8) {
         let carry_inc: u8 = if self.registers.f.carry { 1 } else { 0 };
-        let (new_value, did_overflow) = self.registers.a.overflowing_sub(value.wrapping_sub(carry_inc));
+        let result = self.registers.a as i16 - value as i16 - carry_inc as i16;
+        let new_value = result as u8;
         self.change_flag(
             new_value == 0,
-            false,
-            (self.registers.a & 0x0F) < 0x0F + (value & 0x0F) + carry_inc,
-            did_overflow,
+            true,
+            (self.registers.a & 0x0F) < (value & 0x0F) + carry_inc,
+            result < 0,
         );
 
         self.registers.a = new_value;
@@ -379,11 +570,15 @@ impl CPU {
 
     fn cp(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.cp_value(value);
+    }
+
+    fn cp_value(&mut self, value: u8) {
         let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
         self.change_flag(
             new_value == 0,
-            false,
-            (self.registers.a & 0x0F) < 0x0F + (value & 0x0F),
+            true,
+            (self.registers.a & 0x0F) < (value & 0x0F),
             did_overflow,
         );
 
@@ -393,12 +588,37 @@ impl CPU {
         // }
     }
 
+    // 直前の加算/減算の結果をBCDに補正する (N, H, Cフラグを使う)
     fn daa(&mut self) {
+        let mut a = self.registers.a;
+        let mut carry = self.registers.f.carry;
+        if self.registers.f.subtract {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if self.registers.f.half_carry {
+                a = a.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.registers.f.half_carry || a & 0x0F > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        }
+        self.registers.a = a;
+        self.change_flag(a == 0, self.registers.f.subtract, false, carry);
         // self.pc.wrapping_add(1)
     }
 
     fn and(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.and_value(value);
+    }
+
+    fn and_value(&mut self, value: u8) {
         self.registers.a &= value;
         self.change_flag(self.registers.a == 0, false, true, false);
         // match target {
@@ -409,6 +629,10 @@ impl CPU {
 
     fn or(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.or_value(value);
+    }
+
+    fn or_value(&mut self, value: u8) {
         self.registers.a |= value;
         self.change_flag(self.registers.a == 0, false, false, false);
         // match target {
@@ -419,6 +643,10 @@ impl CPU {
 
     fn xor(&mut self, target: ArithmeticTarget) {
         let value = self.read_registers_arithmeticTarget(target) as u8;
+        self.xor_value(value);
+    }
+
+    fn xor_value(&mut self, value: u8) {
         self.registers.a ^= value;
         self.change_flag(self.registers.a == 0, false, false, false);
         // match target {
@@ -598,30 +826,24 @@ impl CPU {
         // self.pc.wrapping_add(1)
     }
 
+    // 分岐する場合だけpcを書き換える内部サイクルが1つ増える
     fn jump(&mut self, test: JumpTest) {
+        let address = self.fetch_word();
         if self.should_jump(test) {
-            self.pc = self.read_next_word();
-            self.jp_flag = true;
-            // self.read_next_word()
-        } else {
-            self.cycle2_flag = true;
-            // self.pc.wrapping_add(3)
+            self.tick();
+            self.pc = address;
         }
     }
 
     fn jumphl(&mut self) {
         self.pc = self.registers.get_hl();
-        self.jp_flag = true;
     }
 
     fn jr(&mut self, test: JumpTest) {
+        let value = self.fetch_byte() as i8;
         if self.should_jump(test) {
-            let value = self.read_next_byte() as i8;
-            self.pc = self.pc.wrapping_add(2).wrapping_add(value as u16);
-            self.jp_flag = true;
-        } else {
-            self.cycle2_flag = true;
-            // self.pc.wrapping_add(2)
+            self.tick();
+            self.pc = self.pc.wrapping_add(value as u16);
         }
     }
 
@@ -636,18 +858,20 @@ impl CPU {
                 panic!("TODO: support more targets")
             }
         };
+        // SPを減らす内部サイクルの後に上位バイトから書き込む
+        self.tick();
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write(self.sp, ((value & 0xFF00) >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0x00FF) as u8);
+        self.write(self.sp, (value & 0x00FF) as u8);
         // self.pc.wrapping_add(1)
     }
 
     fn pop(&mut self, target: StackTarget) {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
         let result = (msb << 8) | lsb;
@@ -663,38 +887,34 @@ impl CPU {
     }
 
     fn call(&mut self, test: JumpTest) {
-        // self.call(jump_condition)
-        let next_pc = self.pc.wrapping_add(3);
-
+        let address = self.fetch_word();
         if self.should_jump(test) {
-            self.push(StackTarget::D16(next_pc));
-            self.pc = self.read_next_word();
-            self.jp_flag = true;
-        } else {
-            self.cycle2_flag = true;
-            // next_pc
+            self.push(StackTarget::D16(self.pc));
+            self.pc = address;
         }
     }
 
     fn rst(&mut self, address: u16) {
-        let next_pc = self.pc.wrapping_add(3);
-
-        self.push(StackTarget::D16(next_pc));
-        self.pc = address
+        self.push(StackTarget::D16(self.pc));
+        self.pc = address;
     }
 
+    // 条件付きのRETは条件の判定に1サイクルかかる
     fn ret(&mut self, test: JumpTest) {
+        let conditional = !matches!(test, JumpTest::Always);
+        if conditional {
+            self.tick();
+        }
         if self.should_jump(test) {
             self.pop(StackTarget::NONE);
-            self.jp_flag = true;
-        } else {
-            self.cycle2_flag = true;
-            // self.pc.wrapping_add(1)
+            self.tick();
         }
     }
 
     fn reti(&mut self) {
-        self.pop(StackTarget::NONE)
+        self.pop(StackTarget::NONE);
+        self.tick();
+        self.ime = true;
     }
 
     fn halt(&mut self) {
@@ -712,60 +932,368 @@ impl CPU {
         // self.pc.wrapping_add(1)
     }
 
-    pub fn step(&mut self) {
+    // 1命令実行して, かかったサイクル数を返す
+    // メモリアクセスと内部処理の1Mサイクルごとに他の部品を進める
+    pub fn step(&mut self) -> u16 {
+        self.cycles = 0;
+        self.run();
+        // HDMAの転送中はCPUが止まる
+        loop {
+            let stall = self.bus.take_stall();
+            if stall == 0 {
+                break;
+            }
+            self.bus.tick(stall);
+            self.cycles += stall;
+        }
+        self.cycles
+    }
+
+    fn run(&mut self) {
         if self.is_halted {
+            self.tick();
+            return;
+        }
+
+        if let Some(cdl) = &self.bus.cdl {
+            let opcode = self.bus.peek(self.pc);
+            let length = if opcode == 0xCB { 2 } else { BYTES[opcode as usize] };
+            let offsets: Vec<Option<usize>> = (0..length)
+                .map(|i| self.bus.rom_offset(self.pc.wrapping_add(i)))
+                .collect();
+            cdl.log_instruction(self.pc, &offsets, opcode == 0xCB);
+        }
+
+        if let Some(op) = self.fetch_cached() {
+            self.batching = true;
+            self.execute_op(op);
+            self.batching = false;
+            self.flush();
             return;
         }
 
-        let mut instruction_byte = self.bus.read_byte(self.pc);
+        let mut instruction_byte = self.fetch_byte();
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            self.pc = self.pc.wrapping_add(1);
-            instruction_byte = self.bus.read_byte(self.pc);
+            instruction_byte = self.fetch_byte();
         }
 
-        self.cycle2_flag = false;
-        self.jp_flag = false;
-        let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed)
-        {
+        if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
             self.execute(instruction);
-            let mut cycles = CYCLE[instruction_byte as usize];
-            if self.cycle2_flag {
-                cycles = CYCLE_2[instruction_byte as usize]
-            } else if prefixed {
-                cycles = CYCLE_PREFIXED[instruction_byte as usize];
-            }
-            self.bus.gpu.update(cycles);
-            if self.jp_flag {
-                self.pc 
-            } else {
-                self.pc.wrapping_add(BYTES[instruction_byte as usize])
-            }
         } else {
             let description = format!(
                 "0x{}{:x}",
                 if prefixed { "CB" } else { "" },
                 instruction_byte
             );
-            panic!("Unkown instruction found for : 0x{:x}", instruction_byte);
-        };
-
-        self.pc = next_pc;
-        self.bus.gpu.update(1);
+            panic!("Unkown instruction found for : {}", description);
+        }
         // println!("pc:0x{:04X?}, sp:0x{:04X?}, bc:0x{:04X?}, de:0x{:04X?}, hl:0x{:04X?}, af:0x{:04X?}, 0xD943:0x{:02X?}", 
         //         self.pc, self.sp, self.registers.get_bc(), self.registers.get_de(), self.registers.get_hl(), self.registers.get_af(), self.bus.read_byte(0xD943));
     }
 
-    fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+    // ブロックキャッシュ: 今のブロックの終わりまで実行して, かかったサイクル数を返す
+    // 命令の間では, 次のイベント (PPUのモードの切り替わりなど) に達するまで他の部品を進めない
+    // 分岐したとき, フレームが終わったとき, コードやバンクが書き換えられたときはそこで止める
+    // キャッシュできない場所では1命令だけ実行する
+    pub fn step_block(&mut self) -> u32 {
+        if self.is_halted {
+            return self.step() as u32;
+        }
+        let found = match &mut self.blocks {
+            Some(blocks) if self.bus.cache_usable() => blocks.fetch_block(&mut self.bus, self.pc),
+            _ => None,
+        };
+        let (block, start) = match found {
+            Some(found) => found,
+            None => return self.step() as u32,
+        };
+        let frame = self.bus.gpu.frames;
+        let mut total = 0;
+        let mut index = start;
+        self.batching = true;
+        for op in &block.ops[start..] {
+            self.cycles = 0;
+            self.execute_op(*op);
+            index += 1;
+            total += self.cycles as u32;
+            if !self.bus.can_defer(self.deferred) {
+                self.flush();
+                // HDMAの転送中はCPUが止まる
+                loop {
+                    let stall = self.bus.take_stall();
+                    if stall == 0 {
+                        break;
+                    }
+                    self.bus.tick(stall);
+                    total += stall as u32;
+                }
+            }
+            // フレームはメモリアクセスの前に渡したサイクルで終わることもある
+            let next = op.address.wrapping_add(op.length as u16);
+            if self.pc != next
+                || self.bus.gpu.frames != frame
+                || self.bus.dirty_pages != 0
+                || self.bus.bank_switched
+                || self.bus.oam_dma_active()
+            {
+                break;
+            }
+        }
+        self.batching = false;
+        self.flush();
+        if let Some(blocks) = &mut self.blocks {
+            blocks.set_position(block, index);
+        }
+        total
     }
 
-    fn read_next_word(&self) -> u16 {
-        let l = self.bus.read_byte(self.pc + 1) as u16;
-        let u = self.bus.read_byte(self.pc + 2) as u16;
+    fn fetch_cached(&mut self) -> Option<Op> {
+        match &mut self.blocks {
+            Some(blocks) if self.bus.cache_usable() => blocks.fetch(&mut self.bus, self.pc),
+            _ => None,
+        }
+    }
+
+    // ブロックキャッシュの命令を実行する
+    // フェッチはメモリを読まずにサイクルだけ進める
+    fn execute_op(&mut self, op: Op) {
+        let micro = match op.micro {
+            MicroOp::Other(instruction) => {
+                self.fetched = op.bytes;
+                self.fetched_len = op.length;
+                self.fetched_pos = 0;
+                self.fetch_byte();
+                if op.bytes[0] == 0xCB {
+                    self.fetch_byte();
+                }
+                self.execute(instruction);
+                self.fetched_len = 0;
+                return;
+            }
+            micro => micro,
+        };
+        let fetch = 4 * op.length as u16;
+        self.cycles += fetch;
+        self.deferred += fetch;
+        self.pc = op.address.wrapping_add(op.length as u16);
+        match micro {
+            MicroOp::Nop => {}
+            MicroOp::Ld(target, source) => {
+                let value = self.r8(source);
+                self.set_r8(target, value);
+            }
+            MicroOp::LdImm(target, value) => self.set_r8(target, value),
+            MicroOp::LdFromHl(target) => {
+                let value = self.read(self.registers.get_hl());
+                self.set_r8(target, value);
+            }
+            MicroOp::LdToHl(source) => self.write(self.registers.get_hl(), self.r8(source)),
+            MicroOp::LdHlImm(value) => self.write(self.registers.get_hl(), value),
+            MicroOp::LdFromMem(address) => self.registers.a = self.read(address),
+            MicroOp::LdToMem(address) => self.write(address, self.registers.a),
+            MicroOp::LdFromR16(source) => self.registers.a = self.read(self.r16(source)),
+            MicroOp::LdToR16(target) => self.write(self.r16(target), self.registers.a),
+            MicroOp::LdFromHlStep(step) => {
+                let hl = self.registers.get_hl();
+                self.registers.a = self.read(hl);
+                self.registers.set_hl(hl.wrapping_add(step));
+            }
+            MicroOp::LdToHlStep(step) => {
+                let hl = self.registers.get_hl();
+                self.write(hl, self.registers.a);
+                self.registers.set_hl(hl.wrapping_add(step));
+            }
+            MicroOp::LdImm16(target, value) => self.set_r16(target, value),
+            MicroOp::Alu(alu, source) => self.alu(alu, self.r8(source)),
+            MicroOp::AluImm(alu, value) => self.alu(alu, value),
+            MicroOp::AluHl(alu) => {
+                let value = self.read(self.registers.get_hl());
+                self.alu(alu, value);
+            }
+            MicroOp::Inc(target) => {
+                let value = self.r8(target);
+                let new_value = value.wrapping_add(1);
+                self.change_flag(new_value == 0, false, value & 0x0F == 0x0F, self.registers.f.carry);
+                self.set_r8(target, new_value);
+            }
+            MicroOp::Dec(target) => {
+                let value = self.r8(target);
+                let new_value = value.wrapping_sub(1);
+                self.change_flag(new_value == 0, true, value & 0x0F == 0x00, self.registers.f.carry);
+                self.set_r8(target, new_value);
+            }
+            MicroOp::Inc16(target) => {
+                self.tick();
+                self.set_r16(target, self.r16(target).wrapping_add(1));
+            }
+            MicroOp::Dec16(target) => {
+                self.tick();
+                self.set_r16(target, self.r16(target).wrapping_sub(1));
+            }
+            MicroOp::Jr(test, address) | MicroOp::Jp(test, address) => {
+                if self.should_jump(test) {
+                    self.tick();
+                    self.pc = address;
+                }
+            }
+            MicroOp::Call(test, address) => {
+                if self.should_jump(test) {
+                    self.push(StackTarget::D16(self.pc));
+                    self.pc = address;
+                }
+            }
+            MicroOp::Ret(test) => self.ret(test),
+            MicroOp::Push(target) => self.push(target),
+            MicroOp::Pop(target) => self.pop(target),
+            MicroOp::Other(_) => unreachable!(),
+        }
+    }
+
+    fn r8(&self, r: R8) -> u8 {
+        match r {
+            R8::A => self.registers.a,
+            R8::B => self.registers.b,
+            R8::C => self.registers.c,
+            R8::D => self.registers.d,
+            R8::E => self.registers.e,
+            R8::H => self.registers.h,
+            R8::L => self.registers.l,
+        }
+    }
+
+    fn set_r8(&mut self, r: R8, value: u8) {
+        match r {
+            R8::A => self.registers.a = value,
+            R8::B => self.registers.b = value,
+            R8::C => self.registers.c = value,
+            R8::D => self.registers.d = value,
+            R8::E => self.registers.e = value,
+            R8::H => self.registers.h = value,
+            R8::L => self.registers.l = value,
+        }
+    }
+
+    fn r16(&self, r: R16) -> u16 {
+        match r {
+            R16::BC => self.registers.get_bc(),
+            R16::DE => self.registers.get_de(),
+            R16::HL => self.registers.get_hl(),
+            R16::SP => self.sp,
+        }
+    }
+
+    fn set_r16(&mut self, r: R16, value: u16) {
+        match r {
+            R16::BC => self.registers.set_bc(value),
+            R16::DE => self.registers.set_de(value),
+            R16::HL => self.registers.set_hl(value),
+            R16::SP => self.sp = value,
+        }
+    }
+
+    fn alu(&mut self, alu: Alu, value: u8) {
+        match alu {
+            Alu::Add => self.add_value(value),
+            Alu::Adc => self.adc_value(value),
+            Alu::Sub => self.sub_value(value),
+            Alu::Sbc => self.sbc_value(value),
+            Alu::And => self.and_value(value),
+            Alu::Xor => self.xor_value(value),
+            Alu::Or => self.or_value(value),
+            Alu::Cp => self.cp_value(value),
+        }
+    }
+
+    // 1Mサイクル (4クロック) 他の部品を進める
+    fn tick(&mut self) {
+        self.cycles += 4;
+        if self.batching {
+            self.deferred += 4;
+        } else {
+            self.bus.tick(4);
+        }
+        #[cfg(test)]
+        if let Some(log) = &mut self.bus_log {
+            log.push(None);
+        }
+    }
+
+    // メモリアクセスは1Mサイクル進めてから行う
+    fn read(&mut self, address: u16) -> u8 {
+        self.tick();
+        // 時刻で変わらない場所なら溜めたサイクルはそのままでよい
+        if !MemoryBus::is_static(address) {
+            self.flush();
+        }
+        self.bus.sync(address);
+        let value = self.bus.read_byte(address);
+        #[cfg(test)]
+        self.log_access(address, value, false);
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.tick();
+        // WRAM, HRAMへの書き込みは, 溜めたサイクルの間に何も起きないならそのままでよい
+        if address < 0xC000 || !MemoryBus::is_static(address) || !self.bus.can_defer(self.deferred) {
+            self.flush();
+        }
+        self.bus.write_byte(address, value);
+        #[cfg(test)]
+        self.log_access(address, value, true);
+    }
+
+    // 溜めたサイクルをまとめて他の部品に渡す
+    fn flush(&mut self) {
+        if self.deferred > 0 {
+            self.bus.tick(self.deferred);
+            self.deferred = 0;
+        }
+    }
+
+    // テスト用: 今のMサイクルのバスアクセスを記録する
+    #[cfg(test)]
+    fn log_access(&mut self, address: u16, value: u8, write: bool) {
+        if let Some(Some(last)) = self.bus_log.as_mut().map(|log| log.last_mut()) {
+            *last = Some((address, value, write));
+        }
+    }
+
+    // pcの位置から読んでpcを進める
+    fn fetch_byte(&mut self) -> u8 {
+        // キャッシュ済みの命令ならメモリを読まずにサイクルだけ進める
+        if self.fetched_pos < self.fetched_len {
+            self.tick();
+            let value = self.fetched[self.fetched_pos as usize];
+            self.fetched_pos += 1;
+            self.pc = self.pc.wrapping_add(1);
+            return value;
+        }
+        let value = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let l = self.fetch_byte() as u16;
+        let u = self.fetch_byte() as u16;
         (u << 8) | l
     }
 
+    // ADD SP,e8とLD HL,SP+e8: フラグは下位バイト同士の加算で決まる
+    fn sp_plus_e8(&mut self) -> u16 {
+        let value = self.fetch_byte();
+        let low = self.sp & 0x00FF;
+        self.change_flag(
+            false,
+            false,
+            (low & 0x0F) + (value as u16 & 0x0F) > 0x0F,
+            low + value as u16 > 0xFF,
+        );
+        self.sp.wrapping_add(value as i8 as u16)
+    }
+
     //レジスタやメモリの値を持ってくる
     fn read_registers_arithmeticTarget(&mut self, target: ArithmeticTarget) -> u16 {
         match target {
@@ -776,34 +1304,38 @@ impl CPU {
             ArithmeticTarget::E => self.registers.e as u16,
             ArithmeticTarget::H => self.registers.h as u16,
             ArithmeticTarget::L => self.registers.l as u16,
-            ArithmeticTarget::HL_ => self.bus.read_byte(self.registers.get_hl()) as u16,
+            ArithmeticTarget::HL_ => self.read(self.registers.get_hl()) as u16,
             ArithmeticTarget::HLi_ => {
-                let value = self.bus.read_byte(self.registers.get_hl()) as u16;
+                let value = self.read(self.registers.get_hl()) as u16;
                 self.registers
                     .set_hl((self.registers.get_hl()).wrapping_add(1));
                 value
             }
             ArithmeticTarget::HLd_ => {
-                let value = self.bus.read_byte(self.registers.get_hl()) as u16;
+                let value = self.read(self.registers.get_hl()) as u16;
                 self.registers
                     .set_hl((self.registers.get_hl()).wrapping_sub(1));
                 value
             }
-            ArithmeticTarget::BC_ => self.bus.read_byte(self.registers.get_bc()) as u16,
-            ArithmeticTarget::DE_ => self.bus.read_byte(self.registers.get_de()) as u16,
-            ArithmeticTarget::D8 => self.read_next_byte() as u16,
-            ArithmeticTarget::D16_ => self.bus.read_byte(self.read_next_word()) as u16,
+            ArithmeticTarget::BC_ => self.read(self.registers.get_bc()) as u16,
+            ArithmeticTarget::DE_ => self.read(self.registers.get_de()) as u16,
+            ArithmeticTarget::D8 => self.fetch_byte() as u16,
+            ArithmeticTarget::D16_ => {
+                let address = self.fetch_word();
+                self.read(address) as u16
+            }
             ArithmeticTarget::FD8_ => {
-                self.bus.read_byte(0xFF00 + self.read_next_byte() as u16) as u16
+                let address = 0xFF00 + self.fetch_byte() as u16;
+                self.read(address) as u16
             }
-            ArithmeticTarget::FDC_ => self.bus.read_byte(0xFF00 + self.registers.c as u16) as u16,
+            ArithmeticTarget::FDC_ => self.read(0xFF00 + self.registers.c as u16) as u16,
 
             //16bit
             ArithmeticTarget::BC => self.registers.get_bc(),
             ArithmeticTarget::DE => self.registers.get_de(),
             ArithmeticTarget::HL => self.registers.get_hl(),
             ArithmeticTarget::SP => self.sp,
-            ArithmeticTarget::D16 => self.read_next_word(),
+            ArithmeticTarget::D16 => self.fetch_word(),
             ArithmeticTarget::SPA => self.sp,
             // _ => panic!("TODO: support more targets")
         }
@@ -819,27 +1351,29 @@ impl CPU {
             ArithmeticTarget::E => self.registers.e = value as u8,
             ArithmeticTarget::H => self.registers.h = value as u8,
             ArithmeticTarget::L => self.registers.l = value as u8,
-            ArithmeticTarget::BC_ => self.bus.write_byte(self.registers.get_bc(), value as u8),
-            ArithmeticTarget::DE_ => self.bus.write_byte(self.registers.get_de(), value as u8),
-            ArithmeticTarget::HL_ => self.bus.write_byte(self.registers.get_hl(), value as u8),
+            ArithmeticTarget::BC_ => self.write(self.registers.get_bc(), value as u8),
+            ArithmeticTarget::DE_ => self.write(self.registers.get_de(), value as u8),
+            ArithmeticTarget::HL_ => self.write(self.registers.get_hl(), value as u8),
             ArithmeticTarget::HLi_ => {
-                self.bus.write_byte(self.registers.get_hl(), value as u8);
+                self.write(self.registers.get_hl(), value as u8);
                 self.registers
                     .set_hl((self.registers.get_hl()).wrapping_add(1));
             }
             ArithmeticTarget::HLd_ => {
-                self.bus.write_byte(self.registers.get_hl(), value as u8);
+                self.write(self.registers.get_hl(), value as u8);
                 self.registers
                     .set_hl((self.registers.get_hl()).wrapping_sub(1));
             }
             ArithmeticTarget::D16_ => {
-                self.bus.write_byte(self.read_next_word(), value as u8);
+                let address = self.fetch_word();
+                self.write(address, value as u8);
             },
             ArithmeticTarget::FD8_ => {
-                self.bus.write_byte(0xFF00 + self.read_next_byte() as u16, value as u8);
+                let address = 0xFF00 + self.fetch_byte() as u16;
+                self.write(address, value as u8);
             },
             ArithmeticTarget::FDC_ => {
-                self.bus.write_byte(0xFF00 + self.registers.c as u16, value as u8);
+                self.write(0xFF00 + self.registers.c as u16, value as u8);
             }
             // 16bit
             ArithmeticTarget::BC => self.registers.set_bc(value),
@@ -914,6 +1448,7 @@ impl CPU {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::debugger::Watchpoint;
 
     fn F(zero: bool, subtract: bool, half_carry: bool, carry: bool) -> FlagsRegister {
         FlagsRegister {
@@ -924,10 +1459,22 @@ mod test {
         }
     }
 
+    // レジスタが全て0でpc=0, メモリは64KBのRAM
+    // CPUは大きいのでテストのスタックに何個も置かないようにBoxで返す
+    fn new_cpu() -> Box<CPU> {
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]).unwrap();
+        let mut cpu = Box::new(CPU::new(cartridge, Model::DMG, None));
+        cpu.registers = Registers::new();
+        cpu.pc = 0x0000;
+        cpu.sp = 0x0000;
+        cpu.bus.flat = Some(vec![0; 0x10000]);
+        cpu
+    }
+
     #[test]
     fn test_inc() {
         // B
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x04);
         cpu.registers.b = 0x00;
         cpu.step();
@@ -936,7 +1483,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // B zero
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x04);
         cpu.registers.b = 0xFF;
         cpu.step();
@@ -945,7 +1492,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(true, false, true, false));
 
         // (HL)
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x34);
         cpu.bus.write_byte(0x1000, 0x00);
         cpu.registers.set_hl(0x1000);
@@ -955,7 +1502,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // BC
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x03);
         cpu.registers.set_bc(0x1000);
         cpu.step();
@@ -964,7 +1511,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // BC 8
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x03);
         cpu.registers.set_bc(0x00FF);
         cpu.step();
@@ -973,7 +1520,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // BC over
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x03);
         cpu.registers.set_bc(0xFFFF);
         cpu.step();
@@ -985,7 +1532,7 @@ mod test {
     #[test]
     fn test_dec() {
         // B
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x05);
         cpu.registers.b = 0x02;
         cpu.step();
@@ -994,7 +1541,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, true, false, false));
 
         // B zero
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x05);
         cpu.registers.b = 0x01;
         cpu.step();
@@ -1003,7 +1550,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(true, true, false, false));
 
         // (HL)
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x35);
         cpu.bus.write_byte(0x1000, 0x02);
         cpu.registers.set_hl(0x1000);
@@ -1013,7 +1560,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, true, false, false));
 
         // BC
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x0B);
         cpu.registers.set_bc(0x1002);
         cpu.step();
@@ -1022,7 +1569,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // BC 8
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x0B);
         cpu.registers.set_bc(0x0100);
         cpu.step();
@@ -1031,7 +1578,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // BC over
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x0B);
         cpu.registers.set_bc(0x0000);
         cpu.step();
@@ -1043,7 +1590,7 @@ mod test {
     #[test]
     fn test_add_a() {
         // A, A
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x87);
         cpu.registers.a = 0x02;
         cpu.step();
@@ -1128,7 +1675,7 @@ mod test {
     #[test]
     fn test_add_sp() {
         // SP, D8
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xE8);
         cpu.bus.write_byte(0x0001, 0x03);
         cpu.sp = 0x0100;
@@ -1139,7 +1686,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // SP, D8 miner
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xE8);
         cpu.bus.write_byte(0x0001, 0xF0);
         cpu.sp = 0x0000;
@@ -1150,7 +1697,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // SP, D8 carry
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xE8);
         cpu.bus.write_byte(0x0001, 0x10);
         cpu.sp = 0x00F0;
@@ -1158,12 +1705,12 @@ mod test {
         cpu.step();
         assert_eq!(cpu.sp, 0x0100);
         assert_eq!(cpu.pc, 0x0002);
-        assert_eq!(cpu.registers.f, F(false, false, false, false));
+        assert_eq!(cpu.registers.f, F(false, false, false, true));
     }
 
     #[test]
     fn test_add_c() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x81);
         cpu.registers.c = 0x03;
         cpu.registers.a = 0x02;
@@ -1175,7 +1722,7 @@ mod test {
 
     #[test]
     fn test_add_c_zero() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x81);
         cpu.registers.c = 0x00;
         cpu.registers.a = 0x00;
@@ -1187,7 +1734,7 @@ mod test {
 
     #[test]
     fn test_add_c_carry() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x81);
         cpu.registers.c = 0xF0;
         cpu.registers.a = 0x20;
@@ -1199,7 +1746,7 @@ mod test {
 
     #[test]
     fn test_add_c_half_carry() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x81);
         cpu.registers.c = 0x0F;
         cpu.registers.a = 0x01;
@@ -1212,7 +1759,7 @@ mod test {
     #[test]
     fn test_add_hl() {
         // HL, BC
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x09);
         cpu.registers.set_bc(0x0005);
         cpu.registers.set_hl(0x0003);
@@ -1222,7 +1769,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // HL, DE
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x19);
         cpu.registers.set_de(0x0001);
         cpu.registers.set_hl(0x00FF);
@@ -1232,7 +1779,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // HL, HL
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x29);
         cpu.registers.set_hl(0x00FF);
         cpu.step();
@@ -1241,7 +1788,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // HL, SP
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x39);
         cpu.sp = 0x00FF;
         cpu.registers.set_hl(0x00FF);
@@ -1251,7 +1798,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // half carry
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x09);
         cpu.registers.set_bc(0x0100);
         cpu.registers.set_hl(0x0F10);
@@ -1261,7 +1808,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, true, false));
 
         // carry
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x09);
         cpu.registers.set_bc(0xF000);
         cpu.registers.set_hl(0x1000);
@@ -1273,7 +1820,7 @@ mod test {
 
     #[test]
     fn test_or() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xB0);
         cpu.registers.b = 0x0F;
         cpu.registers.a = 0x81;
@@ -1282,7 +1829,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xB0);
         cpu.registers.b = 0x00;
         cpu.registers.a = 0x00;
@@ -1291,7 +1838,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0001);
         assert_eq!(cpu.registers.f, F(true, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xF6);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.registers.a = 0x80;
@@ -1303,7 +1850,7 @@ mod test {
 
     #[test]
     fn test_jp() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xC3);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.bus.write_byte(0x0002, 0x02);
@@ -1311,7 +1858,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0201);
 
         // JP HL
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xC3);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.bus.write_byte(0x0002, 0x02);
@@ -1321,7 +1868,7 @@ mod test {
 
     #[test]
     fn test_call_ret() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCD);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.bus.write_byte(0x0002, 0x02);
@@ -1331,7 +1878,7 @@ mod test {
         cpu.step();
         assert_eq!(cpu.pc, 0x0003);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCC);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.bus.write_byte(0x0002, 0x02);
@@ -1339,7 +1886,7 @@ mod test {
         cpu.step();
         assert_eq!(cpu.pc, 0x0003);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCC);
         cpu.bus.write_byte(0x0001, 0x01);
         cpu.bus.write_byte(0x0002, 0x02);
@@ -1353,32 +1900,32 @@ mod test {
 
     #[test]
     fn test_jr() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x18);
         cpu.bus.write_byte(0x0001, 0xF0);
         cpu.step();
         assert_eq!(cpu.pc, 0xFFF2);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0xFFFE, 0x18);
         cpu.bus.write_byte(0xFFFF, 0x03);
         cpu.pc = 0xFFFE;
         cpu.step();
         assert_eq!(cpu.pc, 0x0003);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x18);
         cpu.bus.write_byte(0x0001, 0x80);
         cpu.step();
         assert_eq!(cpu.pc, 0xFF82);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x18);
         cpu.bus.write_byte(0x0001, 0xFF);
         cpu.step();
         assert_eq!(cpu.pc, 0x0001);
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x28);
         cpu.bus.write_byte(0x0001, 0x10);
         cpu.step();
@@ -1387,7 +1934,7 @@ mod test {
 
     #[test]
     fn test_rr() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x18);
         cpu.registers.b = 0x22;
@@ -1396,7 +1943,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x18);
         cpu.registers.b = 0x22;
@@ -1406,7 +1953,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x18);
         cpu.registers.b = 0x01;
@@ -1416,7 +1963,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(true, false, false, true));
 
         // rra
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x1F);
         cpu.registers.a = 0x01;
         cpu.step();
@@ -1427,7 +1974,7 @@ mod test {
 
     #[test]
     fn test_rrc() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x08);
         cpu.registers.b = 0x22;
@@ -1436,7 +1983,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x08);
         cpu.registers.b = 0x01;
@@ -1445,7 +1992,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, true));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x08);
         cpu.registers.b = 0x00;
@@ -1455,7 +2002,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(true, false, false, false));
 
         //rrca
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x0F);
         cpu.registers.a = 0x00;
         cpu.step();
@@ -1463,7 +2010,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0001);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0x0F);
         cpu.registers.a = 0x81;
         cpu.step();
@@ -1475,7 +2022,7 @@ mod test {
     #[test]
     fn test_bit_res_set() {
         // BIT
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x40);
         cpu.registers.b = 0x81;
@@ -1483,7 +2030,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, true, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x48);
         cpu.registers.b = 0x81;
@@ -1491,7 +2038,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(true, false, true, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x78);
         cpu.registers.b = 0x81;
@@ -1500,7 +2047,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, true, false));
 
         // RES
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x80);
         cpu.registers.b = 0x81;
@@ -1509,7 +2056,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0xA0);
         cpu.registers.b = 0xFF;
@@ -1518,7 +2065,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0xB8);
         cpu.registers.b = 0x80;
@@ -1528,7 +2075,7 @@ mod test {
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
         // SET
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0xC0);
         cpu.registers.b = 0x80;
@@ -1537,7 +2084,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0xE0);
         cpu.registers.b = 0x80;
@@ -1549,7 +2096,7 @@ mod test {
 
     #[test]
     fn test_srl() {
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x38);
         cpu.registers.b = 0x7E;
@@ -1558,7 +2105,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x38);
         cpu.registers.b = 0x7E;
@@ -1568,7 +2115,7 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, false));
 
-        let mut cpu = CPU::new();
+        let mut cpu = new_cpu();
         cpu.bus.write_byte(0x0000, 0xCB);
         cpu.bus.write_byte(0x0001, 0x38);
         cpu.registers.b = 0x81;
@@ -1577,4 +2124,1130 @@ mod test {
         assert_eq!(cpu.pc, 0x0002);
         assert_eq!(cpu.registers.f, F(false, false, false, true));
     }
+
+    #[test]
+    fn test_sub_cp() {
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0x90);
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x01;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x0F);
+        assert_eq!(cpu.registers.f, F(false, true, true, false));
+
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0x98);
+        cpu.registers.a = 0x00;
+        cpu.registers.b = 0xFF;
+        cpu.registers.f.carry = true;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x00);
+        assert_eq!(cpu.registers.f, F(true, true, true, true));
+
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0xFE);
+        cpu.bus.write_byte(0x0001, 0x42);
+        cpu.registers.a = 0x42;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x42);
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cpu.registers.f, F(true, true, false, false));
+    }
+
+    #[test]
+    fn test_daa() {
+        // 0x19 + 0x28 = 0x41 -> 47
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0x80);
+        cpu.bus.write_byte(0x0001, 0x27);
+        cpu.registers.a = 0x19;
+        cpu.registers.b = 0x28;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x47);
+        assert_eq!(cpu.registers.f, F(false, false, false, false));
+
+        // 0x99 + 0x01 -> 00 (桁あふれ)
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0x80);
+        cpu.bus.write_byte(0x0001, 0x27);
+        cpu.registers.a = 0x99;
+        cpu.registers.b = 0x01;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x00);
+        assert_eq!(cpu.registers.f, F(true, false, false, true));
+
+        // 0x10 - 0x01 -> 09
+        let mut cpu = new_cpu();
+        cpu.bus.write_byte(0x0000, 0x90);
+        cpu.bus.write_byte(0x0001, 0x27);
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x01;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x09);
+        assert_eq!(cpu.registers.f, F(false, true, false, false));
+    }
+
+    // 命令ごとのサイクル数 (CYCLE_2は条件分岐で分岐しなかった場合, CYCLE_PREFIXEDはCBを含む)
+    const CYCLE: [u16; 256] = [
+        4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4, 
+        4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+        12, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+        12, 12, 8, 8, 12, 12, 12, 4, 12, 8, 8, 8, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+        20, 12, 16, 16, 24, 16, 8, 16, 20, 16, 16, 4, 24, 24, 8, 16,
+        20, 12, 16, 4, 24, 16, 8, 16, 20, 16, 16, 4, 24, 4, 8, 16,
+        12, 12, 8, 4, 4, 16, 8, 16, 16, 4, 16, 4, 4, 4, 8, 16,
+        12, 12, 8, 4, 4, 16, 8, 16, 12, 8, 16, 4, 4, 4, 8, 16,
+    ];
+
+    const CYCLE_2: [u16; 256] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        8, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0,
+        8, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        8, 0, 12, 0, 12, 0, 0, 0, 8, 0, 12, 0, 12, 0, 0, 0,
+        8, 0, 12, 0, 12, 0, 0, 0, 8, 0, 12, 0, 12, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    const CYCLE_PREFIXED: [u16; 256] = [
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+        8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+        8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+        8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+        8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+    ];
+
+    // ブロックキャッシュ: 書き換えられたRAMのコードを実行し直す
+    #[test]
+    fn test_block_cache() {
+        let mut cpu = new_cpu();
+        cpu.blocks = Some(BlockCache::new());
+        // C000: INC A; JR C000
+        for (i, byte) in [0x3C, 0x18, 0xFD].into_iter().enumerate() {
+            cpu.bus.write_byte(0xC000 + i as u16, byte);
+        }
+        cpu.pc = 0xC000;
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers.a, 0x02);
+        assert_eq!(cpu.pc, 0xC001);
+
+        // INCをDECに書き換える
+        cpu.step();
+        cpu.bus.write_byte(0xC000, 0x3D);
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x01);
+    }
+
+    // バンク1と2の0x4000: INC B; RET と INC C; RET (MBC1, 64KB)
+    // 0100: バンク1, 2, 1の順に切り替えてCALL 4000
+    // 0118: C000にINC D; RETを書いてCALL C000, CALL C200 (NOP; RET)
+    // 0126: C000をINC Eに書き換えてCALL C000
+    const BANK_PROGRAM: [u8; 48] = [
+        0x3E, 0x01, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40,
+        0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40,
+        0x3E, 0x01, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40,
+        0x21, 0x00, 0xC0, 0x36, 0x14, 0x2C, 0x36, 0xC9,
+        0xCD, 0x00, 0xC0, 0xCD, 0x00, 0xC2,
+        0x3E, 0x1C, 0xEA, 0x00, 0xC0, 0xCD, 0x00, 0xC0,
+        0x18, 0xFE,
+    ];
+
+    fn bank_cpu(blocks: bool) -> Box<CPU> {
+        let mut raw = vec![0; 0x10000];
+        raw[0x0100..0x0100 + BANK_PROGRAM.len()].copy_from_slice(&BANK_PROGRAM);
+        raw[0x0147] = 0x01;
+        raw[0x0148] = 0x01;
+        raw[0x4000..0x4002].copy_from_slice(&[0x04, 0xC9]);
+        raw[0x8000..0x8002].copy_from_slice(&[0x0C, 0xC9]);
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        let mut cpu = Box::new(CPU::new(cartridge, Model::DMG, None));
+        cpu.bus.write_byte(0xC200, 0x00);
+        cpu.bus.write_byte(0xC201, 0xC9);
+        if blocks {
+            cpu.blocks = Some(BlockCache::new());
+        }
+        cpu
+    }
+
+    // ブロックキャッシュ: バンクを切り替えたROMと書き換えたWRAMのコードを実行し直す
+    #[test]
+    fn test_block_cache_invalidation() {
+        let mut cpu = bank_cpu(true);
+        let mut expected = bank_cpu(false);
+        while cpu.pc != 0x012E {
+            cpu.step_block();
+            assert!(cpu.bus.scheduler.now < 100_000, "stuck at 0x{:04X}", cpu.pc);
+        }
+        while expected.pc != 0x012E {
+            expected.step();
+        }
+        // ブートROM終了直後はBC=0013, DE=00D8
+        assert_eq!(
+            (cpu.registers.b, cpu.registers.c, cpu.registers.d, cpu.registers.e),
+            (0x02, 0x14, 0x01, 0xD9)
+        );
+        assert_eq!(cpu.save_state(), expected.save_state());
+
+        // 書き換えられたページ (C000-C0FF) のブロックだけ捨てて, C200のブロックは残す
+        let pages = |addresses: &[u16]| addresses.iter().fold(0, |pages, a| pages | 1u128 << ((a - 0x8000) >> 8));
+        assert_eq!(cpu.bus.code_pages, pages(&[0xC000, 0xC200]));
+        cpu.step_block();
+        let count = cpu.blocks.as_ref().unwrap().len();
+        cpu.bus.write_byte(0xC080, 0x00);
+        cpu.step_block();
+        assert_eq!(cpu.blocks.as_ref().unwrap().len(), count - 1);
+        assert_eq!(cpu.bus.code_pages, pages(&[0xC200]));
+    }
+
+    // LYでSCXを書き換え, TIMAとVRAMを読み書きするループ
+    // 0100: LD A,5; LDH (TAC),A
+    // 0104: LDH A,(LY); LDH (SCX),A; LDH A,(TIMA); LD B,A; LD HL,8000; ADD A,(HL); LD (HL),A
+    // 0110: DEC B; JR NZ,0104; JR 0104
+    const RASTER_PROGRAM: [u8; 21] = [
+        0x3E, 0x05, 0xE0, 0x07,
+        0xF0, 0x44, 0xE0, 0x43, 0xF0, 0x05, 0x47, 0x21, 0x00, 0x80, 0x86, 0x77,
+        0x05, 0x20, 0xF1, 0x18, 0xEF,
+    ];
+
+    // (HL+), (HL-), (BC), (DE)の読み書きとPUSH, POP, CALL, RETのループ
+    // 0100: LD SP,FFFE; LD HL,C000; LD BC,C100; LD DE,C100
+    // 010C: INC A; LD (HL+),A; LD (HL-),A; LD A,(HL+); LD A,(HL-); LD (BC),A; LD A,(DE)
+    // 0113: PUSH BC; PUSH AF; CALL 011E; POP AF; POP BC; INC A; JR 010C
+    // 011E: INC A; OR A; RET Z; RET
+    const STACK_PROGRAM: [u8; 34] = [
+        0x31, 0xFE, 0xFF, 0x21, 0x00, 0xC0, 0x01, 0x00, 0xC1, 0x11, 0x00, 0xC1,
+        0x3C, 0x22, 0x32, 0x2A, 0x3A, 0x02, 0x1A,
+        0xC5, 0xF5, 0xCD, 0x1E, 0x01, 0xF1, 0xC1, 0x3C, 0x18, 0xEF, 0x00,
+        0x3C, 0xB7, 0xC8, 0xC9,
+    ];
+
+    // まとめて進めても, 命令ごとに進めたときと同じタイミングで読み書きし,
+    // フレームが終わった命令の直後で止まる
+    #[test]
+    fn test_block_cache_matches_interpreter() {
+        for program in [&SCROLL_PROGRAM[..], &RASTER_PROGRAM[..], &STACK_PROGRAM[..]] {
+            let mut cpu = new_rom_cpu(program, Model::DMG, false);
+            cpu.blocks = Some(BlockCache::new());
+            let mut expected = new_rom_cpu(program, Model::DMG, false);
+            for frame in 1..=10 {
+                while cpu.bus.gpu.frames < frame {
+                    cpu.step_block();
+                }
+                while expected.bus.gpu.frames < frame {
+                    expected.step();
+                }
+                assert_eq!(cpu.pc, expected.pc, "frame {}", frame);
+                assert_eq!(cpu.bus.scheduler.now, expected.bus.scheduler.now, "frame {}", frame);
+                assert!(cpu.save_state() == expected.save_state(), "frame {}", frame);
+            }
+        }
+    }
+
+    // cargo test --release -- --ignored test_block_cache_speed --nocapture
+    // 目標の10倍には届いていない. PPUの描画とイベントの処理だけで600フレームに約130msかかり
+    // (インタプリタの3割ほど), CPU側をいくら速くしてもこの2本では3倍強が上限になる
+    // 実測はどちらも1.8-2.2倍なので, 揺れを見込んで1.5倍を確かめる
+    #[test]
+    #[ignore = "benchmark"]
+    fn test_block_cache_speed() {
+        let run = |program: &[u8], blocks: bool| {
+            let mut cpu = new_rom_cpu(program, Model::DMG, false);
+            if blocks {
+                cpu.blocks = Some(BlockCache::new());
+            }
+            let start = std::time::Instant::now();
+            while cpu.bus.gpu.frames < 600 {
+                if blocks {
+                    cpu.step_block();
+                } else {
+                    cpu.step();
+                }
+            }
+            (start.elapsed(), cpu.save_state())
+        };
+        for (name, program) in [("scroll", &SCROLL_PROGRAM[..]), ("raster", &RASTER_PROGRAM[..])] {
+            // 交互に5回ずつ測って一番速いものを比べる (マシンの負荷の揺れを両方に均す)
+            let (mut interpreter, expected) = run(program, false);
+            let (mut cached, state) = run(program, true);
+            assert_eq!(state, expected);
+            for _ in 1..5 {
+                interpreter = interpreter.min(run(program, false).0);
+                cached = cached.min(run(program, true).0);
+            }
+            let ratio = interpreter.as_secs_f64() / cached.as_secs_f64();
+            println!("{}: interpreter {:?}, block cache {:?} ({:.2}x)", name, interpreter, cached, ratio);
+            assert!(ratio >= 1.5, "{}: {:.2}x", name, ratio);
+        }
+    }
+
+    // ROMのpc=0x0100からの命令 (0x0143でCGB対応にするか選ぶ)
+    fn new_rom_cpu(program: &[u8], model: Model, cgb: bool) -> Box<CPU> {
+        let mut raw = vec![0; 0x8000];
+        raw[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        if cgb {
+            raw[0x0143] = 0x80;
+        }
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        Box::new(CPU::new(cartridge, model, None))
+    }
+
+    fn read_watchpoint(start: u16, end: u16) -> Watchpoint {
+        Watchpoint {
+            start,
+            end,
+            read: true,
+            write: false,
+            execute: false,
+        }
+    }
+
+    // 毎回SCXとタイル0を書き換えるループ (フレームごとに画面が変わる)
+    // 0100: INC A; LDH (SCX),A; LD HL,8000; LD (HL),A; JR 0100
+    const SCROLL_PROGRAM: [u8; 9] = [0x3C, 0xE0, 0x43, 0x21, 0x00, 0x80, 0x77, 0x18, 0xF7];
+
+    fn run_frames(cpu: &mut CPU, frames: u64) {
+        let end = cpu.bus.gpu.frames + frames;
+        while cpu.bus.gpu.frames < end {
+            cpu.step();
+        }
+    }
+
+    // セーブステートを読み込んで同じだけ進めると, 画面も状態も同じになる
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut cpu = new_rom_cpu(&SCROLL_PROGRAM, Model::DMG, false);
+        run_frames(&mut cpu, 3);
+        let state = cpu.save_state();
+        let frame = cpu.bus.gpu.frame;
+
+        run_frames(&mut cpu, 5);
+        let expected_frame = cpu.bus.gpu.frame;
+        let expected_state = cpu.save_state();
+        assert_ne!(expected_frame, frame);
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.save_state(), state);
+        run_frames(&mut cpu, 5);
+        assert_eq!(cpu.bus.gpu.frame, expected_frame);
+        assert_eq!(cpu.save_state(), expected_state);
+
+        // 別のROMの状態は読み込まず, 今の状態も変えない
+        let mut other = new_rom_cpu(&[0x00], Model::DMG, false);
+        let before = other.save_state();
+        assert!(other.load_state(&state).is_err());
+        assert_eq!(other.save_state(), before);
+        // 途中で切れたデータも読み込まない
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(cpu.save_state(), expected_state);
+    }
+
+    // 範囲外のLY, ライン内のサイクル数, ウィンドウのラインは読み込まず, 今の状態も変えない
+    #[test]
+    fn test_save_state_corrupt_fields() {
+        let mut cpu = new_rom_cpu(&SCROLL_PROGRAM, Model::DMG, false);
+        run_frames(&mut cpu, 1);
+        let state = cpu.save_state();
+
+        // LYの位置はLYだけ変えて保存し直して探す
+        cpu.bus.gpu.ly ^= 0xFF;
+        let changed = cpu.save_state();
+        cpu.bus.gpu.ly ^= 0xFF;
+        let ly = (0..state.len()).find(|&i| state[i] != changed[i]).unwrap();
+        // LYの後ろ: LYC, LCDC, STAT, SCY-WX (7), パレットRAM (70 x 2), OPRI, ライン内のサイクル数, HBlank, ウィンドウのライン
+        let scanline_counter = ly + 152;
+        let window_line = ly + 155;
+
+        let cases: [(usize, &[u8]); 4] = [
+            (ly, &[154]),
+            (scanline_counter, &456u16.to_le_bytes()),
+            (scanline_counter, &0xFFFFu16.to_le_bytes()),
+            (window_line, &[145]),
+        ];
+        for (position, value) in cases {
+            let mut corrupt = state.clone();
+            corrupt[position..position + value.len()].copy_from_slice(value);
+            assert!(cpu.load_state(&corrupt).is_err(), "{} {:02X?}", position, value);
+            assert_eq!(cpu.save_state(), state);
+        }
+
+        // 範囲の端は読み込める
+        let mut edge = state.clone();
+        edge[ly] = 153;
+        edge[scanline_counter..scanline_counter + 2].copy_from_slice(&455u16.to_le_bytes());
+        edge[window_line] = 144;
+        cpu.load_state(&edge).unwrap();
+        assert_eq!(cpu.bus.gpu.cycles_until_mode_change(), 1);
+    }
+
+    // 方向キーをSCXとタイル0に, RTCの秒をSCYに毎回書くMBC3+TIMERのプログラム
+    // 0100: LD A,0A; LD (0000),A; LD A,08; LD (4000),A
+    // 010A: LD A,10; LDH (P1),A; LDH A,(P1); LDH (SCX),A; LD HL,8000; LD (HL),A
+    // 0116: XOR A; LD (6000),A; INC A; LD (6000),A; LD A,(A000); LDH (SCY),A; JR 010A
+    const MOVIE_PROGRAM: [u8; 37] = [
+        0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x08, 0xEA, 0x00, 0x40,
+        0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xE0, 0x43, 0x21, 0x00, 0x80, 0x77,
+        0xAF, 0xEA, 0x00, 0x60, 0x3C, 0xEA, 0x00, 0x60, 0xFA, 0x00, 0xA0, 0xE0, 0x42, 0x18, 0xE5,
+    ];
+
+    fn movie_cpu() -> Box<CPU> {
+        let mut raw = vec![0; 0x8000];
+        raw[0x0100..0x0100 + MOVIE_PROGRAM.len()].copy_from_slice(&MOVIE_PROGRAM);
+        raw[0x0147] = 0x10;
+        raw[0x0149] = 0x03;
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        Box::new(CPU::new(cartridge, Model::DMG, None))
+    }
+
+    fn frame_hash(cpu: &CPU) -> u64 {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+        let mut hasher = DefaultHasher::new();
+        cpu.bus.gpu.frame.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // 記録したムービーを再生すると毎フレーム同じ画面になる (RTCもエミュレーション時間で進む)
+    #[test]
+    fn test_movie_round_trip() {
+        use crate::movie::Movie;
+
+        let mut cpu = movie_cpu();
+        let mut movie = Movie::new(Some(cpu.bus.catridge.checksum()), Model::DMG, None);
+        let mut hashes = Vec::new();
+        for frame in 0..150u32 {
+            // 右, 左, 上, 下を順に押して離す
+            let buttons = if frame % 8 < 4 { 0x10 << (frame % 4) } else { 0x00 };
+            cpu.bus.set_joypad(buttons);
+            movie.record(buttons);
+            run_frames(&mut cpu, 1);
+            hashes.push(frame_hash(&cpu));
+        }
+        let mut unique = hashes.clone();
+        unique.sort();
+        unique.dedup();
+        assert!(unique.len() > 4, "{} different frames", unique.len());
+
+        let path = std::env::temp_dir().join(format!("movie_round_trip_{}.gbm", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        movie.save(&path).unwrap();
+        let mut loaded = Movie::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.inputs, movie.inputs);
+
+        let mut cpu = movie_cpu();
+        assert_eq!(loaded.checksum, Some(cpu.bus.catridge.checksum()));
+        for (frame, hash) in hashes.iter().enumerate() {
+            cpu.bus.set_joypad(loaded.next().unwrap());
+            run_frames(&mut cpu, 1);
+            assert_eq!(frame_hash(&cpu), *hash, "frame {}", frame);
+        }
+        assert_eq!(loaded.next(), None);
+    }
+
+    // OAM DMAは160Mサイクルかかり, その間CPUはHRAMとI/Oしか読めない
+    #[test]
+    fn test_oam_dma() {
+        let mut cpu = new_rom_cpu(&[], Model::DMG, false);
+        for i in 0..0xA0 {
+            cpu.bus.write_byte(0xC000 + i, i as u8 ^ 0x5A);
+        }
+        cpu.bus.watchpoints.push(read_watchpoint(0xC000, 0xC09F));
+        cpu.bus.write_byte(0xFF46, 0xC0);
+        assert!(cpu.bus.oam_dma_active());
+        assert_eq!(cpu.bus.read_byte(0xC000), 0xFF);
+        for _ in 0..159 {
+            cpu.bus.tick(4);
+        }
+        assert!(cpu.bus.oam_dma_active());
+        assert_eq!(cpu.bus.gpu.read_oam(0x9E), 0x9E ^ 0x5A);
+        assert_eq!(cpu.bus.gpu.read_oam(0x9F), 0x00);
+        cpu.bus.tick(4);
+        assert!(!cpu.bus.oam_dma_active());
+        assert_eq!(cpu.bus.gpu.read_oam(0x9F), 0x9F ^ 0x5A);
+        // 転送の読み出しはウォッチポイントに引っかからない
+        assert!(cpu.bus.take_watch_hit().is_none());
+        assert_eq!(cpu.bus.read_byte(0xC001), 0x01 ^ 0x5A);
+        assert!(cpu.bus.take_watch_hit().is_some());
+    }
+
+    // TACの書き込みでタイマーのオーバーフローが予約し直される (レジスタを読まなくても割り込みが来る)
+    #[test]
+    fn test_timer_reschedule() {
+        let mut cpu = new_rom_cpu(&[], Model::DMG, false);
+        let overflowed = |cpu: &mut CPU| {
+            let flag = cpu.bus.peek(0xFF0F) & 0x04 != 0;
+            cpu.bus.write_byte(0xFF0F, 0x00);
+            flag
+        };
+        // 止まっている間はオーバーフローしない
+        cpu.bus.write_byte(0xFF07, 0x00);
+        cpu.bus.write_byte(0xFF05, 0xFF);
+        overflowed(&mut cpu);
+        for _ in 0..1000 {
+            cpu.bus.tick(4);
+        }
+        assert!(!overflowed(&mut cpu));
+
+        // 262144Hz (16クロックごと) で動かすと16クロック以内にオーバーフローする
+        cpu.bus.write_byte(0xFF07, 0x05);
+        let mut cycles = 0;
+        while !overflowed(&mut cpu) {
+            cpu.bus.tick(4);
+            cycles += 4;
+            assert!(cycles <= 16, "no overflow after {} cycles", cycles);
+        }
+
+        // 止めたら予約も取り消される
+        cpu.bus.write_byte(0xFF05, 0xFF);
+        cpu.bus.write_byte(0xFF07, 0x00);
+        for _ in 0..1000 {
+            cpu.bus.tick(4);
+        }
+        assert!(!overflowed(&mut cpu));
+    }
+
+    // フレームの途中のLCDCの書き込みはそのラインから反映され, PPUのタイミングは変わらない
+    #[test]
+    fn test_lcdc_write_mid_frame() {
+        let new_cpu = || {
+            let mut cpu = new_rom_cpu(&[], Model::DMG, false);
+            // タイル0を色3で塗る (BGマップは全てタイル0)
+            for i in 0..16 {
+                cpu.bus.gpu.write_vram(i, 0xFF);
+            }
+            cpu
+        };
+        let mut a = new_cpu();
+        let mut b = new_cpu();
+        let tick = |a: &mut CPU, b: &mut CPU| {
+            a.bus.tick(4);
+            b.bus.tick(4);
+            assert_eq!(a.bus.gpu.ly, b.bus.gpu.ly);
+            assert_eq!(a.bus.peek(0xFF41), b.bus.peek(0xFF41));
+        };
+        while a.bus.gpu.frames == 0 {
+            tick(&mut a, &mut b);
+        }
+        while a.bus.gpu.ly != 72 {
+            tick(&mut a, &mut b);
+        }
+        // BGを消す
+        let lcdc = a.bus.peek(0xFF40);
+        a.bus.write_byte(0xFF40, lcdc & !0x01);
+        while a.bus.gpu.frames == 1 {
+            tick(&mut a, &mut b);
+        }
+
+        let row = |cpu: &CPU, y: usize| cpu.bus.gpu.frame[y * 160 * 3..(y + 1) * 160 * 3].to_vec();
+        for y in 0..72 {
+            assert_eq!(row(&a, y), row(&b, y), "line {}", y);
+        }
+        for y in 72..144 {
+            assert_ne!(row(&a, y), row(&b, y), "line {}", y);
+        }
+    }
+
+    // 汎用HDMAは1ブロックにつき8Mサイクル (倍速では16Mサイクル) CPUを止める
+    #[test]
+    fn test_general_hdma() {
+        // LDH (0x55),A
+        let mut cpu = new_rom_cpu(&[0xE0, 0x55], Model::CGB, true);
+        for i in 0..0x20 {
+            cpu.bus.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        for (address, value) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x80), (0xFF54, 0x10)] {
+            cpu.bus.write_byte(address, value);
+        }
+        cpu.bus.watchpoints.push(read_watchpoint(0xC000, 0xC01F));
+        cpu.registers.a = 0x01;
+        assert_eq!(cpu.step(), 12 + 2 * 32);
+        assert!(cpu.bus.take_watch_hit().is_none());
+        assert_eq!(cpu.bus.gpu.read_vram(0x0010), 0x01);
+        assert_eq!(cpu.bus.gpu.read_vram(0x002F), 0x20);
+        assert_eq!(cpu.bus.peek(0xFF55), 0xFF);
+
+        let mut cpu = new_rom_cpu(&[0xE0, 0x55], Model::CGB, true);
+        cpu.bus.double_speed = true;
+        cpu.registers.a = 0x00;
+        assert_eq!(cpu.step(), 12 + 64);
+    }
+
+    // メモリアクセスと内部サイクルの合計が表の値と一致する
+    #[test]
+    fn test_cycle_counts() {
+        for prefixed in [false, true] {
+            for opcode in 0..=255u8 {
+                if Instruction::from_byte(opcode, prefixed).is_none() {
+                    continue;
+                }
+                // 条件分岐は両方の場合を試す (フラグ0: NZ/NCで分岐, 0xF0: Z/Cで分岐)
+                for f in [0x00, 0xF0] {
+                    let mut cpu = new_cpu();
+                    cpu.pc = 0x0100;
+                    cpu.sp = 0xD000;
+                    cpu.registers.set_hl(0xC000);
+                    cpu.registers.set_af(f);
+                    if prefixed {
+                        cpu.bus.write_byte(0x0100, 0xCB);
+                        cpu.bus.write_byte(0x0101, opcode);
+                    } else {
+                        cpu.bus.write_byte(0x0100, opcode);
+                    }
+                    let cycles = cpu.step();
+
+                    let expected = if prefixed {
+                        CYCLE_PREFIXED[opcode as usize]
+                    } else if CYCLE_2[opcode as usize] != 0 {
+                        let zero = f & 0x80 != 0;
+                        let taken = match (opcode >> 3) & 0x03 {
+                            0 => !zero,
+                            1 => zero,
+                            2 => !zero, // NC (キャリーはゼロと同じ値にしている)
+                            _ => zero,
+                        };
+                        if taken { CYCLE[opcode as usize] } else { CYCLE_2[opcode as usize] }
+                    } else {
+                        CYCLE[opcode as usize]
+                    };
+                    assert_eq!(cycles, expected, "{}{:02X} (F={:02X})", if prefixed { "CB " } else { "" }, opcode, f);
+                }
+            }
+        }
+    }
+
+    // SingleStepTestsのsm83のテストベクタ (命令ごとのJSON: "00.json", "cb 00.json")
+    // SM83_TESTSのディレクトリ (なければtests/sm83/v1) から読む
+    // ベクタはリポジトリに含めないので cargo test -- --ignored で実行する
+    #[test]
+    #[ignore = "needs the SingleStepTests sm83 vectors (SM83_TESTS=DIR)"]
+    fn test_single_step_tests() {
+        let dir = std::env::var("SM83_TESTS")
+            .unwrap_or_else(|_| format!("{}/tests/sm83/v1", env!("CARGO_MANIFEST_DIR")));
+        if let Err(e) = std::fs::read_dir(&dir) {
+            panic!("{}: {}", dir, e);
+        }
+
+        let mut cpu = new_cpu();
+        cpu.bus_log = Some(Vec::new());
+        let mut count = 0;
+        let mut missing = Vec::new();
+        let mut failures = Vec::new();
+        for prefixed in [false, true] {
+            for opcode in 0..=255u8 {
+                if Instruction::from_byte(opcode, prefixed).is_none() {
+                    continue;
+                }
+                let name = if prefixed { format!("cb {:02x}", opcode) } else { format!("{:02x}", opcode) };
+                let path = format!("{}/{}.json", dir, name);
+                let text = match std::fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(_) => {
+                        missing.push(name);
+                        continue;
+                    }
+                };
+                let vectors = Json::parse(&text).unwrap_or_else(|e| panic!("{}: {}", path, e));
+                // 命令ごとに最初の失敗だけ表示する
+                let mut failed = 0;
+                let mut first = None;
+                for vector in vectors.array() {
+                    count += 1;
+                    if let Err(e) = run_vector(&mut cpu, vector) {
+                        failed += 1;
+                        first.get_or_insert_with(|| format!("'{}': {}", vector.get("name").string(), e));
+                    }
+                }
+                if let Some(first) = first {
+                    failures.push(format!("{}: {} of {} failed, first {}", name, failed, vectors.array().len(), first));
+                }
+            }
+        }
+        assert!(missing.is_empty(), "{}: no vectors for {}", dir, missing.join(", "));
+        assert!(failures.is_empty(), "{} opcodes failed ({} vectors run):\n{}", failures.len(), count, failures.join("\n"));
+    }
+
+    const VECTOR_REGISTERS: [&str; 10] = ["PC", "SP", "A", "B", "C", "D", "E", "F", "H", "L"];
+
+    // 1つのテストを実行して最終状態とMサイクルごとのバスアクセスを比べる
+    // cyclesの要素は [アドレス, 値, "r-m"/"-wm"/"---"] (内部処理の"---"はアクセスがないことだけ確かめる)
+    fn run_vector(cpu: &mut CPU, vector: &Json) -> Result<(), String> {
+        let initial = vector.get("initial");
+        let expected = vector.get("final");
+        for name in VECTOR_REGISTERS {
+            cpu.set_register(name, initial.get(&name.to_ascii_lowercase()).number() as u16);
+        }
+        cpu.ime = initial.get("ime").number() != 0.0;
+        cpu.is_halted = false;
+        for entry in initial.get("ram").array() {
+            cpu.bus.write_byte(entry.index(0).number() as u16, entry.index(1).number() as u8);
+        }
+
+        if let Some(log) = &mut cpu.bus_log {
+            log.clear();
+        }
+        let cycles = cpu.step();
+
+        let mut errors = Vec::new();
+        for name in VECTOR_REGISTERS {
+            let want = expected.get(&name.to_ascii_lowercase()).number() as u16;
+            let got = cpu.register(name).unwrap();
+            if got != want {
+                errors.push(format!("{}={:04X} (expected {:04X})", name, got, want));
+            }
+        }
+        if let Json::Number(ime) = expected.get("ime") {
+            if cpu.ime != (*ime != 0.0) {
+                errors.push(format!("IME={} (expected {})", cpu.ime as u8, ime));
+            }
+        }
+        for entry in expected.get("ram").array() {
+            let address = entry.index(0).number() as u16;
+            let want = entry.index(1).number() as u8;
+            let got = cpu.bus.peek(address);
+            if got != want {
+                errors.push(format!("[{:04X}]={:02X} (expected {:02X})", address, got, want));
+            }
+        }
+        // cyclesは1要素が1Mサイクル
+        let want = vector.get("cycles").array().len() as u16 * 4;
+        if cycles != want {
+            errors.push(format!("cycles={} (expected {})", cycles, want));
+        }
+        if let Some(log) = &cpu.bus_log {
+            for (i, cycle) in vector.get("cycles").array().iter().enumerate() {
+                let want = match cycle {
+                    Json::Array(_) => {
+                        let pins = cycle.index(2).string();
+                        let access = (cycle.index(0).number() as u16, cycle.index(1).number() as u8);
+                        match (pins.contains('r'), pins.contains('w')) {
+                            (true, _) => Some((access.0, access.1, false)),
+                            (_, true) => Some((access.0, access.1, true)),
+                            _ => None,
+                        }
+                    }
+                    // 中身のない要素は比べない
+                    _ => continue,
+                };
+                let got = log.get(i).copied().flatten();
+                if got != want {
+                    errors.push(format!("M-cycle {}: {} (expected {})", i, describe_access(got), describe_access(want)));
+                    break;
+                }
+            }
+        }
+
+        // 次のテストのためにメモリを0に戻す
+        for state in [initial, expected] {
+            for entry in state.get("ram").array() {
+                cpu.bus.write_byte(entry.index(0).number() as u16, 0);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    fn describe_access(access: Option<(u16, u8, bool)>) -> String {
+        match access {
+            Some((address, value, write)) => {
+                format!("{} {:04X}={:02X}", if write { "write" } else { "read" }, address, value)
+            }
+            None => String::from("no access"),
+        }
+    }
+
+    // バスアクセスの比較が正しく動くか, 手で書いたベクタで確かめる
+    // PUSH BC: フェッチ, 内部処理, 上位, 下位の順に書き込む
+    #[test]
+    fn test_vector_bus_cycles() {
+        let vector = |cycles: &str| {
+            Json::parse(&format!(
+                r#"{{"name": "c5", "initial": {{"pc": 256, "sp": 53248, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0,
+                "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 197]]}}, "final": {{"pc": 257, "sp": 53246,
+                "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0,
+                "ram": [[256, 197], [53246, 52], [53247, 18]]}}, "cycles": {}}}"#,
+                cycles
+            ))
+            .unwrap()
+        };
+        let mut cpu = new_cpu();
+        cpu.bus_log = Some(Vec::new());
+        let good = r#"[[256, 197, "r-m"], [53248, null, "---"], [53247, 18, "-wm"], [53246, 52, "-wm"]]"#;
+        assert_eq!(run_vector(&mut cpu, &vector(good)), Ok(()));
+        // 書き込みの順番が逆
+        let swapped = r#"[[256, 197, "r-m"], [53248, null, "---"], [53246, 52, "-wm"], [53247, 18, "-wm"]]"#;
+        let error = run_vector(&mut cpu, &vector(swapped)).unwrap_err();
+        assert!(error.contains("M-cycle 2: write CFFF=12 (expected write CFFE=34)"), "{}", error);
+        // 内部処理のMサイクルにアクセスがあるとしたベクタ
+        let read = r#"[[256, 197, "r-m"], [53248, 0, "r-m"], [53247, 18, "-wm"], [53246, 52, "-wm"]]"#;
+        let error = run_vector(&mut cpu, &vector(read)).unwrap_err();
+        assert!(error.contains("M-cycle 1: no access"), "{}", error);
+    }
+
+    // DAA: 直前が加算か減算か (N) と, H, Cフラグの組み合わせごとの補正
+    #[test]
+    fn test_daa_flags() {
+        // (A, N, H, C) -> (A, Z, C)
+        let cases = [
+            // 加算: 下位が9を超えるかHで+0x06, 0x99を超えるかCで+0x60
+            ((0x00, false, false, false), (0x00, true, false)),
+            ((0x99, false, false, false), (0x99, false, false)),
+            ((0x0A, false, false, false), (0x10, false, false)),
+            ((0x10, false, true, false), (0x16, false, false)),
+            ((0x20, false, false, true), (0x80, false, true)),
+            ((0xA0, false, false, false), (0x00, true, true)),
+            ((0x9A, false, false, false), (0x00, true, true)),
+            ((0x32, false, true, true), (0x98, false, true)),
+            // 減算: Hで-0x06, Cで-0x60 (下位の値は見ない)
+            ((0x10, true, false, false), (0x10, false, false)),
+            ((0x0F, true, false, false), (0x0F, false, false)),
+            ((0x0F, true, true, false), (0x09, false, false)),
+            ((0x06, true, true, false), (0x00, true, false)),
+            ((0xF0, true, false, true), (0x90, false, true)),
+            ((0xFF, true, true, true), (0x99, false, true)),
+        ];
+        for ((a, subtract, half_carry, carry), (want, zero, want_carry)) in cases {
+            let mut cpu = new_cpu();
+            cpu.bus.write_byte(0x0000, 0x27);
+            cpu.registers.a = a;
+            cpu.registers.f = F(false, subtract, half_carry, carry);
+            cpu.step();
+            let name = format!("A={:02X} N={} H={} C={}", a, subtract as u8, half_carry as u8, carry as u8);
+            assert_eq!(cpu.registers.a, want, "{}", name);
+            // Hは常に0, Nはそのまま
+            assert_eq!(cpu.registers.f, F(zero, subtract, false, want_carry), "{}", name);
+        }
+    }
+
+    // ブートROMを実行しなかったときのレジスタ
+    // (モデル, ヘッダチェックサム, CGBフラグ, ライセンシー, タイトルの先頭) -> (AF, BC, DE, HL)
+    #[test]
+    fn test_post_boot_registers() {
+        let cases = [
+            ((Model::DMG0, 0x00, false, 0x00, 0x00), (0x0100, 0xFF13, 0x00C1, 0x8403)),
+            // DMG/MGBはヘッダチェックサムが0でなければHとCが立つ
+            ((Model::DMG, 0x00, false, 0x00, 0x00), (0x0180, 0x0013, 0x00D8, 0x014D)),
+            ((Model::DMG, 0x3C, false, 0x00, 0x00), (0x01B0, 0x0013, 0x00D8, 0x014D)),
+            ((Model::MGB, 0x3C, false, 0x00, 0x00), (0xFFB0, 0x0013, 0x00D8, 0x014D)),
+            ((Model::SGB, 0x3C, false, 0x00, 0x00), (0x0100, 0x0014, 0x0000, 0xC060)),
+            ((Model::SGB2, 0x3C, false, 0x00, 0x00), (0xFF00, 0x0014, 0x0000, 0xC060)),
+            ((Model::CGB, 0x3C, true, 0x00, 0x00), (0x1180, 0x0000, 0xFF56, 0x000D)),
+            ((Model::AGB, 0x3C, true, 0x00, 0x00), (0x1100, 0x0100, 0xFF56, 0x000D)),
+            // DMG互換モード: 任天堂のタイトルならBはタイトルの合計 (AGBは+1), 0x43か0x58ならHL=0x991A
+            ((Model::CGB, 0x3C, false, 0x00, 0x43), (0x1180, 0x0000, 0x0008, 0x007C)),
+            ((Model::CGB, 0x3C, false, 0x01, 0x20), (0x1180, 0x2000, 0x0008, 0x007C)),
+            ((Model::CGB, 0x3C, false, 0x01, 0x43), (0x1180, 0x4300, 0x0008, 0x991A)),
+            ((Model::AGB, 0x3C, false, 0x01, 0x42), (0x1100, 0x4300, 0x0008, 0x991A)),
+            ((Model::CGB, 0x3C, false, 0x33, 0x58), (0x1180, 0x5800, 0x0008, 0x991A)),
+        ];
+        for ((model, header_checksum, cgb, licensee, title), (af, bc, de, hl)) in cases {
+            let mut raw = vec![0; 0x8000];
+            raw[0x0134] = title;
+            raw[0x0143] = if cgb { 0x80 } else { 0x00 };
+            // 新しいライセンシーコード "01" は任天堂
+            raw[0x0144..=0x0145].copy_from_slice(b"01");
+            raw[0x014B] = licensee;
+            raw[0x014D] = header_checksum;
+            let cpu = CPU::new(Cartridge::from_bytes(raw).unwrap(), model, None);
+            let r = &cpu.registers;
+            let name = format!("{:?} checksum={:02X} cgb={} licensee={:02X} title={:02X}", model, header_checksum, cgb, licensee, title);
+            assert_eq!((r.get_af(), r.get_bc(), r.get_de(), r.get_hl()), (af, bc, de, hl), "{}", name);
+            assert_eq!((cpu.pc, cpu.sp), (0x0100, 0xFFFE), "{}", name);
+        }
+    }
+
+    // モデルによって変わるI/Oレジスタ (DIV, NR52, DMA, STAT)
+    #[test]
+    fn test_post_boot_io() {
+        let cases = [
+            (Model::DMG0, 0x18, 0xF1, 0xFF, 0x81),
+            (Model::DMG, 0xAB, 0xF1, 0xFF, 0x85),
+            (Model::SGB, 0xAB, 0xF0, 0xFF, 0x85),
+            (Model::CGB, 0x00, 0xF1, 0x00, 0x85),
+        ];
+        for (model, div, nr52, dma, stat) in cases {
+            let cpu = new_rom_cpu(&[], model, false);
+            let values = [0xFF04, 0xFF26, 0xFF46, 0xFF41].map(|address| cpu.bus.read_byte(address));
+            assert_eq!(values, [div, nr52, dma, stat], "{:?}", model);
+            assert_eq!(cpu.bus.read_byte(0xFF40), 0x91, "{:?}", model);
+            assert_eq!(cpu.bus.read_byte(0xFF47), 0xFC, "{:?}", model);
+        }
+    }
+
+    // ブートROMは0xFF50に書き込むまでカートリッジに重なる
+    #[test]
+    fn test_boot_rom_unmap() {
+        let mut raw = vec![0x11; 0x8000];
+        raw[0x0143] = 0x80;
+        raw[0x0147..=0x0149].fill(0x00);
+        let cartridge = Cartridge::from_bytes(raw.clone()).unwrap();
+        let mut cpu = CPU::new(cartridge, Model::DMG, Some(vec![0x22; 0x100]));
+        assert_eq!((cpu.pc, cpu.registers.get_af()), (0x0000, 0x0000));
+        assert_eq!(cpu.bus.read_byte(0x0000), 0x22);
+        assert_eq!(cpu.bus.read_byte(0x00FF), 0x22);
+        assert_eq!(cpu.bus.read_byte(0x0100), 0x11);
+        // bit0が0なら外れない
+        cpu.bus.write_byte(0xFF50, 0x00);
+        assert_eq!(cpu.bus.read_byte(0x0000), 0x22);
+        cpu.bus.write_byte(0xFF50, 0x01);
+        assert_eq!(cpu.bus.read_byte(0x0000), 0x11);
+        assert!(cpu.bus.bank_switched);
+
+        // CGBのブートROMはカートリッジヘッダ (0x0100-0x01FF) を避ける
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        let mut cpu = CPU::new(cartridge, Model::CGB, Some(vec![0x22; 0x900]));
+        assert_eq!(cpu.bus.read_byte(0x00FF), 0x22);
+        assert_eq!(cpu.bus.read_byte(0x0143), 0x80);
+        assert_eq!(cpu.bus.read_byte(0x0200), 0x22);
+        assert_eq!(cpu.bus.read_byte(0x08FF), 0x22);
+        assert_eq!(cpu.bus.read_byte(0x0900), 0x11);
+        cpu.bus.write_byte(0xFF50, 0x01);
+        assert_eq!(cpu.bus.read_byte(0x0000), 0x11);
+        assert_eq!(cpu.bus.read_byte(0x0200), 0x11);
+    }
+
+    // テストベクタを読むための最小限のJSON
+    enum Json {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    static NULL: Json = Json::Null;
+
+    impl Json {
+        fn parse(text: &str) -> Result<Json, String> {
+            let mut parser = JsonParser { bytes: text.as_bytes(), pos: 0 };
+            let value = parser.value()?;
+            parser.skip_whitespace();
+            if parser.pos != parser.bytes.len() {
+                return Err(format!("unexpected character at {}", parser.pos));
+            }
+            Ok(value)
+        }
+
+        // 存在しなければNull
+        fn get(&self, key: &str) -> &Json {
+            match self {
+                Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map_or(&NULL, |(_, v)| v),
+                _ => &NULL,
+            }
+        }
+
+        fn index(&self, i: usize) -> &Json {
+            self.array().get(i).unwrap_or(&NULL)
+        }
+
+        fn array(&self) -> &[Json] {
+            match self {
+                Json::Array(items) => items,
+                _ => &[],
+            }
+        }
+
+        fn number(&self) -> f64 {
+            match self {
+                Json::Number(n) => *n,
+                Json::Bool(b) => *b as u8 as f64,
+                _ => 0.0,
+            }
+        }
+
+        fn string(&self) -> &str {
+            match self {
+                Json::String(s) => s,
+                _ => "",
+            }
+        }
+    }
+
+    struct JsonParser<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl JsonParser<'_> {
+        fn peek(&self) -> Option<u8> {
+            self.bytes.get(self.pos).copied()
+        }
+
+        fn skip_whitespace(&mut self) {
+            while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+        }
+
+        fn expect(&mut self, byte: u8) -> Result<(), String> {
+            self.skip_whitespace();
+            if self.peek() != Some(byte) {
+                return Err(format!("expected '{}' at {}", byte as char, self.pos));
+            }
+            self.pos += 1;
+            Ok(())
+        }
+
+        fn value(&mut self) -> Result<Json, String> {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'{') => {
+                    self.pos += 1;
+                    let mut fields = Vec::new();
+                    if !self.closes(b'}') {
+                        loop {
+                            self.skip_whitespace();
+                            let key = self.string()?;
+                            self.expect(b':')?;
+                            fields.push((key, self.value()?));
+                            if !self.next_item(b'}')? {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(Json::Object(fields))
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    let mut items = Vec::new();
+                    if !self.closes(b']') {
+                        loop {
+                            items.push(self.value()?);
+                            if !self.next_item(b']')? {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(Json::Array(items))
+                }
+                Some(b'"') => Ok(Json::String(self.string()?)),
+                Some(b't') => self.literal("true", Json::Bool(true)),
+                Some(b'f') => self.literal("false", Json::Bool(false)),
+                Some(b'n') => self.literal("null", Json::Null),
+                Some(_) => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')) {
+                        self.pos += 1;
+                    }
+                    std::str::from_utf8(&self.bytes[start..self.pos])
+                        .ok()
+                        .and_then(|text| text.parse().ok())
+                        .map(Json::Number)
+                        .ok_or_else(|| format!("invalid value at {}", start))
+                }
+                None => Err(String::from("unexpected end of input")),
+            }
+        }
+
+        // 空の{}や[]なら閉じ括弧を読んでtrue
+        fn closes(&mut self, close: u8) -> bool {
+            self.skip_whitespace();
+            if self.peek() == Some(close) {
+                self.pos += 1;
+                return true;
+            }
+            false
+        }
+
+        // ','なら続きがある, 閉じ括弧なら終わり
+        fn next_item(&mut self, close: u8) -> Result<bool, String> {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => {
+                    self.pos += 1;
+                    Ok(true)
+                }
+                Some(b) if b == close => {
+                    self.pos += 1;
+                    Ok(false)
+                }
+                _ => Err(format!("expected ',' or '{}' at {}", close as char, self.pos)),
+            }
+        }
+
+        fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+            if !self.bytes[self.pos..].starts_with(word.as_bytes()) {
+                return Err(format!("invalid value at {}", self.pos));
+            }
+            self.pos += word.len();
+            Ok(value)
+        }
+
+        fn string(&mut self) -> Result<String, String> {
+            self.expect(b'"')?;
+            let mut bytes = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(b'"') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(b'\\') => {
+                        let escaped = self.bytes.get(self.pos + 1).copied();
+                        self.pos += 2;
+                        let c = match escaped {
+                            Some(b'n') => '\n',
+                            Some(b't') => '\t',
+                            Some(b'r') => '\r',
+                            Some(b'b') => '\u{8}',
+                            Some(b'f') => '\u{c}',
+                            Some(b'u') => {
+                                let code = self
+                                    .bytes
+                                    .get(self.pos..self.pos + 4)
+                                    .and_then(|hex| u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                                    .ok_or_else(|| format!("invalid escape at {}", self.pos))?;
+                                self.pos += 4;
+                                char::from_u32(code).unwrap_or('\u{FFFD}')
+                            }
+                            Some(b) => b as char,
+                            None => return Err(String::from("unexpected end of input")),
+                        };
+                        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    Some(b) => {
+                        bytes.push(b);
+                        self.pos += 1;
+                    }
+                    None => return Err(String::from("unterminated string")),
+                }
+            }
+            String::from_utf8(bytes).map_err(|e| e.to_string())
+        }
+    }
 }
@@ -0,0 +1,532 @@
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::CPU;
+use crate::disassembler;
+use crate::instruction::BYTES;
+use crate::symbols::Symbols;
+
+// バス上のアドレス範囲に対するウォッチポイント
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+// ブレークポイントの条件 (例: A==0x3C)
+#[derive(Clone, Debug)]
+struct Condition {
+    register: String,
+    operator: Operator,
+    value: u16,
+}
+
+impl Condition {
+    fn parse(text: &str) -> Result<Self, String> {
+        // 2文字の演算子を先に探す
+        let operators = [
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            ("<=", Operator::Le),
+            (">=", Operator::Ge),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ];
+        for (symbol, operator) in operators {
+            if let Some((register, value)) = text.split_once(symbol) {
+                return Ok(Condition {
+                    register: register.trim().to_ascii_uppercase(),
+                    operator,
+                    value: parse_number(value.trim())?,
+                });
+            }
+        }
+        Err(format!("invalid condition: {}", text))
+    }
+
+    fn matches(&self, cpu: &CPU) -> bool {
+        let value = match cpu.register(&self.register) {
+            Some(value) => value,
+            None => return false,
+        };
+        match self.operator {
+            Operator::Eq => value == self.value,
+            Operator::Ne => value != self.value,
+            Operator::Lt => value < self.value,
+            Operator::Gt => value > self.value,
+            Operator::Le => value <= self.value,
+            Operator::Ge => value >= self.value,
+        }
+    }
+}
+
+// コマンドの後どうするか
+enum Next {
+    Prompt,
+    Resume,
+    Quit,
+}
+
+struct Breakpoint {
+    bank: Option<u16>, // 指定があればそのバンクが見えているときだけ止まる
+    address: u16,
+    condition: Option<Condition>,
+}
+
+enum Mode {
+    Running,
+    Step(u32),          // 残りの命令数
+    Until(u16),         // ステップオーバー: 次の命令のアドレスまで
+    Finish(u16),        // RETでSPがこの値を超えるまで
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    pub break_requested: bool,
+    pub symbols: Symbols,
+    last_opcode: u8,
+}
+
+const HELP: &str = "\
+s [N]               step N instructions (default 1)
+n                   step over CALL/RST
+finish              run until the current function returns
+c                   continue
+b ADDR [if COND]    set a breakpoint (COND: REG==VALUE, !=, <, >, <=, >=)
+d N                 delete breakpoint N
+w START[-END] [rwx] set a watchpoint (default rw)
+dw N                delete watchpoint N
+l                   list breakpoints and watchpoints
+r                   show registers
+set REG VALUE       modify a register (A-L, AF-HL, SP, PC) or flag (ZF, NF, HF, CF)
+x ADDR [LEN]        hexdump memory
+dis [ADDR] [N]      disassemble N instructions (default: PC, 10)
+q                   quit
+numbers are hex (0x or $ prefix optional), N for s and dis is decimal
+ADDR can also be BANK:ADDR or a symbol name";
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits = text
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid number: {}", text))
+}
+
+// RETの類 (RET, RET cc, RETI)
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: Mode::Running,
+            break_requested: false,
+            symbols: Symbols::new(),
+            last_opcode: 0,
+        }
+    }
+
+    // ブレークポイントもステップ実行もなく, 命令ごとにshould_breakを呼ばなくてよいか
+    pub fn is_running(&self) -> bool {
+        matches!(self.mode, Mode::Running) && self.breakpoints.is_empty() && !self.break_requested
+    }
+
+    // 命令を実行する前に呼ぶ: REPLに入るべきならtrue
+    pub fn should_break(&mut self, cpu: &CPU) -> bool {
+        let pc = cpu.pc;
+        let previous = self.last_opcode;
+        self.last_opcode = cpu.bus.peek(pc);
+
+        if let Some(hit) = cpu.bus.take_watch_hit() {
+            println!(
+                "watchpoint: {} 0x{:04X} = 0x{:02X}",
+                if hit.write { "write" } else { "read" },
+                hit.address,
+                hit.value
+            );
+            return true;
+        }
+        if self.break_requested {
+            self.break_requested = false;
+            return true;
+        }
+
+        match self.mode {
+            Mode::Running => {}
+            Mode::Step(n) => {
+                if n <= 1 {
+                    return true;
+                }
+                self.mode = Mode::Step(n - 1);
+            }
+            Mode::Until(address) if address == pc => return true,
+            Mode::Finish(sp) if is_return(previous) && cpu.sp > sp => return true,
+            _ => {}
+        }
+
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            if breakpoint.address == pc
+                && breakpoint.bank.is_none_or(|bank| bank == cpu.bus.bank(pc))
+                && breakpoint.condition.as_ref().is_none_or(|c| c.matches(cpu))
+            {
+                println!("breakpoint {} at {}", i, self.location_name(breakpoint.bank, pc));
+                return true;
+            }
+        }
+        for watchpoint in &cpu.bus.watchpoints {
+            if watchpoint.execute && (watchpoint.start..=watchpoint.end).contains(&pc) {
+                println!("watchpoint: execute 0x{:04X}", pc);
+                return true;
+            }
+        }
+        false
+    }
+
+    // 端末でコマンドを受け付ける: 終了するならfalseを返す
+    pub fn repl(&mut self, cpu: &mut CPU) -> bool {
+        self.mode = Mode::Running;
+        print_registers(cpu, &self.symbols);
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match self.command(cpu, &words) {
+                Ok(Next::Resume) => {
+                    // REPLの中での読み出しは無視する
+                    cpu.bus.take_watch_hit();
+                    return true;
+                }
+                Ok(Next::Prompt) => {}
+                Ok(Next::Quit) => return false,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    // アドレスの指定: "4567", "02:4567" かシンボル名 (バンクはROMXのときだけ区別する)
+    fn parse_location(&self, text: &str) -> Result<(Option<u16>, u16), String> {
+        if let Some((bank, address)) = text.split_once(':') {
+            return Ok((Some(parse_number(bank)?), parse_number(address)?));
+        }
+        if let Ok(address) = parse_number(text) {
+            return Ok((None, address));
+        }
+        match self.symbols.address_of(text) {
+            Some((bank, address)) if (0x4000..0x8000).contains(&address) => Ok((Some(bank), address)),
+            Some((_, address)) => Ok((None, address)),
+            None => Err(format!("invalid address or unknown symbol: {}", text)),
+        }
+    }
+
+    fn location_name(&self, bank: Option<u16>, address: u16) -> String {
+        let location = match bank {
+            Some(bank) => format!("{:02X}:{:04X}", bank, address),
+            None => format!("0x{:04X}", address),
+        };
+        match self.symbols.describe(bank.unwrap_or(0), address) {
+            Some(name) => format!("{} ({})", location, name),
+            None => location,
+        }
+    }
+
+    fn command(&mut self, cpu: &mut CPU, words: &[&str]) -> Result<Next, String> {
+        let argument = |i: usize| -> Result<&str, String> {
+            words.get(i).copied().ok_or_else(|| String::from("missing argument"))
+        };
+        match words.first().copied().unwrap_or("") {
+            "" => Ok(Next::Prompt),
+            "s" | "step" => {
+                let count = match words.get(1) {
+                    Some(n) => n.parse().map_err(|_| format!("invalid count: {}", n))?,
+                    None => 1,
+                };
+                self.mode = Mode::Step(count);
+                Ok(Next::Resume)
+            }
+            "n" | "next" => {
+                let opcode = cpu.bus.peek(cpu.pc);
+                let is_call = matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC);
+                let is_rst = opcode & 0xC7 == 0xC7;
+                self.mode = if is_call || is_rst {
+                    Mode::Until(cpu.pc.wrapping_add(BYTES[opcode as usize]))
+                } else {
+                    Mode::Step(1)
+                };
+                Ok(Next::Resume)
+            }
+            "finish" => {
+                self.mode = Mode::Finish(cpu.sp);
+                Ok(Next::Resume)
+            }
+            "c" | "continue" => Ok(Next::Resume),
+            "b" | "break" => {
+                let (bank, address) = self.parse_location(argument(1)?)?;
+                let condition = match words.get(2) {
+                    Some(&"if") => Some(Condition::parse(&words[3..].join(""))?),
+                    Some(other) => return Err(format!("expected 'if', got {}", other)),
+                    None => None,
+                };
+                self.breakpoints.push(Breakpoint { bank, address, condition });
+                println!("breakpoint {} at {}", self.breakpoints.len() - 1, self.location_name(bank, address));
+                Ok(Next::Prompt)
+            }
+            "d" | "delete" => {
+                let i: usize = argument(1)?.parse().map_err(|_| String::from("invalid index"))?;
+                if i >= self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", i));
+                }
+                self.breakpoints.remove(i);
+                Ok(Next::Prompt)
+            }
+            "w" | "watch" => {
+                let range = argument(1)?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (self.parse_location(start)?.1, self.parse_location(end)?.1),
+                    None => (self.parse_location(range)?.1, self.parse_location(range)?.1),
+                };
+                let access = words.get(2).copied().unwrap_or("rw");
+                cpu.bus.watchpoints.push(Watchpoint {
+                    start,
+                    end,
+                    read: access.contains('r'),
+                    write: access.contains('w'),
+                    execute: access.contains('x'),
+                });
+                println!("watchpoint {} at 0x{:04X}-0x{:04X} ({})", cpu.bus.watchpoints.len() - 1, start, end, access);
+                Ok(Next::Prompt)
+            }
+            "dw" => {
+                let i: usize = argument(1)?.parse().map_err(|_| String::from("invalid index"))?;
+                if i >= cpu.bus.watchpoints.len() {
+                    return Err(format!("no watchpoint {}", i));
+                }
+                cpu.bus.watchpoints.remove(i);
+                Ok(Next::Prompt)
+            }
+            "l" | "list" => {
+                for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                    let location = self.location_name(breakpoint.bank, breakpoint.address);
+                    match &breakpoint.condition {
+                        Some(c) => println!("breakpoint {}: {} if {} {:?} 0x{:X}", i, location, c.register, c.operator, c.value),
+                        None => println!("breakpoint {}: {}", i, location),
+                    }
+                }
+                for (i, w) in cpu.bus.watchpoints.iter().enumerate() {
+                    println!(
+                        "watchpoint {}: 0x{:04X}-0x{:04X} {}{}{}",
+                        i,
+                        w.start,
+                        w.end,
+                        if w.read { "r" } else { "" },
+                        if w.write { "w" } else { "" },
+                        if w.execute { "x" } else { "" }
+                    );
+                }
+                Ok(Next::Prompt)
+            }
+            "r" | "regs" => {
+                print_registers(cpu, &self.symbols);
+                Ok(Next::Prompt)
+            }
+            "set" => {
+                let name = argument(1)?;
+                let value = parse_number(argument(2)?)?;
+                if !cpu.set_register(name, value) {
+                    return Err(format!("unknown register: {}", name));
+                }
+                print_registers(cpu, &self.symbols);
+                Ok(Next::Prompt)
+            }
+            "x" => {
+                let (_, address) = self.parse_location(argument(1)?)?;
+                let length = match words.get(2) {
+                    Some(n) => parse_number(n)? as usize,
+                    None => 0x80,
+                };
+                hexdump(cpu, address, length);
+                Ok(Next::Prompt)
+            }
+            "dis" => {
+                let mut address = match words.get(1) {
+                    Some(text) => self.parse_location(text)?.1,
+                    None => cpu.pc,
+                };
+                let count = match words.get(2) {
+                    Some(n) => n.parse().map_err(|_| format!("invalid count: {}", n))?,
+                    None => 10,
+                };
+                for _ in 0..count {
+                    let label = |a: u16| self.symbols.lookup(cpu.bus.bank(a), a).map(String::from);
+                    if let Some(name) = label(address) {
+                        println!("{}:", name);
+                    }
+                    let (text, length) = disassembler::disassemble(|a| cpu.bus.peek(a), label, address);
+                    println!("{:04X}: {}", address, text);
+                    address = address.wrapping_add(length);
+                }
+                Ok(Next::Prompt)
+            }
+            "h" | "help" => {
+                println!("{}", HELP);
+                Ok(Next::Prompt)
+            }
+            "q" | "quit" => Ok(Next::Quit),
+            other => Err(format!("unknown command: {} (h for help)", other)),
+        }
+    }
+}
+
+fn print_registers(cpu: &CPU, symbols: &Symbols) {
+    let register = |name: &str| cpu.register(name).unwrap_or(0);
+    let flag = |name: &str, c: char| if register(name) != 0 { c } else { '-' };
+    let pc = cpu.pc;
+    let label = |a: u16| symbols.lookup(cpu.bus.bank(a), a).map(String::from);
+    let (text, length) = disassembler::disassemble(|a| cpu.bus.peek(a), label, pc);
+    let name = match symbols.describe(cpu.bus.bank(pc), pc) {
+        Some(name) => format!(" <{}>", name),
+        None => String::new(),
+    };
+    let bytes: Vec<String> = (0..length)
+        .map(|i| format!("{:02X}", cpu.bus.peek(pc.wrapping_add(i))))
+        .collect();
+    println!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}{} {}{}{}{} IME={} HALT={}  [{}] {}",
+        register("AF"),
+        register("BC"),
+        register("DE"),
+        register("HL"),
+        register("SP"),
+        pc,
+        name,
+        flag("ZF", 'Z'),
+        flag("NF", 'N'),
+        flag("HF", 'H'),
+        flag("CF", 'C'),
+        cpu.ime as u8,
+        cpu.is_halted as u8,
+        bytes.join(" "),
+        text
+    );
+}
+
+fn hexdump(cpu: &CPU, address: u16, length: usize) {
+    for row in (0..length).step_by(16) {
+        let start = address.wrapping_add(row as u16);
+        let bytes: Vec<u8> = (0..16.min(length - row))
+            .map(|i| cpu.bus.peek(start.wrapping_add(i as u16)))
+            .collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' })
+            .collect();
+        println!("{:04X}: {:<47}  {}", start, hex.join(" "), ascii);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::model::Model;
+
+    // 0100: CALL 0110; NOP; NOP; LD (C000),A; NOP; JR 0109
+    // 0110: NOP; NOP; RET
+    fn new_cpu() -> Box<CPU> {
+        let mut raw = vec![0; 0x8000];
+        let program = [0xCD, 0x10, 0x01, 0x00, 0x00, 0xEA, 0x00, 0xC0, 0x00, 0x18, 0xFE];
+        raw[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        raw[0x0112] = 0xC9;
+        let cartridge = Cartridge::from_bytes(raw).unwrap();
+        Box::new(CPU::new(cartridge, Model::DMG, None))
+    }
+
+    // REPLでコマンドを入力して再開し, 次に止まるまでに実行した命令数を返す
+    fn resume(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> u32 {
+        debugger.mode = Mode::Running;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        assert!(matches!(debugger.command(cpu, &words), Ok(Next::Resume)), "{}", line);
+        cpu.bus.take_watch_hit();
+        for count in 1..1000 {
+            cpu.step();
+            if debugger.should_break(cpu) {
+                return count;
+            }
+        }
+        panic!("{} did not stop", line);
+    }
+
+    fn prompt(debugger: &mut Debugger, cpu: &mut CPU, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        assert!(matches!(debugger.command(cpu, &words), Ok(Next::Prompt)), "{}", line);
+    }
+
+    // 再開した直後の命令も数に入れ, 余分に1命令進まない
+    #[test]
+    fn test_resume_then_step() {
+        let mut cpu = new_cpu();
+        let mut debugger = Debugger::new();
+        debugger.break_requested = true;
+        assert!(debugger.should_break(&cpu));
+        assert_eq!(cpu.pc, 0x0100);
+
+        assert_eq!(resume(&mut debugger, &mut cpu, "s"), 1);
+        assert_eq!(cpu.pc, 0x0110);
+        assert_eq!(resume(&mut debugger, &mut cpu, "s 2"), 2);
+        assert_eq!(cpu.pc, 0x0112);
+        assert_eq!(resume(&mut debugger, &mut cpu, "finish"), 1);
+        assert_eq!(cpu.pc, 0x0103);
+
+        // ブレークポイントで止まった位置から再開しても, その場ですぐには止まらない
+        prompt(&mut debugger, &mut cpu, "b 104");
+        prompt(&mut debugger, &mut cpu, "w C000 w");
+        assert_eq!(resume(&mut debugger, &mut cpu, "c"), 1);
+        assert_eq!(cpu.pc, 0x0104);
+        assert_eq!(resume(&mut debugger, &mut cpu, "c"), 2);
+        assert_eq!(cpu.pc, 0x0108);
+        // ウォッチポイントで止まった後の1命令
+        assert_eq!(resume(&mut debugger, &mut cpu, "s"), 1);
+        assert_eq!(cpu.pc, 0x0109);
+        assert_eq!(resume(&mut debugger, &mut cpu, "n"), 1);
+        assert_eq!(cpu.pc, 0x0109);
+    }
+
+    // CALLのステップオーバーは戻り先で止まる
+    #[test]
+    fn test_next_over_call() {
+        let mut cpu = new_cpu();
+        let mut debugger = Debugger::new();
+        debugger.break_requested = true;
+        assert!(debugger.should_break(&cpu));
+        assert_eq!(resume(&mut debugger, &mut cpu, "n"), 4);
+        assert_eq!(cpu.pc, 0x0103);
+    }
+}
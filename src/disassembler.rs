@@ -0,0 +1,332 @@
+use crate::instruction::{ArithmeticTarget, Instruction, JumpTest, LoadType, StackTarget, BYTES};
+use crate::symbols::Symbols;
+
+// Instruction::from_byteの結果をRGBDS風の表記にする
+// オペランドはreadで命令の後ろから読む (例: LD A,(FF00+$44), JR NZ,$0150, CALL $2000)
+
+// 1命令を逆アセンブルする: (テキスト, 命令のバイト数)
+// labelはジャンプ先やメモリのアドレスに付いているシンボル名を返す
+pub fn disassemble(read: impl Fn(u16) -> u8, label: impl Fn(u16) -> Option<String>, address: u16) -> (String, u16) {
+    let opcode = read(address);
+    let (instruction, length) = if opcode == 0xCB {
+        (Instruction::from_byte(read(address.wrapping_add(1)), true), 2)
+    } else {
+        (Instruction::from_byte(opcode, false), BYTES[opcode as usize])
+    };
+    let instruction = match instruction {
+        Some(instruction) => instruction,
+        None => return (format!("DB ${:02X}", opcode), 1),
+    };
+
+    let d8 = read(address.wrapping_add(1));
+    let d16 = u16::from_le_bytes([d8, read(address.wrapping_add(2))]);
+    let location = |address: u16| label(address).unwrap_or_else(|| format!("${:04X}", address));
+    let operand = |target: ArithmeticTarget| -> String {
+        match target {
+            ArithmeticTarget::A => String::from("A"),
+            ArithmeticTarget::B => String::from("B"),
+            ArithmeticTarget::C => String::from("C"),
+            ArithmeticTarget::D => String::from("D"),
+            ArithmeticTarget::E => String::from("E"),
+            ArithmeticTarget::H => String::from("H"),
+            ArithmeticTarget::L => String::from("L"),
+            ArithmeticTarget::BC_ => String::from("(BC)"),
+            ArithmeticTarget::DE_ => String::from("(DE)"),
+            ArithmeticTarget::HL_ => String::from("(HL)"),
+            ArithmeticTarget::HLi_ => String::from("(HL+)"),
+            ArithmeticTarget::HLd_ => String::from("(HL-)"),
+            ArithmeticTarget::BC => String::from("BC"),
+            ArithmeticTarget::DE => String::from("DE"),
+            ArithmeticTarget::HL => String::from("HL"),
+            ArithmeticTarget::SP => String::from("SP"),
+            ArithmeticTarget::D8 => format!("${:02X}", d8),
+            ArithmeticTarget::D16 => format!("${:04X}", d16),
+            ArithmeticTarget::D16_ => format!("({})", location(d16)),
+            ArithmeticTarget::FD8_ => format!("(FF00+${:02X})", d8),
+            ArithmeticTarget::FDC_ => String::from("(FF00+C)"),
+            ArithmeticTarget::SPA => format!("SP{}", signed(d8)),
+        }
+    };
+    let condition = |test: &JumpTest| match test {
+        JumpTest::NotZero => "NZ,",
+        JumpTest::Zero => "Z,",
+        JumpTest::NotCarry => "NC,",
+        JumpTest::Carry => "C,",
+        JumpTest::Always => "",
+    };
+
+    let text = match instruction {
+        Instruction::NOP => String::from("NOP"),
+        Instruction::LD(LoadType::Byte(target, source)) | Instruction::LD(LoadType::WORD(target, source)) => {
+            format!("LD {},{}", operand(target), operand(source))
+        }
+        Instruction::INC(target) => format!("INC {}", operand(target)),
+        Instruction::DEC(target) => format!("DEC {}", operand(target)),
+        // 0xE8はADD SP,e8
+        Instruction::ADD(ArithmeticTarget::SP) => format!("ADD SP,{}", signed(d8)),
+        Instruction::ADD(target) => format!("ADD A,{}", operand(target)),
+        Instruction::ADC(target) => format!("ADC A,{}", operand(target)),
+        Instruction::ADDHL(target) => format!("ADD HL,{}", operand(target)),
+        Instruction::SUB(target) => format!("SUB {}", operand(target)),
+        Instruction::SBC(target) => format!("SBC A,{}", operand(target)),
+        Instruction::AND(target) => format!("AND {}", operand(target)),
+        Instruction::OR(target) => format!("OR {}", operand(target)),
+        Instruction::XOR(target) => format!("XOR {}", operand(target)),
+        Instruction::CP(target) => format!("CP {}", operand(target)),
+        Instruction::CCF => String::from("CCF"),
+        Instruction::SCF => String::from("SCF"),
+        Instruction::DAA => String::from("DAA"),
+        Instruction::JP(test) => format!("JP {}{}", condition(&test), location(d16)),
+        Instruction::JPHL => String::from("JP HL"),
+        Instruction::JR(test) => {
+            let destination = address.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+            format!("JR {}{}", condition(&test), location(destination))
+        }
+        Instruction::PUSH(target) => format!("PUSH {}", stack_target(&target)),
+        Instruction::POP(target) => format!("POP {}", stack_target(&target)),
+        Instruction::CALL(test) => format!("CALL {}{}", condition(&test), location(d16)),
+        Instruction::RST(vector) => format!("RST ${:02X}", vector),
+        Instruction::RET(JumpTest::Always) => String::from("RET"),
+        Instruction::RET(test) => format!("RET {}", condition(&test).trim_end_matches(',')),
+        Instruction::RETI => String::from("RETI"),
+        Instruction::CPL => String::from("CPL"),
+        Instruction::BIT(target, bit) => format!("BIT {},{}", bit, operand(target)),
+        Instruction::RES(target, bit) => format!("RES {},{}", bit, operand(target)),
+        Instruction::SET(target, bit) => format!("SET {},{}", bit, operand(target)),
+        Instruction::SRL(target) => format!("SRL {}", operand(target)),
+        Instruction::RR(target) => format!("RR {}", operand(target)),
+        Instruction::RRA => String::from("RRA"),
+        Instruction::RL(target) => format!("RL {}", operand(target)),
+        Instruction::RLA => String::from("RLA"),
+        Instruction::RRC(target) => format!("RRC {}", operand(target)),
+        Instruction::RRCA => String::from("RRCA"),
+        Instruction::RLC(target) => format!("RLC {}", operand(target)),
+        Instruction::RLCA => String::from("RLCA"),
+        Instruction::SRA(target) => format!("SRA {}", operand(target)),
+        Instruction::SLA(target) => format!("SLA {}", operand(target)),
+        Instruction::SWAP(target) => format!("SWAP {}", operand(target)),
+        Instruction::STOP => String::from("STOP"),
+        Instruction::HALT => String::from("HALT"),
+        Instruction::DI => String::from("DI"),
+        Instruction::EI => String::from("EI"),
+    };
+
+    // I/OレジスタとHRAMにアクセスする命令には名前を添える
+    let name = match opcode {
+        0xE0 | 0xF0 => {
+            let address = 0xFF00 | d8 as u16;
+            io_register_name(address).map(String::from).or_else(|| label(address))
+        }
+        0xEA | 0xFA => io_register_name(d16).map(String::from),
+        _ => None,
+    };
+    match name {
+        Some(name) => (format!("{} ; {}", text, name), length),
+        None => (text, length),
+    }
+}
+
+fn signed(value: u8) -> String {
+    let value = value as i8;
+    if value < 0 {
+        format!("-${:02X}", value.unsigned_abs())
+    } else {
+        format!("+${:02X}", value)
+    }
+}
+
+fn stack_target(target: &StackTarget) -> String {
+    match target {
+        StackTarget::AF => String::from("AF"),
+        StackTarget::BC => String::from("BC"),
+        StackTarget::DE => String::from("DE"),
+        StackTarget::HL => String::from("HL"),
+        StackTarget::D16(value) => format!("${:04X}", value),
+        StackTarget::NONE => String::new(),
+    }
+}
+
+pub fn io_register_name(address: u16) -> Option<&'static str> {
+    let name = match address {
+        0xFF00 => "P1",
+        0xFF01 => "SB",
+        0xFF02 => "SC",
+        0xFF04 => "DIV",
+        0xFF05 => "TIMA",
+        0xFF06 => "TMA",
+        0xFF07 => "TAC",
+        0xFF0F => "IF",
+        0xFF10 => "NR10",
+        0xFF11 => "NR11",
+        0xFF12 => "NR12",
+        0xFF13 => "NR13",
+        0xFF14 => "NR14",
+        0xFF16 => "NR21",
+        0xFF17 => "NR22",
+        0xFF18 => "NR23",
+        0xFF19 => "NR24",
+        0xFF1A => "NR30",
+        0xFF1B => "NR31",
+        0xFF1C => "NR32",
+        0xFF1D => "NR33",
+        0xFF1E => "NR34",
+        0xFF20 => "NR41",
+        0xFF21 => "NR42",
+        0xFF22 => "NR43",
+        0xFF23 => "NR44",
+        0xFF24 => "NR50",
+        0xFF25 => "NR51",
+        0xFF26 => "NR52",
+        0xFF40 => "LCDC",
+        0xFF41 => "STAT",
+        0xFF42 => "SCY",
+        0xFF43 => "SCX",
+        0xFF44 => "LY",
+        0xFF45 => "LYC",
+        0xFF46 => "DMA",
+        0xFF47 => "BGP",
+        0xFF48 => "OBP0",
+        0xFF49 => "OBP1",
+        0xFF4A => "WY",
+        0xFF4B => "WX",
+        0xFF4D => "KEY1",
+        0xFF4F => "VBK",
+        0xFF50 => "BOOT",
+        0xFF51 => "HDMA1",
+        0xFF52 => "HDMA2",
+        0xFF53 => "HDMA3",
+        0xFF54 => "HDMA4",
+        0xFF55 => "HDMA5",
+        0xFF56 => "RP",
+        0xFF68 => "BCPS",
+        0xFF69 => "BCPD",
+        0xFF6A => "OCPS",
+        0xFF6B => "OCPD",
+        0xFF70 => "SVBK",
+        0xFFFF => "IE",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// --disassembleの範囲: "BANK" でバンク全体, "[BANK:]START-END" でアドレス範囲
+pub fn parse_range(text: &str) -> Result<(usize, u16, u16), String> {
+    let number = |text: &str| u16::from_str_radix(text.trim_start_matches("0x").trim_start_matches('$'), 16);
+    let invalid = || format!("invalid range: {} (BANK or [BANK:]START-END)", text);
+    let (bank, range) = match text.split_once(':') {
+        Some((bank, range)) => (Some(number(bank).map_err(|_| invalid())? as usize), range),
+        None => (None, text),
+    };
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let start = number(start).map_err(|_| invalid())?;
+            let end = number(end).map_err(|_| invalid())?;
+            if start > end || (start < 0x4000) != (end < 0x4000) || end >= 0x8000 {
+                return Err(format!("{}: range must be within 0000-3FFF or 4000-7FFF", text));
+            }
+            // バンクの指定がなければ0000-3FFFはバンク0, 4000-7FFFはバンク1
+            let bank = bank.unwrap_or(if start < 0x4000 { 0 } else { 1 });
+            Ok((bank, start, end))
+        }
+        None if bank.is_none() => {
+            let bank = number(range).map_err(|_| invalid())? as usize;
+            Ok(if bank == 0 { (0, 0x0000, 0x3FFF) } else { (bank, 0x4000, 0x7FFF) })
+        }
+        None => Err(invalid()),
+    }
+}
+
+// ROMファイルの1バンク分の範囲を逆アセンブルして表示する
+pub fn dump(rom: &[u8], symbols: &Symbols, bank: usize, start: u16, end: u16) {
+    let read = |address: u16| {
+        let offset = if address < 0x4000 {
+            address as usize
+        } else {
+            bank * 0x4000 + (address as usize - 0x4000)
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    };
+    // 0x4000-0x7FFFは表示中のバンクのシンボルを使う
+    let bank_of = |address: u16| if (0x4000..0x8000).contains(&address) { bank as u16 } else { 0 };
+    let label = |address: u16| symbols.lookup(bank_of(address), address).map(String::from);
+    let mut address = start as u32;
+    while address <= end as u32 {
+        if let Some(name) = label(address as u16) {
+            println!("{}:", name);
+        }
+        let (text, length) = disassemble(read, label, address as u16);
+        let bytes: Vec<String> = (0..length)
+            .map(|i| format!("{:02X}", read((address as u16).wrapping_add(i))))
+            .collect();
+        println!("{:02X}:{:04X}  {:<9} {}", bank, address, bytes.join(" "), text);
+        address += length as u32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // addressからbytesが並んでいるとして1命令を逆アセンブルする (シンボルは0x2000だけ)
+    fn disassemble_bytes(bytes: &[u8], address: u16) -> (String, u16) {
+        let read = |a: u16| bytes.get(a.wrapping_sub(address) as usize).copied().unwrap_or(0);
+        let label = |a: u16| if a == 0x2000 { Some(String::from("Main")) } else { None };
+        disassemble(read, label, address)
+    }
+
+    #[test]
+    fn test_cb_prefixed() {
+        assert_eq!(disassemble_bytes(&[0xCB, 0x7C], 0), (String::from("BIT 7,H"), 2));
+        assert_eq!(disassemble_bytes(&[0xCB, 0x37], 0), (String::from("SWAP A"), 2));
+        assert_eq!(disassemble_bytes(&[0xCB, 0x86], 0), (String::from("RES 0,(HL)"), 2));
+        assert_eq!(disassemble_bytes(&[0xCB, 0xFF], 0), (String::from("SET 7,A"), 2));
+        assert_eq!(disassemble_bytes(&[0xCB, 0x11], 0), (String::from("RL C"), 2));
+        assert_eq!(disassemble_bytes(&[0xCB, 0x3E], 0), (String::from("SRL (HL)"), 2));
+    }
+
+    #[test]
+    fn test_immediate() {
+        assert_eq!(disassemble_bytes(&[0x01, 0x34, 0x12], 0), (String::from("LD BC,$1234"), 3));
+        assert_eq!(disassemble_bytes(&[0x31, 0xFE, 0xFF], 0), (String::from("LD SP,$FFFE"), 3));
+        assert_eq!(disassemble_bytes(&[0xEA, 0x00, 0xC0], 0), (String::from("LD ($C000),A"), 3));
+        assert_eq!(disassemble_bytes(&[0x08, 0x00, 0xC1], 0), (String::from("LD ($C100),SP"), 3));
+        // シンボルとI/Oレジスタの名前
+        assert_eq!(disassemble_bytes(&[0xCD, 0x00, 0x20], 0), (String::from("CALL Main"), 3));
+        assert_eq!(disassemble_bytes(&[0xC2, 0x50, 0x01], 0), (String::from("JP NZ,$0150"), 3));
+        assert_eq!(disassemble_bytes(&[0xFA, 0x40, 0xFF], 0), (String::from("LD A,($FF40) ; LCDC"), 3));
+        assert_eq!(disassemble_bytes(&[0xF0, 0x44], 0), (String::from("LD A,(FF00+$44) ; LY"), 2));
+        assert_eq!(disassemble_bytes(&[0x3E, 0x3C], 0), (String::from("LD A,$3C"), 2));
+        assert_eq!(disassemble_bytes(&[0xE8, 0xFE], 0), (String::from("ADD SP,-$02"), 2));
+        assert_eq!(disassemble_bytes(&[0xF8, 0x05], 0), (String::from("LD HL,SP+$05"), 2));
+    }
+
+    #[test]
+    fn test_relative_jump() {
+        // 移動先は次の命令のアドレスからの相対
+        assert_eq!(disassemble_bytes(&[0x18, 0xFE], 0x0150), (String::from("JR $0150"), 2));
+        assert_eq!(disassemble_bytes(&[0x20, 0x10], 0x0150), (String::from("JR NZ,$0162"), 2));
+        assert_eq!(disassemble_bytes(&[0x38, 0x80], 0x0150), (String::from("JR C,$00D2"), 2));
+        assert_eq!(disassemble_bytes(&[0x28, 0x0E], 0x1FF0), (String::from("JR Z,Main"), 2));
+        // 0xFFFFをまたいで折り返す
+        assert_eq!(disassemble_bytes(&[0x18, 0x02], 0xFFFE), (String::from("JR $0002"), 2));
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        assert_eq!(disassemble_bytes(&[0xD3], 0), (String::from("DB $D3"), 1));
+        assert_eq!(disassemble_bytes(&[0x00], 0), (String::from("NOP"), 1));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0"), Ok((0, 0x0000, 0x3FFF)));
+        assert_eq!(parse_range("1F"), Ok((0x1F, 0x4000, 0x7FFF)));
+        assert_eq!(parse_range("0150-0200"), Ok((0, 0x0150, 0x0200)));
+        assert_eq!(parse_range("$4000-$4010"), Ok((1, 0x4000, 0x4010)));
+        assert_eq!(parse_range("03:4000-4FFF"), Ok((3, 0x4000, 0x4FFF)));
+        assert!(parse_range("3FF0-4010").is_err());
+        assert!(parse_range("0200-0100").is_err());
+        assert!(parse_range("03:4000").is_err());
+        assert!(parse_range("xyz").is_err());
+    }
+}
@@ -0,0 +1,621 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::CPU;
+use crate::debugger::Watchpoint;
+
+// GDBのリモートシリアルプロトコルのサーバー (--gdb PORT)
+// レジスタはAF, BC, DE, HL, SP, PCの順に16bitずつ (リトルエンディアン) 並べる
+
+const REGISTERS: [&str; 6] = ["AF", "BC", "DE", "HL", "SP", "PC"];
+
+// 実行中にCtrl-Cが来ていないか確認する間隔 (命令数)
+const POLL_INTERVAL: u32 = 4096;
+
+pub struct GdbStub {
+    stream: Option<TcpStream>, // Noneなら切断済み
+    breakpoints: Vec<u16>,
+    stopped: bool,  // 次の命令の前で止まる
+    stepping: bool, // 1命令実行したら止まる
+    no_ack: bool,
+    counter: u32,
+}
+
+impl GdbStub {
+    // 接続を待つ (接続したら最初の命令の前で止まる)
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("gdb: {}", e))?;
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        Self::accept(&listener)
+    }
+
+    fn accept(listener: &TcpListener) -> Result<Self, String> {
+        let (stream, address) = listener.accept().map_err(|e| format!("gdb: {}", e))?;
+        println!("gdb connected from {}", address);
+        stream.set_nodelay(true).ok();
+        Ok(GdbStub {
+            stream: Some(stream),
+            breakpoints: Vec::new(),
+            stopped: true,
+            stepping: false,
+            no_ack: false,
+            counter: 0,
+        })
+    }
+
+    // 命令を実行する前に呼ぶ: 止まるべきならtrue
+    pub fn should_break(&mut self, cpu: &CPU) -> bool {
+        if self.stream.is_none() {
+            return false;
+        }
+        if self.stopped || cpu.bus.watch_hit_pending() {
+            return true;
+        }
+        if self.stepping {
+            return true;
+        }
+        if self.breakpoints.contains(&cpu.pc) {
+            return true;
+        }
+        self.counter += 1;
+        if self.counter >= POLL_INTERVAL {
+            self.counter = 0;
+            return self.poll_interrupt();
+        }
+        false
+    }
+
+    // 止まった理由をGDBに伝えてから, continueかstepが来るまでパケットを処理する
+    // killされたらfalseを返す
+    pub fn serve(&mut self, cpu: &mut CPU) -> bool {
+        // 接続直後はGDBからの問い合わせ (?) を待つ
+        if !std::mem::take(&mut self.stopped) {
+            let reply = match cpu.bus.take_watch_hit() {
+                Some(hit) => format!("T05{}:{:04X};", if hit.write { "watch" } else { "rwatch" }, hit.address),
+                None => String::from("S05"),
+            };
+            if self.send(&reply).is_err() {
+                self.disconnect();
+                return true;
+            }
+        }
+        self.stepping = false;
+
+        loop {
+            let packet = match self.receive() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    println!("gdb disconnected: {}", e);
+                    self.disconnect();
+                    return true;
+                }
+            };
+            if packet == "QStartNoAckMode" {
+                // このOKまでは通常通り確認応答する
+                if self.send("OK").is_err() {
+                    self.disconnect();
+                    return true;
+                }
+                self.no_ack = true;
+                continue;
+            }
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => {
+                    self.resume(cpu, &packet[1..]);
+                    return true;
+                }
+                Some(b's') => {
+                    self.resume(cpu, &packet[1..]);
+                    self.stepping = true;
+                    return true;
+                }
+                Some(b'k') => return false,
+                Some(b'D') => {
+                    self.send("OK").ok();
+                    println!("gdb detached");
+                    self.disconnect();
+                    return true;
+                }
+                _ => self.command(cpu, &packet),
+            };
+            if self.send(&reply).is_err() {
+                self.disconnect();
+                return true;
+            }
+        }
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, address: &str) {
+        if let Ok(address) = u16::from_str_radix(address, 16) {
+            cpu.pc = address;
+        }
+        // 止まっている間のメモリアクセスは無視する
+        cpu.bus.take_watch_hit();
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.stopped = false;
+        self.stepping = false;
+    }
+
+    // c, s, k, D以外のパケット: 応答を返す (未対応なら空)
+    fn command(&mut self, cpu: &mut CPU, packet: &str) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (head, rest) = packet.split_at(1);
+        match head {
+            "?" => String::from("S05"),
+            "g" => REGISTERS
+                .iter()
+                .map(|name| hex_u16(cpu.register(name).unwrap_or(0)))
+                .collect(),
+            "G" => {
+                for (i, name) in REGISTERS.iter().enumerate() {
+                    match rest.get(i * 4..i * 4 + 4).and_then(parse_u16_le) {
+                        Some(value) => cpu.set_register(name, value),
+                        None => return String::from("E01"),
+                    };
+                }
+                String::from("OK")
+            }
+            "p" => match usize::from_str_radix(rest, 16).ok().and_then(|i| REGISTERS.get(i)) {
+                Some(name) => hex_u16(cpu.register(name).unwrap_or(0)),
+                None => String::from("E01"),
+            },
+            "P" => {
+                let register = rest.split_once('=').and_then(|(i, value)| {
+                    let name = REGISTERS.get(usize::from_str_radix(i, 16).ok()?)?;
+                    Some((name, parse_u16_le(value)?))
+                });
+                match register {
+                    Some((name, value)) => {
+                        cpu.set_register(name, value);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            "m" => match parse_address_length(rest) {
+                Some((address, length)) => (0..length.min(0x1000))
+                    .map(|i| format!("{:02x}", cpu.bus.peek(address.wrapping_add(i as u16))))
+                    .collect(),
+                None => String::from("E01"),
+            },
+            "M" => {
+                let parsed = rest.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_address_length(range)?;
+                    let bytes = parse_bytes(data)?;
+                    (bytes.len() == length).then_some((address, bytes))
+                });
+                match parsed {
+                    Some((address, bytes)) => {
+                        for (i, byte) in bytes.into_iter().enumerate() {
+                            cpu.bus.write_byte(address.wrapping_add(i as u16), byte);
+                        }
+                        cpu.bus.take_watch_hit();
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            "Z" | "z" => self.breakpoint(cpu, head == "Z", rest),
+            "H" => String::from("OK"),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        }
+    }
+
+    // Z/z TYPE,ADDR,KIND: 0/1はブレークポイント, 2/3/4は書き込み/読み出し/両方のウォッチポイント
+    fn breakpoint(&mut self, cpu: &mut CPU, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next().unwrap_or("");
+        let address = fields.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        let length = fields.next().and_then(|l| u16::from_str_radix(l, 16).ok()).unwrap_or(1).max(1);
+        let address = match address {
+            Some(address) => address,
+            None => return String::from("E01"),
+        };
+        match kind {
+            "0" | "1" => {
+                if insert {
+                    if !self.breakpoints.contains(&address) {
+                        self.breakpoints.push(address);
+                    }
+                } else {
+                    self.breakpoints.retain(|&b| b != address);
+                }
+            }
+            "2" | "3" | "4" => {
+                let watchpoint = Watchpoint {
+                    start: address,
+                    end: address.wrapping_add(length - 1),
+                    read: kind != "2",
+                    write: kind != "3",
+                    execute: false,
+                };
+                let same = |w: &Watchpoint| {
+                    w.start == watchpoint.start
+                        && w.end == watchpoint.end
+                        && w.read == watchpoint.read
+                        && w.write == watchpoint.write
+                        && !w.execute
+                };
+                if insert {
+                    cpu.bus.watchpoints.push(watchpoint);
+                } else if let Some(i) = cpu.bus.watchpoints.iter().position(same) {
+                    cpu.bus.watchpoints.remove(i);
+                }
+            }
+            _ => return String::new(),
+        }
+        String::from("OK")
+    }
+
+    fn query(&self, packet: &str) -> String {
+        let name = packet.split(':').next().unwrap_or("");
+        match name {
+            "qSupported" => String::from("PacketSize=4000;QStartNoAckMode+;hwbreak+"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    // 実行中にCtrl-C (0x03) が来ていたらtrue
+    fn poll_interrupt(&mut self) -> bool {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return false,
+        };
+        let mut byte = [0];
+        stream.set_nonblocking(true).ok();
+        let result = stream.read(&mut byte);
+        stream.set_nonblocking(false).ok();
+        match result {
+            Ok(0) => {
+                println!("gdb disconnected");
+                self.disconnect();
+                false
+            }
+            Ok(_) => byte[0] == 0x03,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => {
+                println!("gdb disconnected: {}", e);
+                self.disconnect();
+                false
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let stream = self.stream.as_mut().ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let stream = self.stream.as_mut().ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+        stream.write_all(data)
+    }
+
+    // $data#XX を1つ受け取る (チェックサムが合わなければ再送を頼む)
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            let (data, valid) = read_packet(|| self.read_byte())?;
+            if !self.no_ack {
+                self.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = frame(data);
+        loop {
+            self.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // '-'なら再送する
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// $data#XX を1つ読む (パケットの外のバイトは読み捨てる): (data, チェックサムが合っているか)
+fn read_packet(mut read_byte: impl FnMut() -> io::Result<u8>) -> io::Result<(Vec<u8>, bool)> {
+    while read_byte()? != b'$' {}
+    let mut data = Vec::new();
+    loop {
+        match read_byte()? {
+            b'#' => break,
+            byte => data.push(byte),
+        }
+    }
+    let checksum = [read_byte()?, read_byte()?];
+    let valid = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|c| u8::from_str_radix(c, 16).ok())
+        == Some(checksum_of(&data));
+    Ok((data, valid))
+}
+
+fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, (value >> 8) as u8)
+}
+
+fn parse_u16_le(text: &str) -> Option<u16> {
+    let bytes = parse_bytes(text)?;
+    match bytes[..] {
+        [low, high] => Some(low as u16 | (high as u16) << 8),
+        _ => None,
+    }
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// "ADDR,LEN"
+fn parse_address_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::model::Model;
+
+    fn new_stub() -> GdbStub {
+        GdbStub {
+            stream: None,
+            breakpoints: Vec::new(),
+            stopped: false,
+            stepping: false,
+            no_ack: false,
+            counter: 0,
+        }
+    }
+
+    fn new_cpu() -> Box<CPU> {
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]).unwrap();
+        Box::new(CPU::new(cartridge, Model::DMG, None))
+    }
+
+    // バイト列から続けてパケットを読む
+    fn read_all(mut input: &[u8]) -> Vec<(String, bool)> {
+        let mut packets = Vec::new();
+        while let Ok((data, valid)) = read_packet(|| {
+            let (&byte, rest) = input.split_first().ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+            input = rest;
+            Ok(byte)
+        }) {
+            packets.push((String::from_utf8(data).unwrap(), valid));
+        }
+        packets
+    }
+
+    #[test]
+    fn test_checksum_and_framing() {
+        assert_eq!(checksum_of(b""), 0x00);
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        // 0xFFを超えたら折り返す
+        assert_eq!(checksum_of(b"qSupported:multiprocess+"), 0xC6);
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame(""), "$#00");
+        assert_eq!(frame("S05"), "$S05#b8");
+
+        // 確認応答やCtrl-Cなどパケットの外のバイトは読み捨てる, チェックサムは大文字でもよい
+        let packets = read_all(b"+\x03$g#67-$m c000,4#FF$OK#9A$OK");
+        assert_eq!(packets, [
+            (String::from("g"), true),
+            (String::from("m c000,4"), false),
+            (String::from("OK"), true),
+        ]);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_u16(0x1234), "3412");
+        assert_eq!(parse_u16_le("3412"), Some(0x1234));
+        assert_eq!(parse_u16_le("34"), None);
+        assert_eq!(parse_u16_le("zz12"), None);
+        assert_eq!(parse_bytes("00ff7Fa0"), Some(vec![0x00, 0xFF, 0x7F, 0xA0]));
+        assert_eq!(parse_bytes(""), Some(vec![]));
+        assert_eq!(parse_bytes("abc"), None);
+        assert_eq!(parse_bytes("éa"), None);
+        assert_eq!(parse_address_length("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_address_length("c000"), None);
+        assert_eq!(parse_address_length("10000,1"), None);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut stub = new_stub();
+        let mut cpu = new_cpu();
+        // AF, BC, DE, HL, SP, PCの順にリトルエンディアン
+        assert_eq!(stub.command(&mut cpu, "G3412bc9a7856f0dedcfe0001"), "OK");
+        assert_eq!(cpu.register("BC"), Some(0x9ABC));
+        assert_eq!(cpu.register("SP"), Some(0xFEDC));
+        assert_eq!(cpu.pc, 0x0100);
+        assert_eq!(stub.command(&mut cpu, "g"), "3012bc9a7856f0dedcfe0001");
+        assert_eq!(stub.command(&mut cpu, "G3412"), "E01");
+        assert_eq!(stub.command(&mut cpu, "p5"), "0001");
+        assert_eq!(stub.command(&mut cpu, "P5=5001"), "OK");
+        assert_eq!(cpu.pc, 0x0150);
+        assert_eq!(stub.command(&mut cpu, "p6"), "E01");
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut stub = new_stub();
+        let mut cpu = new_cpu();
+        assert_eq!(stub.command(&mut cpu, "Mc000,3:0aFF7e"), "OK");
+        assert_eq!(stub.command(&mut cpu, "mc000,4"), "0aff7e00");
+        assert_eq!(cpu.bus.read_byte(0xC001), 0xFF);
+        // 長さとデータが合わない, 壊れた指定
+        assert_eq!(stub.command(&mut cpu, "Mc000,2:0a"), "E01");
+        assert_eq!(stub.command(&mut cpu, "Mc000,1"), "E01");
+        assert_eq!(stub.command(&mut cpu, "mc000"), "E01");
+        assert_eq!(stub.command(&mut cpu, "m0000,0"), "");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut stub = new_stub();
+        let mut cpu = new_cpu();
+        assert_eq!(stub.command(&mut cpu, "Z0,150,1"), "OK");
+        assert_eq!(stub.command(&mut cpu, "Z0,150,1"), "OK");
+        assert_eq!(stub.breakpoints, [0x0150]);
+        cpu.pc = 0x0150;
+        // 接続していなければ止まらない
+        assert!(!stub.should_break(&cpu));
+        assert_eq!(stub.command(&mut cpu, "z0,150,1"), "OK");
+        assert!(stub.breakpoints.is_empty());
+        assert_eq!(stub.command(&mut cpu, "Z0,xyz,1"), "E01");
+
+        // 2: 書き込み, 3: 読み出し, 4: 両方
+        assert_eq!(stub.command(&mut cpu, "Z2,c000,2"), "OK");
+        assert_eq!(stub.command(&mut cpu, "Z3,d000,1"), "OK");
+        let watchpoint = cpu.bus.watchpoints[0];
+        assert_eq!((watchpoint.start, watchpoint.end, watchpoint.read, watchpoint.write), (0xC000, 0xC001, false, true));
+        assert!(cpu.bus.watchpoints[1].read && !cpu.bus.watchpoints[1].write);
+        assert_eq!(stub.command(&mut cpu, "z2,c000,2"), "OK");
+        assert_eq!(cpu.bus.watchpoints.len(), 1);
+        // 未対応の種類
+        assert_eq!(stub.command(&mut cpu, "Z9,c000,1"), "");
+    }
+
+    // GDBの代わりにTCPで接続して決まった順にパケットをやり取りする
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: std::net::SocketAddr) -> Self {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(10))).unwrap();
+            Client { stream }
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn read_packet(&mut self) -> String {
+            let (data, valid) = read_packet(|| {
+                let mut byte = [0];
+                self.stream.read_exact(&mut byte)?;
+                Ok(byte[0])
+            })
+            .unwrap();
+            assert!(valid);
+            String::from_utf8(data).unwrap()
+        }
+
+        // 確認応答ありのやり取り
+        fn request(&mut self, packet: &str) -> String {
+            self.write(frame(packet).as_bytes());
+            assert_eq!(self.read_byte(), b'+', "{}", packet);
+            let reply = self.read_packet();
+            self.write(b"+");
+            reply
+        }
+
+        // QStartNoAckModeの後
+        fn request_no_ack(&mut self, packet: &str) -> String {
+            self.write(frame(packet).as_bytes());
+            self.read_packet()
+        }
+    }
+
+    // 実際の接続でserveを動かす: 確認応答と再送, QStartNoAckMode, c/sでの再開, Ctrl-C, k
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut client = Client::connect(address);
+            assert_eq!(client.request("qSupported:multiprocess+"), "PacketSize=4000;QStartNoAckMode+;hwbreak+");
+            assert_eq!(client.request("?"), "S05");
+
+            // チェックサムが違えば'-'で再送を頼まれる
+            client.write(b"$g#00");
+            assert_eq!(client.read_byte(), b'-');
+            client.write(frame("g").as_bytes());
+            assert_eq!(client.read_byte(), b'+');
+            let registers = client.read_packet();
+            assert_eq!(&registers[20..], "0001");
+            // '-'を返すと同じ応答を送り直す
+            client.write(b"-");
+            assert_eq!(client.read_packet(), registers);
+            client.write(b"+");
+
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            assert_eq!(client.request_no_ack("Z0,105,1"), "OK");
+            // ブレークポイントまで実行する
+            assert_eq!(client.request_no_ack("c"), "S05");
+            assert_eq!(client.request_no_ack("p5"), "0501");
+            // 1命令だけ実行する
+            assert_eq!(client.request_no_ack("s"), "S05");
+            assert_eq!(client.request_no_ack("p5"), "0601");
+
+            // 実行中のCtrl-Cで止まる
+            assert_eq!(client.request_no_ack("z0,105,1"), "OK");
+            client.write(frame("c").as_bytes());
+            client.write(&[0x03]);
+            assert_eq!(client.read_packet(), "S05");
+            client.write(frame("k").as_bytes());
+        });
+
+        let mut stub = GdbStub::accept(&listener).unwrap();
+        let mut cpu = new_cpu();
+        let mut killed = false;
+        for _ in 0..10_000_000 {
+            if stub.should_break(&cpu) && !stub.serve(&mut cpu) {
+                killed = true;
+                break;
+            }
+            cpu.step();
+        }
+        client.join().unwrap();
+        assert!(killed);
+        assert!(stub.no_ack);
+        assert!(stub.breakpoints.is_empty());
+    }
+}
@@ -1,10 +1,15 @@
 use crate::memory_bus::MemoryBus;
+use crate::png;
+use crate::state::{StateReader, StateWriter};
 
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
+pub const OAM_BEGIN: usize = 0xFE00;
+pub const OAM_END: usize = 0xFE9F;
+pub const OAM_SIZE: usize = OAM_END - OAM_BEGIN + 1;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 enum TilePixelValue {
     Zero,
     One,
@@ -26,6 +31,93 @@ fn tilePixelValueToColor(value: TilePixelValue) -> [u8; 3]{
     }
 }
 
+// DMGのパレット (BGP/OBP0/OBP1) で色番号を濃さに変換する
+fn apply_dmg_palette(palette: u8, value: TilePixelValue) -> TilePixelValue {
+    match (palette >> (value as u8 * 2)) & 0x03 {
+        0 => TilePixelValue::Zero,
+        1 => TilePixelValue::One,
+        2 => TilePixelValue::Two,
+        _ => TilePixelValue::Three,
+    }
+}
+
+// RGB555を24bitカラーに変換する
+// 色補正ありの場合は実機の液晶に近い発色にする (Gambatteと同じ近似式)
+pub fn rgb555_to_color(color: u16, color_correction: bool) -> [u8; 3] {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+    if color_correction {
+        [
+            ((r * 13 + g * 2 + b) >> 1) as u8,
+            ((g * 3 + b) << 1) as u8,
+            ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+        ]
+    } else {
+        [
+            (r << 3 | r >> 2) as u8,
+            (g << 3 | g >> 2) as u8,
+            (b << 3 | b >> 2) as u8,
+        ]
+    }
+}
+
+// CGBのパレットRAM (8パレット x 4色 x 2バイト)
+// BCPS/OCPSでインデックスを選び、BCPD/OCPDで読み書きする
+pub struct PaletteRam {
+    data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    pub fn new() -> Self {
+        PaletteRam {
+            data: [0; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        (if self.auto_increment { 0x80 } else { 0 }) | 0x40 | self.index
+    }
+
+    pub fn write_spec(&mut self, value: u8) {
+        self.auto_increment = value & 0x80 != 0;
+        self.index = value & 0x3F;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.data);
+        w.u8(self.index);
+        w.bool(self.auto_increment);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.data)?;
+        self.index = r.u8()? & 0x3F;
+        self.auto_increment = r.bool()?;
+        Ok(())
+    }
+
+    fn color(&self, palette: usize, value: TilePixelValue) -> u16 {
+        let offset = palette * 8 + value as usize * 2;
+        self.data[offset] as u16 | (self.data[offset + 1] as u16) << 8
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct LcdControlregisters {
     // 0xFF40
@@ -79,14 +171,32 @@ impl LcdStatusregisters {
 }
 
 pub struct GPU {
-    vram: [u8; VRAM_SIZE],
+    vram: [u8; VRAM_SIZE * 2], // CGBはVBKで2バンク切り替え
+    pub vram_bank: usize,
+    oam: [u8; OAM_SIZE],
     pub ly: u8,  // 0xFF44
     pub lyc: u8, // 0xFF45
     pub control: LcdControlregisters,
     pub status: LcdStatusregisters,
-    tile_set: [Tile; 384],
+    pub scy: u8,  // 0xFF42
+    pub scx: u8,  // 0xFF43
+    pub bgp: u8,  // 0xFF47
+    pub obp0: u8, // 0xFF48
+    pub obp1: u8, // 0xFF49
+    pub wy: u8,   // 0xFF4A
+    pub wx: u8,   // 0xFF4B
+    pub cgb_mode: bool,
+    pub bg_palette: PaletteRam,  // 0xFF68, 0xFF69
+    pub obj_palette: PaletteRam, // 0xFF6A, 0xFF6B
+    pub obj_priority_by_x: bool, // 0xFF6C (DMGと同じくX座標で優先度を決める)
+    pub color_correction: bool,
+    tile_set: [Tile; 384 * 2],
     scanline_counter: u16,
+    window_line: u8, // ウィンドウの内部ラインカウンタ
+    hblank_started: bool,
     pub frame: [u8; 160 * 3 * 144],
+    pub shades: [u8; 160 * 144], // DMGパレット適用後の濃さ (SGBの着色用)
+    pub frames: u64, // VBlankに入った回数
 }
 
 impl std::convert::From<LcdControlregisters> for u8 {
@@ -160,32 +270,64 @@ impl std::convert::From<u8> for LcdStatusregisters {
 impl GPU {
     pub fn new() -> Self {
         GPU {
-            vram: [0; VRAM_SIZE],
+            vram: [0; VRAM_SIZE * 2],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
             ly: 0,
             lyc: 0,
             control: LcdControlregisters::new(),
             status: LcdStatusregisters::new(),
-            tile_set: [empty_tile(); 384],
+            scy: 0,
+            scx: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            cgb_mode: false,
+            bg_palette: PaletteRam::new(),
+            obj_palette: PaletteRam::new(),
+            obj_priority_by_x: true,
+            color_correction: false,
+            tile_set: [empty_tile(); 384 * 2],
             scanline_counter: 0,
+            window_line: 0,
+            hblank_started: false,
             frame: [0 as u8; 160 * 3 * 144],
+            shades: [0; 160 * 144],
+            frames: 0,
         }
     }
 
     pub fn read_vram(&self, address: usize) -> u8 {
-        self.vram[address]
+        self.vram[self.vram_bank * VRAM_SIZE + address]
+    }
+
+    pub fn read_oam(&self, address: usize) -> u8 {
+        self.oam[address]
+    }
+
+    pub fn write_oam(&mut self, address: usize, value: u8) {
+        self.oam[address] = value;
     }
 
     pub fn write_vram(&mut self, index: usize, value: u8) {
-        self.vram[index] = value;
+        let bank_offset = self.vram_bank * VRAM_SIZE;
+        self.vram[bank_offset + index] = value;
 
         if index >= 0x1800 {
             return;
         }
+        self.update_tile_row(self.vram_bank, index);
+    }
 
-        let normalized_index = index & 0xFFFE;
+    // VRAMの2バイトからタイルの1行をデコードする
+    fn update_tile_row(&mut self, bank: usize, index: usize) {
+        let bank_offset = bank * VRAM_SIZE;
+        let normalized_index = bank_offset + (index & 0xFFFE);
         let byte1 = self.vram[normalized_index];
         let byte2 = self.vram[normalized_index + 1];
-        let tile_index = index / 16;
+        let tile_index = bank * 384 + index / 16;
         let row_index = (index % 16) / 2;
 
         for pixel_index in 0..8 {
@@ -204,6 +346,71 @@ impl GPU {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.u8(self.vram_bank as u8);
+        w.bytes(&self.oam);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(u8::from(self.control));
+        w.u8(u8::from(self.status));
+        for value in [self.scy, self.scx, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            w.u8(value);
+        }
+        self.bg_palette.save_state(w);
+        self.obj_palette.save_state(w);
+        w.bool(self.obj_priority_by_x);
+        w.u16(self.scanline_counter);
+        w.bool(self.hblank_started);
+        w.u8(self.window_line);
+        w.bytes(&self.frame);
+        w.bytes(&self.shades);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.vram)?;
+        self.vram_bank = (r.u8()? & 0x01) as usize;
+        r.bytes_into(&mut self.oam)?;
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.control = LcdControlregisters::from(r.u8()?);
+        self.status = LcdStatusregisters::from(r.u8()?);
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.bgp = r.u8()?;
+        self.obp0 = r.u8()?;
+        self.obp1 = r.u8()?;
+        self.wy = r.u8()?;
+        self.wx = r.u8()?;
+        self.bg_palette.load_state(r)?;
+        self.obj_palette.load_state(r)?;
+        self.obj_priority_by_x = r.bool()?;
+        self.scanline_counter = r.u16()?;
+        self.hblank_started = r.bool()?;
+        self.window_line = r.u8()?;
+        r.bytes_into(&mut self.frame)?;
+        r.bytes_into(&mut self.shades)?;
+
+        // 範囲外の値はモードの計算でアンダーフローするので読み込まない
+        if self.ly > 153 {
+            return Err(format!("save state has LY {}, expected 0-153", self.ly));
+        }
+        if self.scanline_counter >= 456 {
+            return Err(format!("save state has a line cycle of {}, expected 0-455", self.scanline_counter));
+        }
+        if self.window_line > 144 {
+            return Err(format!("save state has window line {}, expected 0-144", self.window_line));
+        }
+
+        // タイルのデコード結果はVRAMから作り直す
+        for bank in 0..2 {
+            for index in (0..0x1800).step_by(2) {
+                self.update_tile_row(bank, index);
+            }
+        }
+        Ok(())
+    }
+
     pub fn update(&mut self, cycles: u16) {
         // setLCDStatus();
         // if (!isLCDEnabled()) return;
@@ -213,50 +420,528 @@ impl GPU {
 
         if self.scanline_counter >= 456 {
             self.scanline_counter -= 456;
+            if self.ly < 144 {
+                self.draw_scan_line(self.ly);
+            }
             self.ly += 1;
-            let currentline = self.ly;
-            if currentline == 144 {
+            if self.ly == 144 {
                 // VBlank
-            } else if currentline > 153 {
+                self.frames += 1;
+            } else if self.ly > 153 {
                 self.ly = 0;
-                self.draw_all();
-            } else if currentline < 144 {
-                self.draw_scan_line(currentline);
+                self.window_line = 0;
             }
         }
+        self.update_mode();
+    }
+
+    // LYとライン内の経過サイクルからSTATのモードを決める
+    fn update_mode(&mut self) {
+        let mode = if self.ly >= 144 {
+            1
+        } else if self.scanline_counter < 80 {
+            2
+        } else if self.scanline_counter < 252 {
+            3
+        } else {
+            0
+        };
+        if mode == 0 && self.status.lyc_ppu_mode != 0 {
+            self.hblank_started = true;
+        }
+        self.status.lyc_ppu_mode = mode;
+        self.status.lyc_eq_ly = self.ly == self.lyc;
+    }
+
+    // 今の画面 (160x144) をPNGで保存する
+    pub fn save_screenshot(&self, path: &str, scale: usize) -> Result<(), String> {
+        png::save(path, 160, 144, &self.frame, scale)
+    }
+
+    // 次にモードが切り替わる (VBlank中はラインが終わる) までのサイクル数
+    pub fn cycles_until_mode_change(&self) -> u16 {
+        if self.ly >= 144 || self.scanline_counter >= 252 {
+            456 - self.scanline_counter
+        } else if self.scanline_counter >= 80 {
+            252 - self.scanline_counter
+        } else {
+            80 - self.scanline_counter
+        }
+    }
+
+    // HBlankに入ったか (HBlank HDMA用, 読むとクリアされる)
+    pub fn take_hblank(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    // タイル番号からtile_setのインデックスを求める (LCDC bit4が0なら0x9000基準の符号付き)
+    fn tile_index(&self, number: u8, bank: usize) -> usize {
+        let index = if self.control.tiles {
+            number as usize
+        } else {
+            (256 + number as i8 as i16) as usize
+        };
+        bank * 384 + index
+    }
+
+    // BG/ウィンドウの1ドット: (色番号, CGBの属性)
+    fn bg_pixel(&self, map: usize, x: u8, y: u8) -> (TilePixelValue, u8) {
+        let offset = map + (y as usize / 8) * 32 + x as usize / 8;
+        let number = self.vram[offset];
+        // CGBはVRAMバンク1の同じ位置に属性がある
+        let attributes = if self.cgb_mode { self.vram[VRAM_SIZE + offset] } else { 0 };
+        let bank = ((attributes >> 3) & 0x01) as usize;
+        let mut tx = (x % 8) as usize;
+        let mut ty = (y % 8) as usize;
+        if attributes & 0x20 != 0 {
+            tx = 7 - tx;
+        }
+        if attributes & 0x40 != 0 {
+            ty = 7 - ty;
+        }
+        let tile = &self.tile_set[self.tile_index(number, bank)];
+        (tile[ty][tx], attributes)
     }
 
     fn draw_scan_line(&mut self, line: u8) {
         //1ライン描画
-        //self.frame
-    }
-
-    fn draw_all(&mut self){
-        for addr in 0x9800..=0x9BFF{
-            let addr = addr as usize - VRAM_BEGIN;
-            let index = self.vram[addr] as usize;
-            let tile = self.tile_set[index];
-            let i = addr - 0x1800;
-            let x = i % 32;
-            let y = i / 32;
-            
-            for ty in 0..8 {
-                for tx in 0..8 {
-                    let value = tile[ty][tx];
-                    let color = tilePixelValueToColor(value);
-                    let rx = x * 8 + tx;
-                    let ry = y * 8 + ty;
-                    if rx >= 160 || ry >= 144 {
-                        continue;
+        let mut values = [TilePixelValue::Zero; 160];
+        let mut attributes = [0u8; 160];
+
+        // DMGではLCDC bit0が0だとBGとウィンドウは白になる (CGBでは優先度の指定になる)
+        let bg_enabled = self.cgb_mode || self.control.bg_window_enabled;
+        let window_visible = bg_enabled
+            && self.control.window_enabled
+            && line >= self.wy
+            && self.wx <= 166;
+        if bg_enabled {
+            let bg_map = if self.control.bg_tile_map { 0x1C00 } else { 0x1800 };
+            let window_map = if self.control.window_tile_map { 0x1C00 } else { 0x1800 };
+            for x in 0..160u8 {
+                let (value, attribute) = if window_visible && x as u16 + 7 >= self.wx as u16 {
+                    let wx = (x as u16 + 7 - self.wx as u16) as u8;
+                    self.bg_pixel(window_map, wx, self.window_line)
+                } else {
+                    self.bg_pixel(bg_map, x.wrapping_add(self.scx), line.wrapping_add(self.scy))
+                };
+                values[x as usize] = value;
+                attributes[x as usize] = attribute;
+            }
+        }
+        if window_visible {
+            self.window_line += 1;
+        }
+
+        for x in 0..160 {
+            let color = if self.cgb_mode {
+                let palette = (attributes[x] & 0x07) as usize;
+                rgb555_to_color(self.bg_palette.color(palette, values[x]), self.color_correction)
+            } else {
+                let shade = apply_dmg_palette(self.bgp, values[x]);
+                self.shades[x + line as usize * 160] = shade as u8;
+                tilePixelValueToColor(shade)
+            };
+            self.set_pixel(x, line as usize, color);
+        }
+
+        if self.control.obj_enabled {
+            self.draw_objects(line, &values, &attributes);
+        }
+    }
+
+    fn draw_objects(&mut self, line: u8, bg_values: &[TilePixelValue; 160], bg_attributes: &[u8; 160]) {
+        let height = if self.control.obj_size { 16 } else { 8 };
+
+        // 1ラインに表示されるのはOAMの先頭から10個まで
+        let mut objects: Vec<usize> = (0..40)
+            .filter(|i| {
+                let y = self.oam[i * 4] as i16 - 16;
+                let line = line as i16;
+                line >= y && line < y + height
+            })
+            .take(10)
+            .collect();
+
+        // 優先度の高いものから並べる
+        // DMGはX座標が小さいほど優先 (同じならOAMの順), CGBはOAMの順のみ
+        if self.obj_priority_by_x {
+            objects.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+        }
+
+        // 各列で最も優先度の高いOBJの不透明なドット: (色番号, 属性)
+        // BGより後ろに表示するかはそのOBJだけで決まり, 下のOBJが透けて見えることはない
+        let mut pixels: [Option<(TilePixelValue, u8)>; 160] = [None; 160];
+        for i in objects {
+            let y = self.oam[i * 4] as i16 - 16;
+            let x = self.oam[i * 4 + 1] as i16 - 8;
+            let mut number = self.oam[i * 4 + 2];
+            let flags = self.oam[i * 4 + 3];
+
+            let mut ty = (line as i16 - y) as u8;
+            if flags & 0x40 != 0 {
+                ty = height as u8 - 1 - ty;
+            }
+            if height == 16 {
+                number &= 0xFE;
+            }
+            let bank = if self.cgb_mode { ((flags >> 3) & 0x01) as usize } else { 0 };
+            // OBJは常に0x8000基準
+            let tile = self.tile_set[bank * 384 + number as usize + ty as usize / 8];
+
+            for tx in 0..8 {
+                let sx = x + tx as i16;
+                if !(0..160).contains(&sx) || pixels[sx as usize].is_some() {
+                    continue;
+                }
+                let column = if flags & 0x20 != 0 { 7 - tx } else { tx };
+                let value = tile[ty as usize % 8][column];
+                if value != TilePixelValue::Zero {
+                    pixels[sx as usize] = Some((value, flags));
+                }
+            }
+        }
+
+        for (sx, pixel) in pixels.into_iter().enumerate() {
+            let (value, flags) = match pixel {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            // BGの色番号1-3より後ろに表示するか
+            let behind_bg = if self.cgb_mode {
+                self.control.bg_window_enabled
+                    && (flags & 0x80 != 0 || bg_attributes[sx] & 0x80 != 0)
+            } else {
+                flags & 0x80 != 0
+            };
+            if behind_bg && bg_values[sx] != TilePixelValue::Zero {
+                continue;
+            }
+
+            let color = if self.cgb_mode {
+                let palette = (flags & 0x07) as usize;
+                rgb555_to_color(self.obj_palette.color(palette, value), self.color_correction)
+            } else {
+                let palette = if flags & 0x10 != 0 { self.obp1 } else { self.obp0 };
+                let shade = apply_dmg_palette(palette, value);
+                self.shades[sx + line as usize * 160] = shade as u8;
+                tilePixelValueToColor(shade)
+            };
+            self.set_pixel(sx, line as usize, color);
+        }
+    }
+
+    // SGBのVRAM転送: 画面に表示されているBGのタイルデータを順に4KB分並べる
+    pub fn screen_tile_data(&self) -> Vec<u8> {
+        let bg_map = if self.control.bg_tile_map { 0x1C00 } else { 0x1800 };
+        let mut data = Vec::with_capacity(0x1000);
+        for i in 0..0x100 {
+            let number = self.vram[bg_map + (i / 20) * 32 + i % 20];
+            let address = if self.control.tiles {
+                number as usize * 16
+            } else {
+                (0x1000 + number as i8 as isize * 16) as usize
+            };
+            data.extend_from_slice(&self.vram[address..address + 16]);
+        }
+        data
+    }
+
+    // デバッグ表示用: 全タイルを横16個ずつ並べる (CGBはバンク1を右に並べる)
+    // パレットは通さず色番号をそのまま濃さにする: (RGB24, 幅)
+    pub fn render_tiles(&self) -> (Vec<u8>, usize) {
+        let banks = if self.cgb_mode { 2 } else { 1 };
+        let width = 128 * banks;
+        let mut data = vec![0; width * 192 * 3];
+        for (i, tile) in self.tile_set[..384 * banks].iter().enumerate() {
+            let left = (i / 384) * 128 + (i % 16) * 8;
+            let top = (i % 384 / 16) * 8;
+            for (y, row) in tile.iter().enumerate() {
+                for (x, value) in row.iter().enumerate() {
+                    let o = ((top + y) * width + left + x) * 3;
+                    data[o..o + 3].copy_from_slice(&tilePixelValueToColor(*value));
+                }
+            }
+        }
+        (data, width)
+    }
+
+    // デバッグ表示用: BGマップ (0x9800なら0x1800, 0x9C00なら0x1C00) を256x256で描く
+    // BGに使われているマップにはSCX/SCYの表示範囲を赤枠で重ねる
+    pub fn render_bg_map(&self, map: usize) -> Vec<u8> {
+        let mut data = vec![0; 256 * 256 * 3];
+        for y in 0..=255u8 {
+            for x in 0..=255u8 {
+                let (value, attributes) = self.bg_pixel(map, x, y);
+                let color = if self.cgb_mode {
+                    rgb555_to_color(self.bg_palette.color((attributes & 0x07) as usize, value), self.color_correction)
+                } else {
+                    tilePixelValueToColor(apply_dmg_palette(self.bgp, value))
+                };
+                let o = (y as usize * 256 + x as usize) * 3;
+                data[o..o + 3].copy_from_slice(&color);
+            }
+        }
+
+        let bg_map = if self.control.bg_tile_map { 0x1C00 } else { 0x1800 };
+        if map == bg_map {
+            let mut mark = |x: u8, y: u8| {
+                let o = (y as usize * 256 + x as usize) * 3;
+                data[o..o + 3].copy_from_slice(&[255, 0, 0]);
+            };
+            for i in 0..160u8 {
+                mark(self.scx.wrapping_add(i), self.scy);
+                mark(self.scx.wrapping_add(i), self.scy.wrapping_add(143));
+            }
+            for i in 0..144u8 {
+                mark(self.scx, self.scy.wrapping_add(i));
+                mark(self.scx.wrapping_add(159), self.scy.wrapping_add(i));
+            }
+        }
+        data
+    }
+
+    // デバッグ表示用: OAMのi番目のオブジェクトを8x16で描く (透明な部分と8x8モードの下半分はNone)
+    pub fn render_object(&self, i: usize) -> [[Option<[u8; 3]>; 8]; 16] {
+        let mut pixels = [[None; 8]; 16];
+        let height = if self.control.obj_size { 16 } else { 8 };
+        let mut number = self.oam[i * 4 + 2];
+        let flags = self.oam[i * 4 + 3];
+        if height == 16 {
+            number &= 0xFE;
+        }
+        let bank = if self.cgb_mode { ((flags >> 3) & 0x01) as usize } else { 0 };
+        for (y, row) in pixels.iter_mut().enumerate().take(height) {
+            let ty = if flags & 0x40 != 0 { height - 1 - y } else { y };
+            let tile = &self.tile_set[bank * 384 + number as usize + ty / 8];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let column = if flags & 0x20 != 0 { 7 - x } else { x };
+                let value = tile[ty % 8][column];
+                if value == TilePixelValue::Zero {
+                    continue;
+                }
+                *pixel = Some(if self.cgb_mode {
+                    rgb555_to_color(self.obj_palette.color((flags & 0x07) as usize, value), self.color_correction)
+                } else {
+                    let palette = if flags & 0x10 != 0 { self.obp1 } else { self.obp0 };
+                    tilePixelValueToColor(apply_dmg_palette(palette, value))
+                });
+            }
+        }
+        pixels
+    }
+
+    // デバッグ表示用: パレットの4色 (DMGはBGがBGPの1本, OBJがOBP0/OBP1の2本)
+    pub fn palette_colors(&self, obj: bool, palette: usize) -> Option<[[u8; 3]; 4]> {
+        let values = [TilePixelValue::Zero, TilePixelValue::One, TilePixelValue::Two, TilePixelValue::Three];
+        if self.cgb_mode {
+            let ram = if obj { &self.obj_palette } else { &self.bg_palette };
+            return Some(values.map(|value| rgb555_to_color(ram.color(palette, value), self.color_correction)));
+        }
+        let register = match (obj, palette) {
+            (false, 0) => self.bgp,
+            (true, 0) => self.obp0,
+            (true, 1) => self.obp1,
+            _ => return None,
+        };
+        Some(values.map(|value| tilePixelValueToColor(apply_dmg_palette(register, value))))
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let o = (x + y * 160) * 3;
+        self.frame[o] = color[0];
+        self.frame[o + 1] = color[1];
+        self.frame[o + 2] = color[2];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const LIGHT: [u8; 3] = [175, 175, 175];
+    const DARK: [u8; 3] = [85, 85, 85];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    // LCD, BG, OBJ (8x8) を有効にし, タイルは0x8000基準, マップは0x9800
+    fn new_gpu(cgb: bool) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.cgb_mode = cgb;
+        gpu.obj_priority_by_x = !cgb;
+        gpu.control = LcdControlregisters::from(0x93);
+        gpu.bgp = 0xE4;
+        gpu.obp0 = 0xE4;
+        gpu.obp1 = 0xE4;
+        gpu
+    }
+
+    // タイルの1行 (lowとhighの2バイト) を全ての行に書く
+    fn set_tile(gpu: &mut GPU, bank: usize, number: usize, low: u8, high: u8) {
+        gpu.vram_bank = bank;
+        for row in 0..8 {
+            gpu.write_vram(number * 16 + row * 2, low);
+            gpu.write_vram(number * 16 + row * 2 + 1, high);
+        }
+        gpu.vram_bank = 0;
+    }
+
+    fn set_object(gpu: &mut GPU, i: usize, x: u8, number: u8, flags: u8) {
+        gpu.oam[i * 4..i * 4 + 4].copy_from_slice(&[16, x, number, flags]);
+    }
+
+    fn pixel(gpu: &GPU, x: usize, y: usize) -> [u8; 3] {
+        let o = (x + y * 160) * 3;
+        [gpu.frame[o], gpu.frame[o + 1], gpu.frame[o + 2]]
+    }
+
+    // 優先度の高いOBJがBGの後ろに隠れるとき, 下のOBJは見えずBGが見える
+    #[test]
+    fn test_object_behind_bg() {
+        for cgb in [false, true] {
+            let mut gpu = new_gpu(cgb);
+            let [white, light, dark, black] = if cgb {
+                // パレット0: 白, 明るい灰, 暗い灰, 黒 (BGとOBJで共通)
+                let colors = [0x7FFFu16, 0x56B5, 0x294A, 0x0000];
+                for palette in [&mut gpu.bg_palette, &mut gpu.obj_palette] {
+                    palette.write_spec(0x80);
+                    for color in colors {
+                        palette.write_data(color as u8);
+                        palette.write_data((color >> 8) as u8);
                     }
-                    let o = (rx + ry * 160) * 3;
-                    // {println!("{}", o / 3);};
-                    // {println!("{},{}", x, y);};
-                    self.frame[o] = color[0];
-                    self.frame[o + 1] = color[1];
-                    self.frame[o + 2] = color[2];
                 }
+                colors.map(|color| rgb555_to_color(color, false))
+            } else {
+                [WHITE, LIGHT, DARK, BLACK]
+            };
+            // BG: 0-7列目は色番号1, 8列目からは色番号0
+            set_tile(&mut gpu, 0, 1, 0xFF, 0x00);
+            gpu.vram[0x1800] = 1;
+            set_tile(&mut gpu, 0, 2, 0xFF, 0xFF);
+            set_tile(&mut gpu, 0, 3, 0x00, 0xFF);
+            // 優先度の高いOBJ (OAM 0, X=4-11) はBGの後ろ, 低いOBJ (OAM 1) は手前
+            // DMGでは同じX座標ならOAMの順, CGBはX座標に関係なくOAMの順
+            set_object(&mut gpu, 0, 12, 2, 0x80);
+            set_object(&mut gpu, 1, if cgb { 10 } else { 12 }, 3, 0x00);
+            gpu.draw_scan_line(0);
+
+            // CGBのOAM 1は2-3列目だけ見える
+            for x in 0..4 {
+                let expected = if cgb && x >= 2 { dark } else { light };
+                assert_eq!(pixel(&gpu, x, 0), expected, "cgb={} x={}", cgb, x);
+            }
+            for x in 4..8 {
+                assert_eq!(pixel(&gpu, x, 0), light, "cgb={} x={}", cgb, x);
+            }
+            for x in 8..12 {
+                assert_eq!(pixel(&gpu, x, 0), black, "cgb={} x={}", cgb, x);
             }
+            assert_eq!(pixel(&gpu, 12, 0), white, "cgb={}", cgb);
         }
     }
+
+    // DMGはX座標が小さいOBJが優先 (OAMの順に関係なく)
+    #[test]
+    fn test_object_priority_by_x() {
+        let mut gpu = new_gpu(false);
+        set_tile(&mut gpu, 0, 2, 0xFF, 0xFF);
+        set_tile(&mut gpu, 0, 3, 0x00, 0xFF);
+        set_object(&mut gpu, 0, 12, 2, 0x00);
+        set_object(&mut gpu, 1, 10, 3, 0x00);
+        gpu.draw_scan_line(0);
+        assert_eq!(pixel(&gpu, 3, 0), DARK);
+        assert_eq!(pixel(&gpu, 9, 0), DARK);
+        assert_eq!(pixel(&gpu, 10, 0), BLACK);
+
+        // CGBと同じくOAMの順にするとOAM 0が優先
+        gpu.obj_priority_by_x = false;
+        gpu.draw_scan_line(0);
+        assert_eq!(pixel(&gpu, 3, 0), DARK);
+        assert_eq!(pixel(&gpu, 4, 0), BLACK);
+        assert_eq!(pixel(&gpu, 9, 0), BLACK);
+    }
+
+    // CGBの属性: VRAMバンク (bit3), 左右反転 (bit5), 上下反転 (bit6), パレット (bit0-2)
+    #[test]
+    fn test_cgb_object_attributes() {
+        let mut gpu = new_gpu(true);
+        // OBJパレット2の色番号3は赤
+        gpu.obj_palette.write_spec(0x80 | (2 * 8 + 3 * 2));
+        gpu.obj_palette.write_data(0x1F);
+        gpu.obj_palette.write_data(0x00);
+        // バンク0のタイル1は左から2ドット目, バンク1のタイル1は左端
+        set_tile(&mut gpu, 0, 1, 0x40, 0x40);
+        set_tile(&mut gpu, 1, 1, 0x80, 0x80);
+        // バンク1のタイル2は上端の行だけ
+        gpu.vram_bank = 1;
+        gpu.write_vram(2 * 16, 0xFF);
+        gpu.write_vram(2 * 16 + 1, 0xFF);
+        gpu.vram_bank = 0;
+
+        const RED: [u8; 3] = [255, 0, 0];
+        set_object(&mut gpu, 0, 8, 1, 0x08 | 0x20 | 0x02);
+        set_object(&mut gpu, 1, 16, 1, 0x02);
+        set_object(&mut gpu, 2, 24, 2, 0x08 | 0x40 | 0x02);
+        gpu.draw_scan_line(0);
+        gpu.draw_scan_line(7);
+
+        // バンク1を左右反転すると右端
+        assert_eq!(pixel(&gpu, 7, 0), RED);
+        assert_eq!(pixel(&gpu, 6, 0), BLACK);
+        assert_eq!(pixel(&gpu, 0, 0), BLACK);
+        // バンク0のまま
+        assert_eq!(pixel(&gpu, 9, 0), RED);
+        assert_eq!(pixel(&gpu, 8, 0), BLACK);
+        // 上下反転すると上端の行が8ライン目に来る
+        assert_eq!(pixel(&gpu, 16, 0), BLACK);
+        assert_eq!(pixel(&gpu, 16, 7), RED);
+        assert_eq!(pixel(&gpu, 23, 7), RED);
+    }
+
+    // BGマップの表示: SCX/SCYの範囲 (160x144) を赤枠で囲み, 256を超えたら折り返す
+    #[test]
+    fn test_render_bg_map_viewport() {
+        const RED: [u8; 3] = [255, 0, 0];
+        let mut gpu = new_gpu(false);
+        gpu.scx = 200;
+        gpu.scy = 240;
+        let data = gpu.render_bg_map(0x1800);
+        let at = |x: usize, y: usize| {
+            let o = (y * 256 + x) * 3;
+            [data[o], data[o + 1], data[o + 2]]
+        };
+        // 上下の辺はy=240と(240+143)%256=127, 左右の辺はx=200と(200+159)%256=103
+        for (x, y) in [(200, 240), (255, 240), (0, 240), (103, 240), (200, 127), (103, 127), (200, 0), (103, 100)] {
+            assert_eq!(at(x, y), RED, "({}, {})", x, y);
+        }
+        for (x, y) in [(199, 240), (104, 240), (201, 241), (50, 200), (200, 128), (102, 0)] {
+            assert_eq!(at(x, y), WHITE, "({}, {})", x, y);
+        }
+        // BGに使われていないマップには枠を描かない
+        assert!(gpu.render_bg_map(0x1C00).chunks(3).all(|pixel| pixel == WHITE));
+    }
+
+    // BCPS/OCPSのbit7が立っていればBCPD/OCPDへの書き込みでインデックスが進む (0x3Fの次は0)
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut palette = PaletteRam::new();
+        palette.write_spec(0x80 | 0x3E);
+        assert_eq!(palette.read_spec(), 0xFE);
+        palette.write_data(0x12);
+        palette.write_data(0x34);
+        palette.write_data(0x56);
+        assert_eq!(palette.read_spec(), 0xC1);
+        assert_eq!(palette.color(7, TilePixelValue::Three), 0x3412);
+        assert_eq!(palette.color(0, TilePixelValue::Zero) & 0xFF, 0x56);
+
+        // 読み出しでは進まない, bit7が0なら書き込みでも進まない
+        palette.write_spec(0x3E);
+        assert_eq!(palette.read_data(), 0x12);
+        assert_eq!(palette.read_data(), 0x12);
+        palette.write_data(0x78);
+        palette.write_data(0x9A);
+        assert_eq!(palette.read_spec(), 0x7E);
+        assert_eq!(palette.read_data(), 0x9A);
+    }
 }
@@ -0,0 +1,232 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// フレームレートの制御
+// モニタのリフレッシュレート (vsync) には頼らず, 実機と同じ4194304/70224Hz (約59.73Hz) でフレームを進める
+// 待つ時刻は開始時刻からのフレーム数で決めるので, 誤差が積み重ならない
+
+pub const CYCLES_PER_FRAME: u32 = 70224;
+const CYCLES_PER_SECOND: u64 = 4194304;
+
+// 選べる速さ (--speedと-/=キー)
+pub const SPEEDS: [f64; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+// これ以上遅れたら追いつこうとせず, 基準の時刻を取り直す (デバッガで止めていたときなど)
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// sleepは遅れることがあるので, 最後のこの時間は空回りして待つ
+const SPIN: Duration = Duration::from_millis(1);
+
+pub struct FrameLimiter {
+    start: Instant,
+    frames: u64, // startから進めたフレーム数
+    speed: f64, // 実機に対する速さ
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        FrameLimiter {
+            start: Instant::now(),
+            frames: 0,
+            speed: 1.0,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.frames = 0;
+    }
+
+    // 1フレーム進めて, そのフレームを表示する時刻まで待つ
+    pub fn wait(&mut self) {
+        self.frames += 1;
+        let deadline = self.start + frame_time(self.frames).div_f64(self.speed);
+        let now = Instant::now();
+        if now > deadline + MAX_LAG {
+            self.reset();
+            return;
+        }
+        if deadline > now + SPIN {
+            thread::sleep(deadline - now - SPIN);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// 一時停止とコマ送り (N)
+// コマ送りは次のフレームの区切りまでCPUを進めてから止まる
+pub struct Pause {
+    pub paused: bool,
+    advancing: bool,
+}
+
+impl Pause {
+    pub fn new(paused: bool) -> Self {
+        Pause {
+            paused,
+            advancing: false,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.paused = !self.paused;
+        self.advancing = false;
+    }
+
+    pub fn advance(&mut self) {
+        self.paused = true;
+        self.advancing = true;
+    }
+
+    // CPUを進めるか
+    pub fn running(&self) -> bool {
+        !self.paused || self.advancing
+    }
+
+    // フレームを1つ進め終わった
+    pub fn end_frame(&mut self) {
+        self.advancing = false;
+    }
+}
+
+// エミュレートしているフレームの区切り
+// 1フレームはVBlankに入るまで (PPUが止まっていても70224サイクルで区切る)
+pub struct FrameClock {
+    cycles: u32,
+    last_frame: u64,
+}
+
+impl FrameClock {
+    pub fn new(frames: u64) -> Self {
+        FrameClock {
+            cycles: 0,
+            last_frame: frames,
+        }
+    }
+
+    // cycles (実時間で数えたサイクル数) だけ進めて, フレームが終わったらtrue
+    pub fn tick(&mut self, frames: u64, cycles: u32) -> bool {
+        self.cycles += cycles;
+        if frames == self.last_frame && self.cycles < CYCLES_PER_FRAME {
+            return false;
+        }
+        self.last_frame = frames;
+        self.cycles = 0;
+        true
+    }
+}
+
+// framesフレーム分の実時間
+fn frame_time(frames: u64) -> Duration {
+    let nanos = frames as u128 * CYCLES_PER_FRAME as u128 * 1_000_000_000 / CYCLES_PER_SECOND as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CPU;
+    use crate::model::Model;
+
+    // 止まっている間にコマ送りすると, 1フレームだけ進んで画面が変わる
+    #[test]
+    fn test_frame_advance() {
+        // INC A; LDH (SCY),A; LD HL,8000; LD (HL),A; JR -9
+        let program = [0x3C, 0xE0, 0x43, 0x21, 0x00, 0x80, 0x77, 0x18, 0xF7];
+        let mut raw = vec![0; 0x8000];
+        raw[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        let mut cpu = CPU::new(Cartridge::from_bytes(raw).unwrap(), Model::DMG, None);
+        let mut pause = Pause::new(true);
+        let mut clock = FrameClock::new(cpu.bus.gpu.frames);
+        let mut emulate = |cpu: &mut CPU, pause: &mut Pause| {
+            while pause.running() {
+                let cycles = cpu.step_block();
+                if clock.tick(cpu.bus.gpu.frames, cycles) {
+                    pause.end_frame();
+                }
+            }
+        };
+
+        // 止まったままなら進まない
+        emulate(&mut cpu, &mut pause);
+        let start = cpu.bus.gpu.frames;
+        let frame = cpu.bus.gpu.frame;
+
+        pause.advance();
+        emulate(&mut cpu, &mut pause);
+        assert!(pause.paused);
+        assert_eq!(cpu.bus.gpu.frames, start + 1);
+        assert_ne!(cpu.bus.gpu.frame, frame);
+
+        // もう一度コマ送りすると次のフレームへ
+        let frame = cpu.bus.gpu.frame;
+        pause.advance();
+        emulate(&mut cpu, &mut pause);
+        assert_eq!(cpu.bus.gpu.frames, start + 2);
+        assert_ne!(cpu.bus.gpu.frame, frame);
+    }
+
+    // 512フレームがちょうど8.572265625秒. 何フレーム目でも開始からの計算なので誤差がたまらない
+    #[test]
+    fn test_frame_time() {
+        assert_eq!(frame_time(0), Duration::ZERO);
+        assert_eq!(frame_time(1), Duration::from_nanos(16_742_706));
+        assert_eq!(frame_time(512), Duration::from_nanos(8_572_265_625));
+        // 約4日分
+        assert_eq!(frame_time(512 * 40_000), frame_time(512) * 40_000);
+        // 1フレームずつ足していたら約6ミリ秒ずれている
+        assert!(frame_time(512 * 40_000) - frame_time(1) * (512 * 40_000) > Duration::from_millis(5));
+        // 隣り合うフレームの差は1ナノ秒以内しか変わらない
+        for frames in 1_000_000..1_001_000 {
+            let delta = frame_time(frames + 1) - frame_time(frames);
+            assert!(delta == Duration::from_nanos(16_742_706) || delta == Duration::from_nanos(16_742_707));
+        }
+    }
+
+    // MAX_LAGより遅れていたら待たずに基準の時刻を取り直す
+    #[test]
+    fn test_max_lag_reset() {
+        let mut limiter = FrameLimiter::new();
+        limiter.start = Instant::now() - Duration::from_secs(1);
+        limiter.frames = 10;
+        let before = Instant::now();
+        limiter.wait();
+        assert_eq!(limiter.frames, 0);
+        assert!(limiter.start >= before);
+
+        // 取り直した後は普通に1フレーム分待つ
+        limiter.set_speed(8.0);
+        limiter.wait();
+        assert_eq!(limiter.frames, 1);
+        assert!(Instant::now() >= limiter.start + frame_time(1).div_f64(8.0));
+    }
+
+    // PPUが止まっていてframesが変わらなくても70224サイクルで区切る
+    #[test]
+    fn test_frame_clock() {
+        let mut clock = FrameClock::new(5);
+        assert!(!clock.tick(5, 70000));
+        assert!(!clock.tick(5, 220));
+        assert!(clock.tick(5, 4));
+        // 区切ったらサイクル数は0から
+        assert!(!clock.tick(5, CYCLES_PER_FRAME - 1));
+        assert!(clock.tick(5, 1));
+
+        // framesが変われば途中でも区切る
+        assert!(!clock.tick(5, 100));
+        assert!(clock.tick(6, 4));
+        assert!(!clock.tick(6, CYCLES_PER_FRAME - 1));
+    }
+}
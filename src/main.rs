@@ -1,62 +1,532 @@
+mod block_cache;
+mod avi;
 mod cartridge;
+mod cdl;
 mod cpu;
+mod debugger;
+mod disassembler;
+mod gdb;
 mod gpu;
 mod instruction;
+mod limiter;
 mod mapper;
 mod memory_bus;
+mod model;
+mod movie;
+mod png;
+mod rewind;
+mod scheduler;
+mod sgb;
+mod state;
+mod symbols;
+mod timer;
+mod trace;
+mod viewer;
 
+use avi::AviWriter;
+use block_cache::BlockCache;
 use cartridge::Cartridge;
+use cdl::CodeDataLog;
 use cpu::CPU;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use debugger::Debugger;
+use gdb::GdbStub;
+use limiter::{FrameClock, FrameLimiter, Pause, SPEEDS};
+use model::Model;
+use movie::Movie;
+use rewind::Rewind;
+use symbols::Symbols;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod, Scancode};
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
+use trace::Trace;
+use viewer::Viewers;
 
 const SCREEN_VISUAL: bool = true; //画面描画するか
 
 fn main() {
+    let mut rom_path = String::from("rom//08-misc instrs.gb");
+    let mut camera_image = None;
+    let mut boot_rom_path = None;
+    let mut model = None;
+    let mut color_correction = false;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut debug = false;
+    let mut disassemble = None;
+    let mut trace_path = None;
+    let mut trace_range = None;
+    let mut trace_limit = None;
+    let mut viewer_kinds = Vec::new();
+    let mut gdb_port = None;
+    let mut symbol_paths = Vec::new();
+    let mut cdl_path = None;
+    let mut block_cache = false;
+    let mut speed = 1.0;
+    let mut fast_forward = false;
+    let mut paused = false;
+    let mut screenshot_after = None;
+    let mut screenshot_path = None;
+    let mut screenshot_scale = 1;
+    let mut video_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--camera" => camera_image = args.next(),
+            "--boot" => boot_rom_path = args.next(),
+            "--color-correction" => color_correction = true,
+            "--record" => record_path = args.next(),
+            "--play" => play_path = args.next(),
+            "--debug" => debug = true,
+            "--gdb" => {
+                let port = args.next().unwrap_or_default();
+                gdb_port = Some(port.parse::<u16>().unwrap_or_else(|_| {
+                    eprintln!("invalid port: {}", port);
+                    std::process::exit(1);
+                }));
+            }
+            "--disassemble" => disassemble = args.next(),
+            "--sym" => symbol_paths.extend(args.next()),
+            "--cdl" => cdl_path = args.next(),
+            "--block-cache" => block_cache = true,
+            "--speed" => {
+                let value = args.next().unwrap_or_default();
+                speed = value
+                    .trim_end_matches('x')
+                    .parse()
+                    .ok()
+                    .filter(|speed| SPEEDS.contains(speed))
+                    .unwrap_or_else(|| {
+                        eprintln!("invalid speed: {} (0.25, 0.5, 1, 2, 4, 8)", value);
+                        std::process::exit(1);
+                    });
+            }
+            "--fast-forward" => fast_forward = true,
+            "--paused" => paused = true,
+            "--screenshot-after" => {
+                let frames = args.next().unwrap_or_default();
+                screenshot_after = Some(frames.parse::<u64>().unwrap_or_else(|_| {
+                    eprintln!("invalid frame count: {}", frames);
+                    std::process::exit(1);
+                }));
+            }
+            "--screenshot" => screenshot_path = args.next(),
+            "--record-video" => video_path = args.next(),
+            "--screenshot-scale" => {
+                let scale = args.next().unwrap_or_default();
+                screenshot_scale = scale.parse::<usize>().ok().filter(|&s| s >= 1).unwrap_or_else(|| {
+                    eprintln!("invalid scale: {}", scale);
+                    std::process::exit(1);
+                });
+            }
+            "--trace" => trace_path = args.next(),
+            "--trace-range" => {
+                let range = args.next().unwrap_or_default();
+                trace_range = Some(trace::parse_range(&range).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }));
+            }
+            "--trace-limit" => {
+                let limit = args.next().unwrap_or_default();
+                trace_limit = Some(limit.parse().unwrap_or_else(|_| {
+                    eprintln!("invalid line count: {}", limit);
+                    std::process::exit(1);
+                }));
+            }
+            "--viewer" => {
+                let name = args.next().unwrap_or_default();
+                viewer_kinds.extend(viewer::Kind::from_name(&name).unwrap_or_else(|| {
+                    eprintln!("unknown viewer: {} (tiles, maps, oam, palettes, all)", name);
+                    std::process::exit(1);
+                }));
+            }
+            "--model" => {
+                let name = args.next().unwrap_or_default();
+                model = Some(Model::from_name(&name).unwrap_or_else(|| {
+                    eprintln!("unknown model: {} (DMG0, DMG, MGB, SGB, SGB2, CGB, AGB)", name);
+                    std::process::exit(1);
+                }));
+            }
+            _ => rom_path = arg,
+        }
+    }
+
+    // ROMと同じ名前の.symがあれば一緒に読み込む
+    let default_symbols = std::path::Path::new(&rom_path).with_extension("sym");
+    if symbol_paths.is_empty() && default_symbols.exists() {
+        symbol_paths.push(default_symbols.to_string_lossy().into_owned());
+    }
+    let mut symbols = Symbols::new();
+    for path in &symbol_paths {
+        match symbols.load(path) {
+            Ok(count) => println!("loaded {} symbols from {}", count, path),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    // 逆アセンブルだけして終了する
+    if let Some(range) = disassemble {
+        let rom = std::fs::read(&rom_path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", rom_path, e);
+            std::process::exit(1);
+        });
+        match disassembler::parse_range(&range) {
+            Ok((bank, start, end)) => disassembler::dump(&rom, &symbols, bank, start, end),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut playing = play_path.map(|path| match Movie::load(&path) {
+        Ok(movie) => movie,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
+    if let Some(movie) = &playing {
+        model = Some(movie.model);
+    }
+
+    // ムービーの記録/再生中は実時間や.savに依存しないようにする
+    let deterministic = playing.is_some() || record_path.is_some();
+    let cartridge = if deterministic {
+        Cartridge::new_without_save(&rom_path)
+    } else {
+        Cartridge::new(&rom_path)
+    };
+    let mut cartridge = match cartridge {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = camera_image {
+        if let Err(e) = cartridge.set_camera_image(&path) {
+            eprintln!("{}", e);
+        }
+    }
+    // 指定がなければCGB対応カートリッジはCGBで動かす
+    let model = model.unwrap_or(if cartridge.read_byte(0x0143) & 0x80 != 0 {
+        Model::CGB
+    } else {
+        Model::DMG
+    });
+
+    let boot_rom = boot_rom_path.map(|path| match std::fs::read(&path) {
+        Ok(data) if data.len() == model.boot_rom_size() => data,
+        Ok(data) => {
+            eprintln!("{}: boot rom must be {} bytes for {:?} (got {})", path, model.boot_rom_size(), model, data.len());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    });
+    let mut cpu = CPU::new(cartridge, model, boot_rom);
+    cpu.bus.gpu.color_correction = color_correction;
+    if block_cache {
+        cpu.blocks = Some(BlockCache::new());
+    }
+    if let Some(path) = cdl_path {
+        match CodeDataLog::open(&path, cpu.bus.catridge.rom_len()) {
+            Ok(cdl) => cpu.bus.cdl = Some(cdl),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(movie) = &playing {
+        if movie.checksum.is_some_and(|checksum| checksum != cpu.bus.catridge.checksum()) {
+            eprintln!("movie was recorded with a different rom");
+            std::process::exit(1);
+        }
+        if let Some(state) = &movie.start_state {
+            if let Err(e) = cpu.load_state(state) {
+                eprintln!("movie: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut trace = trace_path.map(|path| match Trace::new(&path, trace_range, trace_limit) {
+        Ok(mut trace) => {
+            trace.symbols = symbols.clone();
+            trace
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
+    let mut recording = record_path
+        .as_ref()
+        .map(|_| Movie::new(Some(cpu.bus.catridge.checksum()), model, None));
+
+    // --screenshot-after N: 画面を出さずにNフレーム進めてから保存して終了する
+    if let Some(frames) = screenshot_after {
+        let start = cpu.bus.gpu.frames;
+        while cpu.bus.gpu.frames - start < frames {
+            let frame = cpu.bus.gpu.frames;
+            while cpu.bus.gpu.frames == frame {
+                cpu.step_block();
+            }
+            if let Some(Some(buttons)) = playing.as_mut().map(|movie| movie.next()) {
+                cpu.bus.set_joypad(buttons);
+            }
+        }
+        let path = screenshot_path.unwrap_or_else(|| new_capture_path(&rom_path, &cpu.bus.catridge.title(), "png"));
+        match save_screenshot(&cpu, &path, screenshot_scale) {
+            Ok(_) => println!("saved screenshot {}", path),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // SGBは枠を含めた256x224で表示する
+    let (width, height) = if cpu.bus.sgb.is_some() {
+        (sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT)
+    } else {
+        (160, 144)
+    };
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let scale: f32 = 3.0;
     let window = video_subsystem
         .window(
             "Gameboy Emulator",
-            (20.0 * 8.0 * scale) as u32,
-            (18.0 * 8.0 * scale) as u32,
+            (width as f32 * scale) as u32,
+            (height as f32 * scale) as u32,
         )
         .position_centered()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // 速さはFrameLimiterで決めるのでvsyncは待たない
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
     canvas.set_scale(scale, scale).unwrap();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 160, 144)
+        .create_texture_target(PixelFormatEnum::RGB24, width as u32, height as u32)
         .unwrap();
 
     let mut screen_state = [0 as u8; 160 * 3 * 144];
 
+    let mut viewer_creators = Vec::new();
+    let mut viewers = match Viewers::new(&video_subsystem, &viewer_kinds, cpu.bus.gpu.cgb_mode, &mut viewer_creators) {
+        Ok(viewers) => viewers,
+        Err(e) => {
+            eprintln!("viewer: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     //...
 
-    let cartridge = Cartridge::new("rom//08-misc instrs.gb");
-    let mut cpu = CPU::new(cartridge);
 
+    // 巻き戻し: 2フレームごとに記録し, 64MBを超えたら古いものから捨てる
+    let mut rewind = Rewind::new(2, 64 * 1024 * 1024);
+    let mut rewinding = false;
+
+    let mut clock = FrameClock::new(cpu.bus.gpu.frames);
+    let mut limiter = FrameLimiter::new();
+    limiter.set_speed(speed);
+
+    // --record-videoかVで録画する (エミュレートしたフレームを1回ずつ書く)
+    let mut video = video_path.map(|path| match AviWriter::create(&path, width, height) {
+        Ok(video) => {
+            println!("recording video {}", path);
+            video
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
+
+    // Tabを押している間か, Shift+Tabで切り替えて早送りする (速さの制限なし)
+    let mut fast_forward_held = false;
+    // 止まっている間にNを押すと1フレームだけ進める
+    let mut pause = Pause::new(paused);
+
+    // --debugかF12で端末のデバッガに入る
+    let mut debugger = Debugger::new();
+    debugger.break_requested = debug;
+    debugger.symbols = symbols;
+
+    // --gdbならGDBの接続を待ち, 端末のデバッガの代わりに使う
+    let mut gdb = gdb_port.map(|port| match GdbStub::listen(port) {
+        Ok(gdb) => gdb,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
 
     loop {
         // println!("{}", cpu.bus.gpu.ly);
-        cpu.step();
-        if !SCREEN_VISUAL { continue; }
-        if cpu.bus.gpu.ly == 144 {
+        let mut new_frame = false;
+        if rewinding {
+            if let Some(state) = rewind.pop() {
+                if let Err(e) = cpu.load_state(&state) {
+                    eprintln!("rewind: {}", e);
+                }
+            }
+        } else if pause.running() {
+            if let Some(gdb) = &mut gdb {
+                if gdb.should_break(&cpu) && !gdb.serve(&mut cpu) {
+                    quit(&mut cpu, &recording, &record_path, &mut trace, &mut video);
+                }
+            } else if debugger.should_break(&cpu) && !debugger.repl(&mut cpu) {
+                quit(&mut cpu, &recording, &record_path, &mut trace, &mut video);
+            }
+            if let Some(trace) = &mut trace {
+                trace.log(&cpu);
+            }
+            // 命令ごとに止めたり記録したりしないなら, ブロックキャッシュで1ブロックずつ実行する
+            let cycles = if gdb.is_none() && trace.is_none() && debugger.is_running() {
+                cpu.step_block()
+            } else {
+                cpu.step() as u32
+            };
+            // 倍速モードでもフレームの長さは実時間で数える
+            let cycles = if cpu.bus.double_speed { cycles / 2 } else { cycles };
+            if !SCREEN_VISUAL { continue; }
+            if !clock.tick(cpu.bus.gpu.frames, cycles) { continue; }
+            pause.end_frame();
+            new_frame = true;
+            if rewind.tick() {
+                rewind.push(&cpu.save_state());
+            }
+            // 録画はエミュレートしたフレームだけ (巻き戻しで読み込んだフレームや止まっている間は書かない)
+            if let Some(writer) = &mut video {
+                let frame = match &cpu.bus.sgb {
+                    Some(sgb) => &sgb.frame[..],
+                    None => &cpu.bus.gpu.frame[..],
+                };
+                if let Err(e) = writer.write_frame(frame) {
+                    eprintln!("{}", e);
+                    video = None;
+                }
+            }
+        }
+        {
             let screen_state = cpu.bus.gpu.frame;
 
-            handle_user_input(&mut event_pump);
-            texture.update(None, &screen_state, 160 * scale as usize).unwrap();
+            for action in handle_user_input(&mut event_pump) {
+                match action {
+                    Action::Quit => quit(&mut cpu, &recording, &record_path, &mut trace, &mut video),
+                    Action::Break => debugger.break_requested = true,
+                    // メインのウィンドウを閉じたら終了し, デバッグ用のウィンドウなら表示をやめる
+                    Action::CloseWindow(id) if id == canvas.window().id() => {
+                        quit(&mut cpu, &recording, &record_path, &mut trace, &mut video)
+                    }
+                    Action::CloseWindow(id) => viewers.close(id),
+                    Action::SaveState(slot) => {
+                        let path = state_path(&rom_path, slot);
+                        match std::fs::write(&path, cpu.save_state()) {
+                            Ok(_) => println!("saved state {}", path.display()),
+                            Err(e) => eprintln!("{}: {}", path.display(), e),
+                        }
+                    }
+                    // ムービーとずれるので記録/再生中は状態を戻せない
+                    Action::LoadState(_) | Action::Rewind(true) if deterministic => {
+                        eprintln!("cannot load a state while a movie is active");
+                    }
+                    Action::LoadState(slot) => {
+                        let path = state_path(&rom_path, slot);
+                        match std::fs::read(&path).map_err(|e| e.to_string()).and_then(|data| cpu.load_state(&data)) {
+                            Ok(_) => println!("loaded state {}", path.display()),
+                            Err(e) => eprintln!("{}: {}", path.display(), e),
+                        }
+                    }
+                    Action::Rewind(on) => rewinding = on,
+                    Action::Screenshot(window_scale) => {
+                        let path = new_capture_path(&rom_path, &cpu.bus.catridge.title(), "png");
+                        let scale = if window_scale { scale as usize } else { 1 };
+                        match save_screenshot(&cpu, &path, scale) {
+                            Ok(_) => println!("saved screenshot {}", path),
+                            Err(e) => eprintln!("{}", e),
+                        }
+                    }
+                    Action::FastForward(on) => fast_forward_held = on,
+                    Action::ToggleFastForward => {
+                        fast_forward = !fast_forward;
+                        println!("fast forward {}", if fast_forward { "on" } else { "off" });
+                    }
+                    Action::ChangeSpeed(step) => {
+                        let i = SPEEDS.iter().position(|&s| s == limiter.speed()).unwrap_or(2) as isize;
+                        let speed = SPEEDS[(i + step).clamp(0, SPEEDS.len() as isize - 1) as usize];
+                        limiter.set_speed(speed);
+                        println!("speed {}x", speed);
+                    }
+                    Action::ToggleVideo => match video.take() {
+                        Some(writer) => finish_video(writer),
+                        None => {
+                            let path = new_capture_path(&rom_path, &cpu.bus.catridge.title(), "avi");
+                            match AviWriter::create(&path, width, height) {
+                                Ok(writer) => {
+                                    println!("recording video {}", path);
+                                    video = Some(writer);
+                                }
+                                Err(e) => eprintln!("{}", e),
+                            }
+                        }
+                    },
+                    Action::Pause => {
+                        pause.toggle();
+                        println!("{}", if pause.paused { "paused" } else { "resumed" });
+                    }
+                    Action::FrameAdvance => pause.advance(),
+                }
+            }
+            // 止まっている間は入力を読まずに画面だけ更新する (コマ送りで進めたフレームは表示する)
+            if pause.paused && !new_frame && !rewinding {
+                canvas.copy(&texture, None, None).unwrap();
+                canvas.present();
+                limiter.wait();
+                continue;
+            }
+            // 次のフレームの入力
+            let buttons = match playing.as_mut().map(|movie| movie.next()) {
+                Some(Some(buttons)) => buttons,
+                Some(None) => {
+                    println!("movie finished");
+                    playing = None;
+                    read_joypad(&event_pump)
+                }
+                None => read_joypad(&event_pump),
+            };
+            cpu.bus.set_joypad(buttons);
+            if let Some(movie) = &mut recording {
+                movie.record(buttons);
+            }
+
+            cpu.bus.catridge.autosave();
+            match &cpu.bus.sgb {
+                Some(sgb) => texture.update(None, &sgb.frame, width * 3).unwrap(),
+                None => texture.update(None, &screen_state, 160 * scale as usize).unwrap(),
+            }
             canvas.copy(&texture, None, None).unwrap();
             canvas.present();
-            // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
+            viewers.update(&cpu.bus.gpu);
+            if fast_forward || fast_forward_held {
+                limiter.reset();
+            } else {
+                limiter.wait();
+            }
         }
     }
 
@@ -73,15 +543,226 @@ fn main() {
     // init sdl2
 }
 
-fn handle_user_input(event_pump: &mut EventPump) {
+// 記録中のムービー, コード/データのログと.savを保存して終了する
+fn quit(
+    cpu: &mut CPU,
+    recording: &Option<Movie>,
+    record_path: &Option<String>,
+    trace: &mut Option<Trace>,
+    video: &mut Option<AviWriter>,
+) -> ! {
+    if let Some(trace) = trace {
+        trace.flush();
+    }
+    if let Some(writer) = video.take() {
+        finish_video(writer);
+    }
+    if let (Some(movie), Some(path)) = (recording, record_path) {
+        match movie.save(path) {
+            Ok(_) => println!("saved movie {} ({} frames)", path, movie.inputs.len()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    if let Some(cdl) = &cpu.bus.cdl {
+        match cdl.save() {
+            Ok(_) => print!("{}", cdl.summary()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    cpu.bus.catridge.save();
+    std::process::exit(0);
+}
+
+// 矢印キー, X: A, Z: B, Enter: Start, 右Shift: Select
+fn read_joypad(event_pump: &EventPump) -> u8 {
+    let keyboard = event_pump.keyboard_state();
+    let keys = [
+        Scancode::Right, Scancode::Left, Scancode::Up, Scancode::Down,
+        Scancode::X, Scancode::Z, Scancode::RShift, Scancode::Return,
+    ];
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| keyboard.is_scancode_pressed(**key))
+        .fold(0, |buttons, (i, _)| buttons | 1 << i)
+}
+
+enum Action {
+    Quit,
+    SaveState(usize),
+    LoadState(usize),
+    Rewind(bool),
+    Break,
+    CloseWindow(u32),
+    FastForward(bool),
+    ToggleFastForward,
+    ChangeSpeed(isize),
+    Pause,
+    FrameAdvance,
+    Screenshot(bool), // trueならウィンドウと同じ倍率
+    ToggleVideo,
+}
+
+// セーブステートのファイル名 (ROMと同じ場所に.ss1-.ss10)
+fn state_path(rom_path: &str, slot: usize) -> std::path::PathBuf {
+    std::path::Path::new(rom_path).with_extension(format!("ss{}", slot))
+}
+
+fn finish_video(writer: AviWriter) {
+    let frames = writer.frames;
+    match writer.finish() {
+        Ok(_) => println!("stopped recording video ({} frames)", frames),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// 表示している画面をPNGで保存する (SGBは枠を含める)
+fn save_screenshot(cpu: &CPU, path: &str, scale: usize) -> Result<(), String> {
+    match &cpu.bus.sgb {
+        Some(sgb) => png::save(path, sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT, &sgb.frame, scale),
+        None => cpu.bus.gpu.save_screenshot(path, scale),
+    }
+}
+
+// スクリーンショットと録画のファイル名 (ROMと同じ場所に "タイトル_20240131-235959.png")
+// 同じ秒に撮ったものがあれば番号を付ける
+fn new_capture_path(rom_path: &str, title: &str, extension: &str) -> String {
+    let rom_path = std::path::Path::new(rom_path);
+    let title: String = match title {
+        "" => rom_path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+        title => title.to_string(),
+    };
+    let title: String = title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    let base = format!("{}_{}", title, timestamp());
+    let mut path = rom_path.with_file_name(format!("{}.{}", base, extension));
+    let mut n = 2;
+    while path.exists() {
+        path = rom_path.with_file_name(format!("{}-{}.{}", base, n, extension));
+        n += 1;
+    }
+    path.to_string_lossy().into_owned()
+}
+
+// 現在時刻 (UTC) を "20240131-235959" の形にする
+fn timestamp() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // 1970-01-01からの日数を年月日にする (3月始まりの暦で計算する)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + year_of_era + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, time / 3600, time / 60 % 60, time % 60
+    )
+}
+
+// F1-F10でステートを読み込み, Shift+F1-F10で保存する
+// Backspaceを押している間は巻き戻す, F12でデバッガに入る
+// Tabを押している間は早送り (Shift+Tabで切り替え), -/=で遅く/速くする
+// Pで一時停止, Nで1フレーム進める
+// F11で画面をPNGで保存する (Shift+F11はウィンドウと同じ倍率), Vで録画を始める/止める
+fn handle_user_input(event_pump: &mut EventPump) -> Vec<Action> {
+    let mut actions = Vec::new();
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
+            } => actions.push(Action::Quit),
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            } => actions.push(Action::CloseWindow(window_id)),
+            Event::KeyDown {
+                keycode: Some(Keycode::Backspace),
+                repeat: false,
+                ..
+            } => actions.push(Action::Rewind(true)),
+            Event::KeyUp {
+                keycode: Some(Keycode::Backspace),
+                ..
+            } => actions.push(Action::Rewind(false)),
+            Event::KeyDown {
+                keycode: Some(Keycode::F12),
+                ..
+            } => actions.push(Action::Break),
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                keymod,
+                repeat: false,
+                ..
+            } => actions.push(Action::Screenshot(keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD))),
+            Event::KeyDown {
+                keycode: Some(Keycode::Tab),
+                keymod,
+                repeat: false,
+                ..
+            } => {
+                if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                    actions.push(Action::ToggleFastForward);
+                } else {
+                    actions.push(Action::FastForward(true));
+                }
+            }
+            Event::KeyUp {
+                keycode: Some(Keycode::Tab),
+                ..
+            } => actions.push(Action::FastForward(false)),
+            Event::KeyDown {
+                keycode: Some(Keycode::Minus),
+                ..
+            } => actions.push(Action::ChangeSpeed(-1)),
+            Event::KeyDown {
+                keycode: Some(Keycode::Equals),
+                ..
+            } => actions.push(Action::ChangeSpeed(1)),
+            Event::KeyDown {
+                keycode: Some(Keycode::P),
+                repeat: false,
+                ..
+            } => actions.push(Action::Pause),
+            Event::KeyDown {
+                keycode: Some(Keycode::N),
+                ..
+            } => actions.push(Action::FrameAdvance),
+            Event::KeyDown {
+                keycode: Some(Keycode::V),
+                repeat: false,
+                ..
+            } => actions.push(Action::ToggleVideo),
+            Event::KeyDown {
+                keycode: Some(keycode),
+                keymod,
+                ..
+            } => {
+                let function_keys = [
+                    Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::F5,
+                    Keycode::F6, Keycode::F7, Keycode::F8, Keycode::F9, Keycode::F10,
+                ];
+                if let Some(i) = function_keys.iter().position(|k| *k == keycode) {
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        actions.push(Action::SaveState(i + 1));
+                    } else {
+                        actions.push(Action::LoadState(i + 1));
+                    }
+                }
+            }
             _ => { /* do nothing */ }
         }
     }
+    actions
 }
@@ -1 +1,77 @@
-pub mod mbc1;
\ No newline at end of file
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rom_only;
+pub mod huc1;
+pub mod huc3;
+pub mod mmm01;
+pub mod pocket_camera;
+pub mod tama5;
+
+use crate::state::{StateReader, StateWriter};
+
+pub trait Mapper {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8;
+    fn write_byte(&mut self, raw: &mut [u8], addr: u16, value: u8);
+
+    // 外部RAM (バッテリーバックアップ対象)
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    fn ram_enabled(&self) -> bool;
+
+    // この書き込みがRAMの無効化か (自動保存のきっかけ)
+    fn disables_ram(&self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && value & 0x0F != 0x0A
+    }
+
+    // RAM有効化のレジスタを持たないもの (ROM+RAMなど) はfalse
+    fn has_ram_gate(&self) -> bool {
+        true
+    }
+
+    // addrに見えているROMのバンク番号 (シンボルの解決用)
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        rom_offset(raw, (addr >= 0x4000) as usize, addr) / 0x4000
+    }
+
+    // RTCを持つもの (MBC3, HuC3) は時計に触れる書き込みの前に現在時刻 (UNIX時間, 秒) を受け取る
+    fn set_time(&mut self, _now: u64) {}
+
+    // .savのRAMの後ろに付ける時計データ (MBC3, HuC3)
+    fn save_footer(&mut self, _now: u64) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_footer(&mut self, _footer: &[u8], _now: u64) {}
+
+    // セーブステート用のレジスタ (RAMと時計はCartridgeがまとめて扱う)
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+
+    // Pocket Cameraのセンサー画像 (128x112, 8bitグレースケール)
+    fn set_camera_image(&mut self, _pixels: Vec<u8>) {}
+}
+
+// バンク番号からROM内のオフセットを求める (ROMサイズで折り返す)
+pub fn rom_offset(raw: &[u8], bank: usize, addr: u16) -> usize {
+    let banks = (raw.len() / 0x4000).max(1);
+    (bank % banks) * 0x4000 + (addr as usize & 0x3FFF)
+}
+
+// テスト用: 各バンクの先頭バイトがバンク番号のROM
+#[cfg(test)]
+pub fn banked_rom(banks: usize) -> Vec<u8> {
+    let mut raw = vec![0; banks * 0x4000];
+    for bank in 0..banks {
+        raw[bank * 0x4000] = bank as u8;
+    }
+    raw
+}
+
+pub fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    Some((bank * 0x2000 + (addr as usize & 0x1FFF)) % ram.len())
+}
@@ -0,0 +1,148 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+// HuC1: MBC1に近いが0x0000-0x1FFFでRAMと赤外線ポートを切り替える
+pub struct HuC1 {
+    bank: u8,
+    ram_bank: u8,
+    ir_mode: bool,
+    ir_led: bool,
+    ram: Vec<u8>,
+}
+
+impl HuC1 {
+    pub fn new(ram_size: usize) -> Self {
+        HuC1 {
+            bank: 1,
+            ram_bank: 0,
+            ir_mode: false,
+            ir_led: false,
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mapper for HuC1 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if self.ir_mode {
+                    // 赤外線は未接続: 受光なし
+                    return 0xC0;
+                }
+                match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
+                }
+            }
+            _ => panic!("unsupported HuC1 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ir_mode = value & 0x0F == 0x0E;
+            }
+            0x2000..=0x3FFF => {
+                self.bank = value & 0x3F;
+                if self.bank == 0 {
+                    self.bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x03;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ir_mode {
+                    self.ir_led = value & 0x01 != 0;
+                    return;
+                }
+                if let Some(i) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    self.ram[i] = value;
+                }
+            }
+            _ => panic!("unsupported HuC1 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ir_mode);
+        w.bool(self.ir_led);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ir_mode = r.bool()?;
+        self.ir_led = r.bool()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        !self.ir_mode
+    }
+
+    // RAMの有効化はなく, 赤外線に切り替えたときだけRAMが見えなくなる
+    fn disables_ram(&self, addr: u16, value: u8) -> bool {
+        addr <= 0x1FFF && value & 0x0F == 0x0E
+    }
+
+    fn has_ram_gate(&self) -> bool {
+        false
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::banked_rom;
+
+    #[test]
+    fn test_bank_switch() {
+        let mut raw = banked_rom(64);
+        let mut huc1 = HuC1::new(0x8000);
+        assert_eq!(huc1.read_byte(&raw, 0x4000), 1);
+        huc1.write_byte(&mut raw, 0x2000, 0x25);
+        assert_eq!(huc1.read_byte(&raw, 0x4000), 0x25);
+        assert_eq!(huc1.read_byte(&raw, 0x0000), 0);
+        // バンク0は1になる
+        huc1.write_byte(&mut raw, 0x2000, 0x00);
+        assert_eq!(huc1.read_byte(&raw, 0x4000), 1);
+
+        // RAMバンクは有効化なしで読み書きできる
+        huc1.write_byte(&mut raw, 0x4000, 0x02);
+        huc1.write_byte(&mut raw, 0xA000, 0x12);
+        huc1.write_byte(&mut raw, 0x4000, 0x03);
+        huc1.write_byte(&mut raw, 0xA000, 0x13);
+        huc1.write_byte(&mut raw, 0x4000, 0x02);
+        assert_eq!(huc1.read_byte(&raw, 0xA000), 0x12);
+        assert_eq!(huc1.ram()[0x6000], 0x13);
+
+        // 赤外線モードの間はRAMが見えない
+        huc1.write_byte(&mut raw, 0x0000, 0x0E);
+        assert_eq!(huc1.read_byte(&raw, 0xA000), 0xC0);
+        huc1.write_byte(&mut raw, 0xA000, 0x01);
+        assert_eq!(huc1.ram()[0x4000], 0x12);
+        huc1.write_byte(&mut raw, 0x0000, 0x00);
+        assert_eq!(huc1.read_byte(&raw, 0xA000), 0x12);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+pub const HUC3_FOOTER_SIZE: usize = 12;
+
+// HuC3: 0x0000-0x1FFFに書いた値でA000-BFFFの機能が変わる
+// 0x0A: RAM, 0x0B: コマンド書き込み, 0x0C: コマンド結果, 0x0D: セマフォ, 0x0E: 赤外線
+pub struct HuC3 {
+    bank: u8,
+    ram_bank: u8,
+    mode: u8,
+    ram: Vec<u8>,
+    minutes: u16, // 0-1439
+    days: u16,
+    timestamp: u64,
+    now: u64, // 最後に受け取った現在時刻
+    memory: [u8; 0x100], // RTCチップ内の4bitメモリ
+    address: u8,
+    result: u8,
+}
+
+impl HuC3 {
+    pub fn new(ram_size: usize, now: u64) -> Self {
+        HuC3 {
+            bank: 1,
+            ram_bank: 0,
+            mode: 0,
+            ram: vec![0; ram_size],
+            minutes: 0,
+            days: 0,
+            timestamp: now,
+            now,
+            memory: [0; 0x100],
+            address: 0,
+            result: 0,
+        }
+    }
+
+    fn sync(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.timestamp) / 60;
+        self.timestamp += elapsed * 60;
+        let total = self.minutes as u64 + elapsed;
+        self.minutes = (total % 1440) as u16;
+        self.days = ((self.days as u64 + total / 1440) & 0xFFF) as u16;
+    }
+
+    fn command(&mut self, value: u8) {
+        let argument = value & 0x0F;
+        match value >> 4 {
+            // 読み出し (アドレスを進める)
+            0x1 => {
+                self.result = self.memory[self.address as usize];
+                self.address = self.address.wrapping_add(1);
+            }
+            // 書き込み (0x3はアドレスを進める)
+            0x2 | 0x3 => {
+                self.memory[self.address as usize] = argument;
+                if value >> 4 == 0x3 {
+                    self.address = self.address.wrapping_add(1);
+                }
+            }
+            0x4 => self.address = (self.address & 0xF0) | argument,
+            0x5 => self.address = (self.address & 0x0F) | argument << 4,
+            0x6 => match argument {
+                // 現在時刻をメモリ0x00-0x05へコピー
+                0x0 => {
+                    self.sync(self.now);
+                    for i in 0..3 {
+                        self.memory[i] = ((self.minutes >> (i * 4)) & 0x0F) as u8;
+                        self.memory[i + 3] = ((self.days >> (i * 4)) & 0x0F) as u8;
+                    }
+                }
+                // メモリ0x00-0x05から時刻を設定
+                0x1 => {
+                    self.sync(self.now);
+                    let mut minutes = 0;
+                    let mut days = 0;
+                    for i in 0..3 {
+                        minutes |= (self.memory[i] as u16) << (i * 4);
+                        days |= (self.memory[i + 3] as u16) << (i * 4);
+                    }
+                    self.minutes = minutes % 1440;
+                    self.days = days;
+                }
+                // 状態確認: 常に準備完了
+                0x2 => self.result = 0x01,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for HuC3 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => match self.mode {
+                0x00 | 0x0A => match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
+                },
+                0x0C => 0x80 | self.result,
+                0x0D => 0x01,
+                0x0E => 0xC0,
+                _ => 0xFF,
+            },
+            _ => panic!("unsupported HuC3 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.mode = value & 0x0F;
+            }
+            0x2000..=0x3FFF => {
+                self.bank = value & 0x7F;
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x03;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => match self.mode {
+                0x0A => {
+                    if let Some(i) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                        self.ram[i] = value;
+                    }
+                }
+                0x0B => self.command(value),
+                _ => {}
+            },
+            _ => panic!("unsupported HuC3 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.u8(self.ram_bank);
+        w.u8(self.mode);
+        w.bytes(&self.memory);
+        w.u8(self.address);
+        w.u8(self.result);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.mode = r.u8()?;
+        r.bytes_into(&mut self.memory)?;
+        self.address = r.u8()?;
+        self.result = r.u8()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.mode == 0x0A
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    // タイムスタンプ(u64), 分(u16), 日(u16)
+    fn save_footer(&mut self, now: u64) -> Vec<u8> {
+        self.sync(now);
+        let mut footer = Vec::with_capacity(HUC3_FOOTER_SIZE);
+        footer.extend_from_slice(&self.timestamp.to_le_bytes());
+        footer.extend_from_slice(&self.minutes.to_le_bytes());
+        footer.extend_from_slice(&self.days.to_le_bytes());
+        footer
+    }
+
+    fn load_footer(&mut self, footer: &[u8], now: u64) {
+        if footer.len() < HUC3_FOOTER_SIZE {
+            return;
+        }
+        self.timestamp = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        self.minutes = u16::from_le_bytes(footer[8..10].try_into().unwrap()) % 1440;
+        self.days = u16::from_le_bytes(footer[10..12].try_into().unwrap()) & 0xFFF;
+        self.sync(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::banked_rom;
+
+    #[test]
+    fn test_bank_switch() {
+        let mut raw = banked_rom(128);
+        let mut huc3 = HuC3::new(0x8000, 0);
+        assert_eq!(huc3.read_byte(&raw, 0x4000), 1);
+        huc3.write_byte(&mut raw, 0x2000, 0xFF);
+        assert_eq!(huc3.read_byte(&raw, 0x4000), 0x7F);
+        // HuC3はバンク0もそのまま選べる
+        huc3.write_byte(&mut raw, 0x2000, 0x00);
+        assert_eq!(huc3.read_byte(&raw, 0x4000), 0);
+
+        // RAMへの書き込みはモード0x0Aのときだけ
+        huc3.write_byte(&mut raw, 0x4000, 0x01);
+        huc3.write_byte(&mut raw, 0xA010, 0x55);
+        assert_eq!(huc3.ram()[0x2010], 0x00);
+        huc3.write_byte(&mut raw, 0x0000, 0x0A);
+        huc3.write_byte(&mut raw, 0xA010, 0x55);
+        assert_eq!(huc3.read_byte(&raw, 0xA010), 0x55);
+        huc3.write_byte(&mut raw, 0x4000, 0x00);
+        assert_eq!(huc3.read_byte(&raw, 0xA010), 0x00);
+
+        // 0x0Dはセマフォ (常に準備完了), 0x0Eは赤外線
+        huc3.write_byte(&mut raw, 0x0000, 0x0D);
+        assert_eq!(huc3.read_byte(&raw, 0xA000), 0x01);
+        huc3.write_byte(&mut raw, 0x0000, 0x0E);
+        assert_eq!(huc3.read_byte(&raw, 0xA000), 0xC0);
+    }
+
+    #[test]
+    fn test_rtc_memory() {
+        let mut raw = banked_rom(2);
+        let mut huc3 = HuC3::new(0x2000, 0);
+        // アドレス0x12に0x7を書いて (0x3: 書き込んで次へ), 0x12から読み直す
+        huc3.write_byte(&mut raw, 0x0000, 0x0B);
+        for command in [0x42, 0x51, 0x37, 0x42, 0x10] {
+            huc3.write_byte(&mut raw, 0xA000, command);
+        }
+        huc3.write_byte(&mut raw, 0x0000, 0x0C);
+        assert_eq!(huc3.read_byte(&raw, 0xA000), 0x87);
+    }
+}
@@ -1,46 +1,108 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
 pub struct MBC1 {
     bank: u8,
+    bank2: u8,
+    mode: bool,
+    ram_enabled: bool,
+    ram: Vec<u8>,
 }
 
 impl MBC1 {
-    pub fn new() -> Self {
+    pub fn new(ram_size: usize) -> Self {
         MBC1 {
             bank: 1,
+            bank2: 0,
+            mode: false,
+            ram_enabled: false,
+            ram: vec![0; ram_size],
         }
     }
+}
 
-    pub fn read_byte(&self, raw: &Vec<u8>, addr: u16) -> u8{
+impl Mapper for MBC1 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8{
         match addr {
-            0x0000..=0x3FFF => {
-                raw[addr as usize]
-            },
-            0x4000..=0x7FFF => {
-                let mut bank = self.bank & 0x1F;
-                if bank == 0{
-                    bank = 1;
+            0x0000..=0x7FFF => raw[rom_offset(raw, self.mapped_bank(raw, addr), addr)],
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                let bank = if self.mode { self.bank2 as usize } else { 0 };
+                match ram_offset(&self.ram, bank, addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
                 }
-                let addr = addr as usize + (bank as usize - 1) * 0x4000;
-                raw[addr as usize]
             }
             _ => panic!("unsupported MBC1 memory.")
         }
     }
 
-    pub fn write_byte(&mut self, raw: &mut Vec<u8>, addr: u16, value: u8) {
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
         match addr {
             0x0000..=0x1FFF => {
-
+                self.ram_enabled = value & 0x0F == 0x0A;
             },
             0x2000..=0x3FFF => {
-                self.bank = value;
+                self.bank = value & 0x1F;
             },
             0x4000..=0x5FFF => {
-
+                self.bank2 = value & 0x03;
             },
             0x6000..=0x7FFF => {
-
+                self.mode = value & 0x01 != 0;
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                let bank = if self.mode { self.bank2 as usize } else { 0 };
+                if let Some(i) = ram_offset(&self.ram, bank, addr) {
+                    self.ram[i] = value;
+                }
             }
             _ => panic!("unsupported MBC1 memory."),
         }
     }
-}
\ No newline at end of file
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.u8(self.bank2);
+        w.bool(self.mode);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.bank2 = r.u8()?;
+        self.mode = r.bool()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            if self.mode { (self.bank2 as usize) << 5 } else { 0 }
+        } else {
+            let mut bank = self.bank & 0x1F;
+            if bank == 0{
+                bank = 1;
+            }
+            (self.bank2 as usize) << 5 | bank as usize
+        };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
@@ -0,0 +1,92 @@
+use crate::mapper::{rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+// MBC2は512x4bitの内蔵RAMを持つ
+pub struct MBC2 {
+    bank: u8,
+    ram_enabled: bool,
+    ram: Vec<u8>,
+}
+
+impl MBC2 {
+    pub fn new() -> Self {
+        MBC2 {
+            bank: 1,
+            ram_enabled: false,
+            ram: vec![0; 0x200],
+        }
+    }
+}
+
+impl Mapper for MBC2 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                // 上位4bitは不定 (1として読める)
+                self.ram[addr as usize & 0x1FF] | 0xF0
+            }
+            _ => panic!("unsupported MBC2 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x3FFF => {
+                // アドレスのbit8でRAM有効化とバンク切り替えを区別する
+                if addr & 0x0100 == 0 {
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else {
+                    self.bank = value & 0x0F;
+                    if self.bank == 0 {
+                        self.bank = 1;
+                    }
+                }
+            }
+            0x4000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    self.ram[addr as usize & 0x1FF] = value & 0x0F;
+                }
+            }
+            _ => panic!("unsupported MBC2 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    // RAM有効化はA8=0のときだけ
+    fn disables_ram(&self, addr: u16, value: u8) -> bool {
+        addr <= 0x3FFF && addr & 0x0100 == 0 && value & 0x0F != 0x0A
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
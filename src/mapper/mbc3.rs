@@ -0,0 +1,253 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+// MBC3のリアルタイムクロック
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days_low: u8,
+    days_high: u8, // bit0: 日数の9bit目, bit6: 停止, bit7: 日数キャリー
+    latched: [u8; 5],
+    latch_flag: bool,
+    timestamp: u64, // 最後に時刻を進めたUNIX時間
+}
+
+impl Rtc {
+    pub fn new(now: u64) -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days_low: 0,
+            days_high: 0,
+            latched: [0; 5],
+            latch_flag: false,
+            timestamp: now,
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [self.seconds, self.minutes, self.hours, self.days_low, self.days_high]
+    }
+
+    // 前回からの経過時間だけ時計を進める
+    pub fn sync(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.timestamp);
+        self.timestamp = now;
+        if self.days_high & 0x40 != 0 {
+            return;
+        }
+
+        let days = (self.days_high as u64 & 0x01) << 8 | self.days_low as u64;
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + days * 86400
+            + elapsed;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.days_high |= 0x80;
+        }
+        self.days_low = (days & 0xFF) as u8;
+        self.days_high = (self.days_high & 0xFE) | ((days >> 8) & 0x01) as u8;
+    }
+
+    fn latch(&mut self, value: u8, now: u64) {
+        if self.latch_flag && value == 0x01 {
+            self.sync(now);
+            self.latched = self.registers();
+        }
+        self.latch_flag = value == 0x00;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    fn write(&mut self, register: u8, value: u8, now: u64) {
+        self.sync(now);
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days_low = value,
+            0x0C => self.days_high = value & 0xC1,
+            _ => {}
+        }
+    }
+
+    // VBA-M/BGB互換のフッター (現在値5個, ラッチ値5個, タイムスタンプ)
+    pub fn save_footer(&mut self, now: u64) -> Vec<u8> {
+        self.sync(now);
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for value in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*value as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&self.timestamp.to_le_bytes());
+        footer
+    }
+
+    // 44バイト (32bitタイムスタンプ) 版にも対応する
+    pub fn load_footer(&mut self, footer: &[u8], now: u64) {
+        if footer.len() < 44 {
+            return;
+        }
+        let word = |i: usize| footer[i * 4];
+        self.seconds = word(0);
+        self.minutes = word(1);
+        self.hours = word(2);
+        self.days_low = word(3);
+        self.days_high = word(4);
+        for i in 0..5 {
+            self.latched[i] = word(5 + i);
+        }
+        self.timestamp = if footer.len() >= 48 {
+            u64::from_le_bytes(footer[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64
+        };
+        self.sync(now);
+    }
+}
+
+pub struct MBC3 {
+    bank: u8,
+    ram_bank: u8, // 0x08-0x0CはRTCレジスタ
+    ram_enabled: bool,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    now: u64, // 最後に受け取った現在時刻
+}
+
+impl MBC3 {
+    pub fn new(ram_size: usize, has_rtc: bool, now: u64) -> Self {
+        MBC3 {
+            bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            ram: vec![0; ram_size],
+            rtc: if has_rtc { Some(Rtc::new(now)) } else { None },
+            now,
+        }
+    }
+}
+
+impl Mapper for MBC3 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                match (self.ram_bank, &self.rtc) {
+                    (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+                    (0x00..=0x07, _) => match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                        Some(i) => self.ram[i],
+                        None => 0xFF,
+                    },
+                    _ => 0xFF,
+                }
+            }
+            _ => panic!("unsupported MBC3 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                self.bank = value & 0x7F;
+                if self.bank == 0 {
+                    self.bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x0F;
+            }
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.latch(value, self.now);
+                }
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                match (self.ram_bank, &mut self.rtc) {
+                    (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value, self.now),
+                    (0x00..=0x07, _) => {
+                        if let Some(i) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                            self.ram[i] = value;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => panic!("unsupported MBC3 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        if let Some(rtc) = &self.rtc {
+            w.bool(rtc.latch_flag);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.latch_flag = r.bool()?;
+        }
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    fn save_footer(&mut self, now: u64) -> Vec<u8> {
+        match &mut self.rtc {
+            Some(rtc) => rtc.save_footer(now),
+            None => Vec::new(),
+        }
+    }
+
+    fn load_footer(&mut self, footer: &[u8], now: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_footer(&footer[..footer.len().min(RTC_FOOTER_SIZE)], now);
+        }
+    }
+}
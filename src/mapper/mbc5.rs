@@ -0,0 +1,97 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+pub struct MBC5 {
+    bank: u16,
+    ram_bank: u8,
+    ram_enabled: bool,
+    ram: Vec<u8>,
+}
+
+impl MBC5 {
+    pub fn new(ram_size: usize) -> Self {
+        MBC5 {
+            bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mapper for MBC5 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            // MBC5はバンク0も選択できる
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
+                }
+            }
+            _ => panic!("unsupported MBC5 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x2FFF => {
+                self.bank = (self.bank & 0x100) | value as u16;
+            }
+            0x3000..=0x3FFF => {
+                self.bank = (self.bank & 0xFF) | ((value as u16 & 0x01) << 8);
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x0F;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                if let Some(i) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    self.ram[i] = value;
+                }
+            }
+            _ => panic!("unsupported MBC5 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u16()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
@@ -0,0 +1,216 @@
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+// MMM01: 複数タイトルを1本に収めたカートリッジ
+// 起動直後はROM末尾の32KB (メニュー) が見えていて、0x0000-0x1FFFのbit6でマッピングを固定する
+pub struct MMM01 {
+    locked: bool,
+    ram_enabled: bool,
+    rom_low: u8,  // bit0-4
+    rom_mid: u8,  // bit5-6
+    rom_high: u8, // bit7-8
+    rom_mask: u8, // 固定するbank番号のbit (bit1-4)
+    ram_bank: u8,
+    ram_high: u8,
+    mode: bool,
+    ram: Vec<u8>,
+}
+
+impl MMM01 {
+    pub fn new(ram_size: usize) -> Self {
+        MMM01 {
+            locked: false,
+            ram_enabled: false,
+            rom_low: 0,
+            rom_mid: 0,
+            rom_high: 0,
+            rom_mask: 0,
+            ram_bank: 0,
+            ram_high: 0,
+            mode: false,
+            ram: vec![0; ram_size],
+        }
+    }
+
+    fn rom_base(&self) -> usize {
+        (self.rom_high as usize) << 7 | (self.rom_mid as usize) << 5
+    }
+
+    fn rom_bank(&self) -> usize {
+        let mut low = self.rom_low & 0x1F;
+        if low & !(self.rom_mask << 1) & 0x1F == 0 {
+            low |= 0x01;
+        }
+        self.rom_base() | low as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        (self.ram_high as usize) << 2 | self.ram_bank as usize
+    }
+}
+
+impl Mapper for MMM01 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => raw[rom_offset(raw, self.mapped_bank(raw, addr), addr)],
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+                match ram_offset(&self.ram, self.ram_bank(), addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
+                }
+            }
+            _ => panic!("unsupported MMM01 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+                if !self.locked {
+                    self.locked = value & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3FFF => {
+                // マスクされたbitは固定後は変更できない
+                let fixed = if self.locked { (self.rom_mask << 1) & 0x1F } else { 0 };
+                self.rom_low = (self.rom_low & fixed) | (value & 0x1F & !fixed);
+                if !self.locked {
+                    self.rom_mid = (value >> 5) & 0x03;
+                }
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x03;
+                if !self.locked {
+                    self.ram_high = (value >> 2) & 0x03;
+                    self.rom_high = (value >> 4) & 0x03;
+                }
+            }
+            0x6000..=0x7FFF => {
+                self.mode = value & 0x01 != 0;
+                if !self.locked {
+                    self.rom_mask = (value >> 2) & 0x0F;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+                let bank = self.ram_bank();
+                if let Some(i) = ram_offset(&self.ram, bank, addr) {
+                    self.ram[i] = value;
+                }
+            }
+            _ => panic!("unsupported MMM01 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.locked);
+        w.bool(self.ram_enabled);
+        w.u8(self.rom_low);
+        w.u8(self.rom_mid);
+        w.u8(self.rom_high);
+        w.u8(self.rom_mask);
+        w.u8(self.ram_bank);
+        w.u8(self.ram_high);
+        w.bool(self.mode);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.locked = r.bool()?;
+        self.ram_enabled = r.bool()?;
+        self.rom_low = r.u8()?;
+        self.rom_mid = r.u8()?;
+        self.rom_high = r.u8()?;
+        self.rom_mask = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_high = r.u8()?;
+        self.mode = r.bool()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let banks = (raw.len() / 0x4000).max(2);
+        let bank = if addr < 0x4000 {
+            if !self.locked {
+                banks - 2
+            } else if self.mode {
+                self.rom_base() | (self.rom_low & (self.rom_mask << 1) & 0x1F) as usize
+            } else {
+                self.rom_base()
+            }
+        } else if self.locked {
+            self.rom_bank()
+        } else {
+            banks - 1
+        };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::banked_rom;
+
+    #[test]
+    fn test_menu_and_lock() {
+        let mut raw = banked_rom(64);
+        let mut mmm01 = MMM01::new(0x8000);
+        // 起動直後は末尾の32KB (メニュー)
+        assert_eq!(mmm01.read_byte(&raw, 0x0000), 62);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 63);
+
+        // ゲームの先頭をバンク32に置いて (rom_mid=1), バンク3を選んでから固定する
+        mmm01.write_byte(&mut raw, 0x2000, 0x23);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 63);
+        mmm01.write_byte(&mut raw, 0x0000, 0x40);
+        assert_eq!(mmm01.read_byte(&raw, 0x0000), 32);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 35);
+
+        // 固定後はゲームの中のバンクだけが切り替わる
+        mmm01.write_byte(&mut raw, 0x2000, 0x45);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 37);
+        mmm01.write_byte(&mut raw, 0x4000, 0x30);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 37);
+        // 固定は解除できない
+        mmm01.write_byte(&mut raw, 0x0000, 0x00);
+        assert_eq!(mmm01.read_byte(&raw, 0x0000), 32);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 37);
+        // 下位bitが0のときは1になる
+        mmm01.write_byte(&mut raw, 0x2000, 0x00);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 33);
+    }
+
+    #[test]
+    fn test_rom_mask() {
+        let mut raw = banked_rom(64);
+        let mut mmm01 = MMM01::new(0);
+        // bank番号のbit1-2を固定する
+        mmm01.write_byte(&mut raw, 0x6000, 0x0C);
+        mmm01.write_byte(&mut raw, 0x2000, 0x04);
+        mmm01.write_byte(&mut raw, 0x0000, 0x40);
+        // 固定されていないbitが全て0なら1になる
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 5);
+        mmm01.write_byte(&mut raw, 0x2000, 0x1A);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 0x1C);
+        mmm01.write_byte(&mut raw, 0x2000, 0x18);
+        assert_eq!(mmm01.read_byte(&raw, 0x4000), 0x1C);
+    }
+}
@@ -0,0 +1,312 @@
+use std::fs;
+
+use crate::mapper::{ram_offset, rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+// 撮影結果を書き込むRAM上の位置 (バンク0)
+const IMAGE_ADDRESS: usize = 0x0100;
+
+// Pocket Camera (0xFC)
+// RAMバンクのbit4を立てるとA000-A07Fがカメラのレジスタになる
+pub struct PocketCamera {
+    bank: u8,
+    ram_bank: u8,
+    ram_enabled: bool,
+    ram: Vec<u8>,
+    registers: [u8; 0x36],
+    sensor: Vec<u8>,
+}
+
+impl PocketCamera {
+    pub fn new(ram_size: usize) -> Self {
+        PocketCamera {
+            bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            ram: vec![0; ram_size.max(0x20000)],
+            registers: [0; 0x36],
+            sensor: vec![0x80; SENSOR_WIDTH * SENSOR_HEIGHT],
+        }
+    }
+
+    // センサー画像をしきい値行列でディザリングして2bppタイルとしてRAMに書き込む
+    fn capture(&mut self) {
+        let exposure = (self.registers[2] as u32) << 8 | self.registers[3] as u32;
+        let invert = self.registers[1] & 0x80 != 0;
+
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let mut value = (self.sensor[y * SENSOR_WIDTH + x] as u32 * exposure / 0x1000).min(255) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+                let base = 6 + ((y % 4) * 4 + (x % 4)) * 3;
+                let color = if value < self.registers[base] {
+                    3
+                } else if value < self.registers[base + 1] {
+                    2
+                } else if value < self.registers[base + 2] {
+                    1
+                } else {
+                    0
+                };
+
+                let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+                let offset = IMAGE_ADDRESS + tile * 16 + (y % 8) * 2;
+                let mask = 0x80 >> (x % 8);
+                self.ram[offset] = (self.ram[offset] & !mask) | if color & 0x01 != 0 { mask } else { 0 };
+                self.ram[offset + 1] = (self.ram[offset + 1] & !mask) | if color & 0x02 != 0 { mask } else { 0 };
+            }
+        }
+    }
+}
+
+// PGM (P5/P2) または128x112のベタデータを読み込み、センサーサイズに合わせる
+pub fn load_image(path: &str) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    if data.len() == SENSOR_WIDTH * SENSOR_HEIGHT {
+        return Ok(data);
+    }
+    if data.len() < 2 || data[0] != b'P' || (data[1] != b'5' && data[1] != b'2') {
+        return Err(format!("{}: unsupported image format (PGM only)", path));
+    }
+
+    // ヘッダ: 幅, 高さ, 最大値 (コメント行は読み飛ばす)
+    let mut fields = Vec::new();
+    let mut i = 2;
+    while fields.len() < 3 && i < data.len() {
+        match data[i] {
+            b'#' => {
+                while i < data.len() && data[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'0'..=b'9' => {
+                let start = i;
+                while i < data.len() && data[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let text = std::str::from_utf8(&data[start..i]).unwrap();
+                fields.push(text.parse::<usize>().unwrap_or(0));
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if fields.len() < 3 || fields[0] == 0 || fields[1] == 0 || fields[2] == 0 {
+        return Err(format!("{}: broken PGM header", path));
+    }
+    let (width, height, max) = (fields[0], fields[1], fields[2]);
+
+    let pixels: Vec<usize> = if data[1] == b'5' {
+        let body = &data[(i + 1).min(data.len())..];
+        if max > 255 {
+            body.chunks(2).map(|c| (c[0] as usize) << 8 | *c.get(1).unwrap_or(&0) as usize).collect()
+        } else {
+            body.iter().map(|b| *b as usize).collect()
+        }
+    } else {
+        std::str::from_utf8(&data[i..])
+            .unwrap_or("")
+            .split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .collect()
+    };
+    if pixels.len() < width * height {
+        return Err(format!("{}: image data too short", path));
+    }
+
+    let mut sensor = vec![0; SENSOR_WIDTH * SENSOR_HEIGHT];
+    for y in 0..SENSOR_HEIGHT {
+        for x in 0..SENSOR_WIDTH {
+            let sx = x * width / SENSOR_WIDTH;
+            let sy = y * height / SENSOR_HEIGHT;
+            sensor[y * SENSOR_WIDTH + x] = (pixels[sy * width + sx] * 255 / max).min(255) as u8;
+        }
+    }
+    Ok(sensor)
+}
+
+impl Mapper for PocketCamera {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if self.ram_bank & 0x10 != 0 {
+                    // 読めるのはレジスタ0のみ
+                    return if addr & 0x7F == 0 { self.registers[0] } else { 0x00 };
+                }
+                match ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    Some(i) => self.ram[i],
+                    None => 0xFF,
+                }
+            }
+            _ => panic!("unsupported Pocket Camera memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                self.bank = value & 0x3F;
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x1F;
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ram_bank & 0x10 != 0 {
+                    let register = (addr & 0x7F) as usize;
+                    if register < self.registers.len() {
+                        self.registers[register] = value;
+                    }
+                    // 撮影は即座に完了させる
+                    if register == 0 && value & 0x01 != 0 {
+                        self.capture();
+                        self.registers[0] &= 0xFE;
+                    }
+                    return;
+                }
+                if !self.ram_enabled {
+                    return;
+                }
+                if let Some(i) = ram_offset(&self.ram, self.ram_bank as usize, addr) {
+                    self.ram[i] = value;
+                }
+            }
+            _ => panic!("unsupported Pocket Camera memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bytes(&self.registers);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        r.bytes_into(&mut self.registers)?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+
+    fn set_camera_image(&mut self, pixels: Vec<u8>) {
+        if pixels.len() == self.sensor.len() {
+            self.sensor = pixels;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_temp(name: &str, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_load_image() {
+        // P5: 2倍の大きさ, コメント付き
+        let mut data = b"P5\n# comment\n256 224\n255\n".to_vec();
+        for y in 0..224 {
+            for x in 0..256 {
+                data.push((x / 2 + y / 2) as u8);
+            }
+        }
+        let path = write_temp("camera.pgm", &data);
+        let sensor = load_image(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(sensor.len(), SENSOR_WIDTH * SENSOR_HEIGHT);
+        assert_eq!(sensor[0], 0);
+        assert_eq!(sensor[3 * SENSOR_WIDTH + 5], 8);
+        assert_eq!(sensor[SENSOR_WIDTH * SENSOR_HEIGHT - 1], 127 + 111);
+
+        // P2: 最大値15を255に広げる
+        let path = write_temp("camera_ascii.pgm", b"P2 2 1 15\n0 15\n");
+        let sensor = load_image(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(sensor[0], 0);
+        assert_eq!(sensor[SENSOR_WIDTH / 2], 255);
+
+        let path = write_temp("camera.png", b"\x89PNG\r\n");
+        assert!(load_image(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        let path = write_temp("camera_short.pgm", b"P5 4 4 255\n\x00\x01");
+        assert!(load_image(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut raw = vec![0; 0x8000];
+        let mut camera = PocketCamera::new(0x20000);
+        // 32列ごとに明るさを変える
+        let sensor = (0..SENSOR_WIDTH * SENSOR_HEIGHT)
+            .map(|i| [0x00, 0x60, 0xA0, 0xFF][i % SENSOR_WIDTH / 32])
+            .collect();
+        camera.set_camera_image(sensor);
+
+        camera.write_byte(&mut raw, 0x4000, 0x10);
+        // 露光0x1000 (等倍), 4x4のしきい値は全て (0x40, 0x80, 0xC0)
+        camera.write_byte(&mut raw, 0xA002, 0x10);
+        camera.write_byte(&mut raw, 0xA003, 0x00);
+        for i in 0..16 {
+            for (j, threshold) in [0x40, 0x80, 0xC0].into_iter().enumerate() {
+                camera.write_byte(&mut raw, 0xA006 + i * 3 + j as u16, threshold);
+            }
+        }
+        camera.write_byte(&mut raw, 0xA000, 0x01);
+        // 撮影はすぐ終わる
+        assert_eq!(camera.read_byte(&raw, 0xA000), 0x00);
+
+        // タイル0, 4, 8, 12の先頭の行: 暗いほど色番号が大きい
+        let row = |camera: &PocketCamera, tile: usize| {
+            let offset = IMAGE_ADDRESS + tile * 16;
+            (camera.ram()[offset], camera.ram()[offset + 1])
+        };
+        assert_eq!(row(&camera, 0), (0xFF, 0xFF));
+        assert_eq!(row(&camera, 4), (0x00, 0xFF));
+        assert_eq!(row(&camera, 8), (0xFF, 0x00));
+        assert_eq!(row(&camera, 12), (0x00, 0x00));
+        // 最後のタイルの最後の行
+        let last = IMAGE_ADDRESS + (SENSOR_WIDTH / 8) * (SENSOR_HEIGHT / 8) * 16 - 2;
+        assert_eq!(&camera.ram()[last..last + 2], &[0x00, 0x00]);
+
+        // 反転
+        camera.write_byte(&mut raw, 0xA001, 0x80);
+        camera.write_byte(&mut raw, 0xA000, 0x01);
+        assert_eq!(row(&camera, 0), (0x00, 0x00));
+        assert_eq!(row(&camera, 12), (0xFF, 0xFF));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::mapper::{ram_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+// MBCなし (0x00, 0x08, 0x09)
+pub struct RomOnly {
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(ram_size: usize) -> Self {
+        RomOnly {
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => raw.get(addr as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF => match ram_offset(&self.ram, 0, addr) {
+                Some(i) => self.ram[i],
+                None => 0xFF,
+            },
+            _ => panic!("unsupported ROM only memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if let Some(i) = ram_offset(&self.ram, 0, addr) {
+                    self.ram[i] = value;
+                }
+            }
+            _ => panic!("unsupported ROM only memory."),
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+
+    fn disables_ram(&self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn has_ram_gate(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,224 @@
+use crate::mapper::{rom_offset, Mapper};
+use crate::state::{StateReader, StateWriter};
+
+// Bandai TAMA5
+// A001にレジスタ番号、A000に4bitの値を書いてやり取りする
+// 内部RAM (32バイト) は.savに保存する. RTCは未対応 (時計のコマンドは無視する)
+pub struct TAMA5 {
+    register: u8,
+    bank: u8,
+    data: u8,       // 書き込む値 (レジスタ4, 5)
+    address: u8,    // 内部RAMのアドレス (レジスタ6のbit0 + レジスタ7)
+    command: u8,    // レジスタ6のbit1-3
+    result: u8,     // レジスタ0x0C, 0x0Dで読める値
+    ram: Vec<u8>,   // 32バイトの内部RAM
+}
+
+impl TAMA5 {
+    pub fn new() -> Self {
+        TAMA5 {
+            register: 0,
+            bank: 0,
+            data: 0,
+            address: 0,
+            command: 0,
+            result: 0,
+            ram: vec![0; 0x20],
+        }
+    }
+
+    fn write_register(&mut self, value: u8) {
+        let value = value & 0x0F;
+        match self.register {
+            0x00 => self.bank = (self.bank & 0x10) | value,
+            0x01 => self.bank = (self.bank & 0x0F) | (value & 0x01) << 4,
+            0x04 => self.data = (self.data & 0xF0) | value,
+            0x05 => self.data = (self.data & 0x0F) | value << 4,
+            0x06 => {
+                self.address = (self.address & 0x0F) | (value & 0x01) << 4;
+                self.command = value >> 1;
+            }
+            0x07 => {
+                self.address = (self.address & 0x10) | value;
+                match self.command {
+                    0x00 => self.ram[self.address as usize] = self.data,
+                    0x01 => self.result = self.ram[self.address as usize],
+                    // RTC (時計の読み書き, アラーム) は未実装
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for TAMA5 {
+    fn read_byte(&self, raw: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => raw[rom_offset(raw, 0, addr)],
+            0x4000..=0x7FFF => raw[rom_offset(raw, self.bank as usize, addr)],
+            0xA000..=0xBFFF => {
+                if addr & 0x01 != 0 {
+                    return 0xFF;
+                }
+                match self.register {
+                    // 0x0Aを選ぶと準備完了を返す
+                    0x0A => 0xF1,
+                    0x0C => 0xF0 | (self.result & 0x0F),
+                    0x0D => 0xF0 | (self.result >> 4),
+                    _ => 0xFF,
+                }
+            }
+            _ => panic!("unsupported TAMA5 memory."),
+        }
+    }
+
+    fn write_byte(&mut self, _raw: &mut [u8], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if addr & 0x01 != 0 {
+                    self.register = value & 0x0F;
+                } else {
+                    self.write_register(value);
+                }
+            }
+            _ => panic!("unsupported TAMA5 memory."),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.bank);
+        w.u8(self.data);
+        w.u8(self.address);
+        w.u8(self.command);
+        w.u8(self.result);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.register = r.u8()?;
+        self.bank = r.u8()?;
+        self.data = r.u8()?;
+        self.address = r.u8()?;
+        self.command = r.u8()?;
+        self.result = r.u8()?;
+        Ok(())
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+
+    fn disables_ram(&self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn has_ram_gate(&self) -> bool {
+        false
+    }
+
+    fn mapped_bank(&self, raw: &[u8], addr: u16) -> usize {
+        let bank = if addr < 0x4000 { 0 } else { self.bank as usize };
+        rom_offset(raw, bank, addr) / 0x4000
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::banked_rom;
+
+    // レジスタ番号を選んでから値を書く
+    fn write(tama5: &mut TAMA5, raw: &mut [u8], register: u8, value: u8) {
+        tama5.write_byte(raw, 0xA001, register);
+        tama5.write_byte(raw, 0xA000, value);
+    }
+
+    fn read_result(tama5: &mut TAMA5, raw: &mut [u8]) -> u8 {
+        tama5.write_byte(raw, 0xA001, 0x0C);
+        let low = tama5.read_byte(raw, 0xA000) & 0x0F;
+        tama5.write_byte(raw, 0xA001, 0x0D);
+        let high = tama5.read_byte(raw, 0xA000) & 0x0F;
+        high << 4 | low
+    }
+
+    #[test]
+    fn test_bank_switch() {
+        let mut raw = banked_rom(32);
+        let mut tama5 = TAMA5::new();
+        assert_eq!(tama5.read_byte(&raw, 0x4000), 0);
+        // レジスタ0が下位4bit, レジスタ1のbit0がbit4
+        write(&mut tama5, &mut raw, 0x00, 0x05);
+        assert_eq!(tama5.read_byte(&raw, 0x4000), 5);
+        write(&mut tama5, &mut raw, 0x01, 0x01);
+        assert_eq!(tama5.read_byte(&raw, 0x4000), 0x15);
+        assert_eq!(tama5.mapped_bank(&raw, 0x4000), 0x15);
+        assert_eq!(tama5.read_byte(&raw, 0x0000), 0);
+
+        // ROMへの書き込みは無視する
+        tama5.write_byte(&mut raw, 0x2000, 0x03);
+        assert_eq!(tama5.read_byte(&raw, 0x4000), 0x15);
+    }
+
+    #[test]
+    fn test_register_protocol() {
+        let mut raw = banked_rom(2);
+        let mut tama5 = TAMA5::new();
+        // 0x0Aは準備完了, 奇数アドレスと他のレジスタは0xFF
+        tama5.write_byte(&mut raw, 0xA001, 0x0A);
+        assert_eq!(tama5.read_byte(&raw, 0xA000), 0xF1);
+        assert_eq!(tama5.read_byte(&raw, 0xA001), 0xFF);
+        tama5.write_byte(&mut raw, 0xA001, 0x03);
+        assert_eq!(tama5.read_byte(&raw, 0xA000), 0xFF);
+
+        // 0x1Bに0xA5を書く: 値 (レジスタ4, 5), アドレスbit4とコマンド0 (レジスタ6), 下位 (レジスタ7)
+        write(&mut tama5, &mut raw, 0x04, 0x05);
+        write(&mut tama5, &mut raw, 0x05, 0x0A);
+        write(&mut tama5, &mut raw, 0x06, 0x01);
+        write(&mut tama5, &mut raw, 0x07, 0x0B);
+        assert_eq!(tama5.ram()[0x1B], 0xA5);
+        assert!(tama5.ram().iter().enumerate().all(|(i, &byte)| i == 0x1B || byte == 0));
+
+        // コマンド1で読み出して0x0C, 0x0Dで受け取る
+        write(&mut tama5, &mut raw, 0x06, 0x03);
+        write(&mut tama5, &mut raw, 0x07, 0x0B);
+        assert_eq!(read_result(&mut tama5, &mut raw), 0xA5);
+
+        // 時計のコマンドは何もしない
+        write(&mut tama5, &mut raw, 0x06, 0x05);
+        write(&mut tama5, &mut raw, 0x07, 0x0B);
+        assert_eq!(tama5.ram()[0x1B], 0xA5);
+        assert_eq!(read_result(&mut tama5, &mut raw), 0xA5);
+    }
+
+    // .savから読み込んだRAMがレジスタ経由で読める
+    #[test]
+    fn test_ram_round_trip() {
+        let mut raw = banked_rom(2);
+        let mut tama5 = TAMA5::new();
+        for address in 0..0x20u8 {
+            write(&mut tama5, &mut raw, 0x04, address & 0x0F);
+            write(&mut tama5, &mut raw, 0x05, !address & 0x0F);
+            write(&mut tama5, &mut raw, 0x06, address >> 4);
+            write(&mut tama5, &mut raw, 0x07, address & 0x0F);
+        }
+        let saved = tama5.ram().to_vec();
+
+        let mut loaded = TAMA5::new();
+        loaded.ram_mut().copy_from_slice(&saved);
+        for address in 0..0x20u8 {
+            write(&mut loaded, &mut raw, 0x06, 0x02 | address >> 4);
+            write(&mut loaded, &mut raw, 0x07, address & 0x0F);
+            assert_eq!(read_result(&mut loaded, &mut raw), (!address) << 4 | (address & 0x0F));
+        }
+    }
+}
@@ -1,47 +1,829 @@
-use crate::{cartridge::{self, Cartridge}, gpu::{self, LcdControlregisters, LcdStatusregisters, GPU, VRAM_BEGIN, VRAM_END}};
+use std::cell::Cell;
+
+use crate::{cartridge::Cartridge, cdl::CodeDataLog, debugger::{WatchHit, Watchpoint}, gpu::{LcdControlregisters, LcdStatusregisters, GPU, OAM_BEGIN, OAM_END, VRAM_BEGIN, VRAM_END}, model::Model, scheduler::{Event, Scheduler}, sgb::Sgb, state::{StateReader, StateWriter}, timer::Timer};
+
+pub const WRAM_BEGIN: usize = 0xC000;
+pub const WRAM_END: usize = 0xDFFF;
+pub const ECHO_BEGIN: usize = 0xE000;
+pub const ECHO_END: usize = 0xFDFF;
+pub const IO_BEGIN: usize = 0xFF00;
+pub const IO_END: usize = 0xFF7F;
+pub const HRAM_BEGIN: usize = 0xFF80;
+pub const HRAM_END: usize = 0xFFFE;
+
+// I/Oレジスタの読み出しマスク (未使用bitは1として読める, 0xFFは未使用アドレス)
+const IO_READ_MASK: [u8; 0x80] = [
+    // FF00: P1, SB, SC, -, DIV, TIMA, TMA, TAC, -, -, -, -, -, -, -, IF
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    // FF10: NR10-NR14, -, NR21-NR24, NR30-NR34, -
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // FF20: NR41-NR44, NR50-NR52, -
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // FF30: 波形RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // FF40: LCDC, STAT, SCY, SCX, LY, LYC, DMA, BGP, OBP0, OBP1, WY, WX, -
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    // FF50-FF7F: DMGでは未使用
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+// OAM DMAは1Mサイクルに1バイトずつ転送する
+const OAM_DMA_LENGTH: u16 = 0xA0;
+
+// HDMAの1ブロック (16バイト) の間CPUが止まるサイクル数 (1倍速のとき, 8Mサイクル)
+const HDMA_BLOCK_CYCLES: u16 = 32;
+
+// HDMA (0xFF51-0xFF55)
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    remaining: u8, // 残りブロック数-1 (1ブロック16バイト)
+    hblank_active: bool,
+}
 
 pub struct  MemoryBus{
-    memory: [u8; 0x10000],
+    wram: [u8; 0x8000], // CGBはSVBKでバンク1-7を切り替え
+    wram_bank: usize,
+    hram: [u8; 0x7F],
+    io: [u8; 0x80],
+    ie: u8, // 0xFFFF
+    joypad: u8, // 押されているボタン (bit0-3: 右左上下, bit4-7: A B Select Start)
+    boot_rom: Option<Vec<u8>>, // 0xFF50に書き込まれるまで0x0000-0x00FFに重ねる
+    pub model: Model,
+    pub cgb_mode: bool,
+    pub double_speed: bool,
+    speed_switch: bool, // KEY1 bit0
+    gpu_cycle_remainder: u16,
+    hdma: Hdma,
+    stall: u16, // HDMAでCPUを止めるサイクル数
+    oam_dma_source: u16,
+    oam_dma_index: u16, // 次に転送するバイト (OAM_DMA_LENGTHなら転送していない)
+    pub scheduler: Scheduler,
+    timer_synced: u64, // タイマーを最後に進めた時刻
+    video_synced: u64, // PPUとカートリッジの時計を最後に進めた時刻
+    pub timer: Timer,
     pub gpu: GPU,
-    catridge: Cartridge,
+    pub sgb: Option<Sgb>,
+    pub catridge: Cartridge,
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
+    pub cdl: Option<CodeDataLog>,
+    pub code_pages: u128, // ブロックキャッシュに入っているRAMのコードのページ (0x8000からの256バイト単位)
+    pub dirty_pages: u128, // code_pagesのうち書き換えられたページ
+    pub bank_switched: bool, // ROMやWRAMのバンク, ブートROMが切り替わったかもしれない
+    #[cfg(test)]
+    pub flat: Option<Vec<u8>>, // テスト用: 0x0000-0xFFFFを全て1枚のRAMとして扱う
 }
 
 impl MemoryBus{
-    pub fn new(cartridge: Cartridge) -> Self{
-        MemoryBus {
-            memory: [0; 0x10000],
+    pub fn new(cartridge: Cartridge, model: Model, boot_rom: Option<Vec<u8>>) -> Self{
+        // CGBモードはカートリッジヘッダ0x0143のbit7で決まる
+        let cgb_mode = model.is_cgb() && cartridge.read_byte(0x0143) & 0x80 != 0;
+        let mut bus = MemoryBus {
+            wram: [0; 0x8000],
+            wram_bank: 1,
+            hram: [0; 0x7F],
+            io: [0; 0x80],
+            ie: 0,
+            joypad: 0,
+            boot_rom,
+            model,
+            cgb_mode,
+            double_speed: false,
+            speed_switch: false,
+            gpu_cycle_remainder: 0,
+            hdma: Hdma {
+                source: 0,
+                destination: 0,
+                remaining: 0x7F,
+                hblank_active: false,
+            },
+            stall: 0,
+            oam_dma_source: 0,
+            oam_dma_index: OAM_DMA_LENGTH,
+            scheduler: Scheduler::new(),
+            timer_synced: 0,
+            video_synced: 0,
+            timer: Timer::new(),
             gpu: GPU::new(),
+            sgb: if model.is_sgb() { Some(Sgb::new()) } else { None },
             catridge: cartridge,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            cdl: None,
+            code_pages: 0,
+            dirty_pages: 0,
+            bank_switched: false,
+            #[cfg(test)]
+            flat: None,
+        };
+        bus.gpu.cgb_mode = cgb_mode;
+        bus.gpu.obj_priority_by_x = !cgb_mode;
+        if bus.boot_rom.is_none() {
+            bus.init_post_boot();
         }
+        bus.sync_timer();
+        bus.sync_video();
+        bus
+    }
+
+    // ブートROM終了直後のI/Oレジスタの値
+    fn init_post_boot(&mut self) {
+        let values: [(usize, u8); 22] = [
+            (0xFF00, 0xCF), (0xFF02, 0x7E), (0xFF0F, 0xE1),
+            (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+            (0xFF16, 0x3F), (0xFF18, 0xFF), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+            (0xFF20, 0xFF), (0xFF23, 0xBF),
+            (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+            (0xFF46, 0xFF),
+        ];
+        for (address, value) in values {
+            self.io[address - IO_BEGIN] = value;
+        }
+
+        self.timer.counter = match self.model {
+            Model::DMG0 => 0x1800,
+            Model::CGB | Model::AGB => 0x0000,
+            _ => 0xABCC,
+        };
+        if self.model.is_cgb() {
+            self.io[0x46] = 0x00;
+        }
+        if self.model.is_sgb() {
+            self.io[0x26] = 0xF0;
+        }
+
+        self.gpu.bgp = 0xFC;
+        self.gpu.obp0 = 0xFF;
+        self.gpu.obp1 = 0xFF;
+        // CGBのブートROMはBGパレットを全て白にする
+        if self.cgb_mode {
+            self.gpu.bg_palette.write_spec(0x80);
+            for _ in 0..32 {
+                self.gpu.bg_palette.write_data(0xFF);
+                self.gpu.bg_palette.write_data(0x7F);
+            }
+        }
+
+        self.gpu.control = LcdControlregisters::from(0x91);
+        self.gpu.status = LcdStatusregisters::from(if self.model == Model::DMG0 { 0x81 } else { 0x85 });
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
+        // OAM DMAの間CPUが読めるのはI/OとHRAMだけ
+        if self.oam_dma_active() && address < 0xFF00 {
+            return 0xFF;
+        }
+        let value = self.peek(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, false);
+        }
+        if let (Some(cdl), Some(offset)) = (&self.cdl, self.rom_offset(address)) {
+            cdl.log_read(address, offset, value);
+        }
+        value
+    }
+
+    // ウォッチポイントに引っかからない読み出し (デバッガとDMA用)
+    pub fn peek(&self, address: u16) -> u8 {
+        #[cfg(test)]
+        if let Some(flat) = &self.flat {
+            return flat[address as usize];
+        }
         let address = address as usize;
+        if let Some(boot_rom) = &self.boot_rom {
+            // CGBのブートROMは0x0100-0x01FF (カートリッジヘッダ) を避けて配置される
+            if address < 0x100 || (0x200..boot_rom.len()).contains(&address) {
+                return boot_rom[address];
+            }
+        }
         match address {
             0x0000..=0x7FFF => self.catridge.read_byte(address as u16),
             VRAM_BEGIN..=VRAM_END => {
                 self.gpu.read_vram(address - VRAM_BEGIN)
             },
-            0xFF40 => u8::from(self.gpu.control),
-            0xFF41 => u8::from(self.gpu.status),
-            0xFF44 => self.gpu.ly,
-            0xFF45 => self.gpu.lyc,
-            _ => self.memory[address]
+            0xA000..=0xBFFF => self.catridge.read_byte(address as u16),
+            WRAM_BEGIN..=WRAM_END => self.wram[self.wram_index(address - WRAM_BEGIN)],
+            ECHO_BEGIN..=ECHO_END => self.wram[self.wram_index(address - ECHO_BEGIN)],
+            OAM_BEGIN..=OAM_END => self.gpu.read_oam(address - OAM_BEGIN),
+            0xFEA0..=0xFEFF => 0x00, // 使用不可領域
+            IO_BEGIN..=IO_END => self.read_io(address),
+            HRAM_BEGIN..=HRAM_END => self.hram[address - HRAM_BEGIN],
+            0xFFFF => self.ie,
+            _ => 0xFF,
         }
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8){
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, true);
+        }
+        #[cfg(test)]
+        if self.flat.is_some() {
+            if address >= 0x8000 {
+                self.code_write(address as usize);
+            }
+            if let Some(flat) = &mut self.flat {
+                flat[address as usize] = value;
+            }
+            return;
+        }
         let address = address as usize;
         match address {
-            0x0000..=0x7FFF => self.catridge.write_byte(address as u16, value),
+            0x0000..=0x7FFF => {
+                // 見えているROMのバンクが変わったときだけブロックキャッシュに知らせる (RAMの有効化などでは捨てない)
+                let banks = (self.catridge.rom_offset(0x0000), self.catridge.rom_offset(0x4000));
+                self.catridge.write_byte(address as u16, value);
+                if (self.catridge.rom_offset(0x0000), self.catridge.rom_offset(0x4000)) != banks {
+                    self.bank_switched = true;
+                }
+            },
             VRAM_BEGIN..=VRAM_END => {
+                if let Some(cdl) = &self.cdl {
+                    if address - VRAM_BEGIN < 0x1800 {
+                        cdl.log_tile_write(value);
+                    }
+                }
                 self.gpu.write_vram(address - VRAM_BEGIN, value)
             },
+            0xA000..=0xBFFF => self.catridge.write_byte(address as u16, value),
+            // ブロックキャッシュに入っているコードへの書き込みを知らせる
+            WRAM_BEGIN..=WRAM_END => {
+                self.code_write(address);
+                self.wram[self.wram_index(address - WRAM_BEGIN)] = value
+            },
+            ECHO_BEGIN..=ECHO_END => {
+                self.code_write(address - 0x2000);
+                self.wram[self.wram_index(address - ECHO_BEGIN)] = value
+            },
+            OAM_BEGIN..=OAM_END => self.gpu.write_oam(address - OAM_BEGIN, value),
+            0xFEA0..=0xFEFF => { /* 使用不可領域 */ },
+            IO_BEGIN..=IO_END => self.write_io(address, value),
+            HRAM_BEGIN..=HRAM_END => {
+                self.code_write(address);
+                self.hram[address - HRAM_BEGIN] = value
+            },
+            0xFFFF => self.ie = value,
+            _ => {},
+        }
+    }
+
+    fn check_watchpoints(&self, address: u16, value: u8, write: bool) {
+        let hit = self.watchpoints.iter().any(|w| {
+            (if write { w.write } else { w.read }) && (w.start..=w.end).contains(&address)
+        });
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { address, value, write }));
+        }
+    }
+
+    // ブロックキャッシュに入っているページへの書き込みを知らせる
+    #[inline]
+    fn code_write(&mut self, address: usize) {
+        self.dirty_pages |= self.code_pages & 1 << ((address - 0x8000) >> 8);
+    }
+
+    // ブロックキャッシュを使えるか
+    // ウォッチポイントとCDLは命令のフェッチも見るので, その間はキャッシュを使わない
+    // OAM DMAの間はフェッチが0xFFになることがあるので使わない
+    pub fn cache_usable(&self) -> bool {
+        self.watchpoints.is_empty() && self.cdl.is_none() && !self.oam_dma_active()
+    }
+
+    // 読んだ値が時刻で変わらない場所 (ROM, WRAM, HRAM)
+    // ブロックキャッシュはここを読むときは溜めたサイクルを渡さずに読む (OAM DMA中は使わない)
+    pub fn is_static(address: u16) -> bool {
+        matches!(address, 0x0000..=0x7FFF | 0xC000..=0xFDFF | 0xFF80..=0xFFFE)
+    }
+
+    // 今からcyclesサイクル進める間, 何も起きず (イベント, OAM DMA, HDMAのCPU停止) まとめて進めてよいか
+    pub fn can_defer(&self, cycles: u16) -> bool {
+        !self.scheduler.due(cycles as u64) && !self.oam_dma_active() && self.stall == 0
+    }
+
+    // ブロックキャッシュでaddressのコードを引くときのバンク (ROM, WRAM, HRAM以外はNone)
+    pub fn code_bank(&self, address: u16) -> Option<u16> {
+        match address as usize {
+            // ブートROMが重なっている間はキャッシュしない
+            0x0000..=0x7FFF if self.boot_rom.is_some() => None,
+            0x0000..=0x7FFF => Some((self.catridge.rom_offset(address) / 0x4000) as u16),
+            0xC000..=0xCFFF | HRAM_BEGIN..=HRAM_END => Some(0),
+            0xD000..=0xDFFF => Some(self.wram_bank as u16),
+            _ => None,
+        }
+    }
+
+    // アドレスに見えているカートリッジROMのオフセット (ブートROMが重なっていればNone)
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
+        if address >= 0x8000 {
+            return None;
+        }
+        if let Some(boot_rom) = &self.boot_rom {
+            let address = address as usize;
+            if address < 0x100 || (0x200..boot_rom.len()).contains(&address) {
+                return None;
+            }
+        }
+        Some(self.catridge.rom_offset(address))
+    }
+
+    // アドレスに見えているバンク (シンボルの解決用, 切り替えのない領域は0)
+    pub fn bank(&self, address: u16) -> u16 {
+        match address as usize {
+            0x0000..=0x7FFF => self.catridge.rom_bank(address) as u16,
+            VRAM_BEGIN..=VRAM_END => self.gpu.vram_bank as u16,
+            0xD000..=0xDFFF => self.wram_bank as u16,
+            _ => 0,
+        }
+    }
+
+    pub fn watch_hit_pending(&self) -> bool {
+        self.watch_hit.get().is_some()
+    }
+
+    // 最後の命令で引っかかったウォッチポイント
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // 0xD000-0xDFFFはSVBKで選んだバンク
+    fn wram_index(&self, offset: usize) -> usize {
+        if offset < 0x1000 {
+            offset
+        } else {
+            self.wram_bank * 0x1000 + (offset - 0x1000)
+        }
+    }
+
+    fn read_io(&self, address: usize) -> u8 {
+        if self.cgb_mode {
+            match address {
+                0xFF4D => return 0x7E | (if self.double_speed { 0x80 } else { 0 }) | self.speed_switch as u8,
+                0xFF4F => return 0xFE | self.gpu.vram_bank as u8,
+                0xFF51..=0xFF54 => return 0xFF,
+                0xFF55 => return (if self.hdma.hblank_active { 0x00 } else { 0x80 }) | self.hdma.remaining,
+                0xFF68 => return self.gpu.bg_palette.read_spec(),
+                0xFF69 => return self.gpu.bg_palette.read_data(),
+                0xFF6A => return self.gpu.obj_palette.read_spec(),
+                0xFF6B => return self.gpu.obj_palette.read_data(),
+                0xFF6C => return 0xFE | self.gpu.obj_priority_by_x as u8,
+                0xFF70 => return 0xF8 | self.wram_bank as u8,
+                _ => {}
+            }
+        }
+        let mask = IO_READ_MASK[address - IO_BEGIN];
+        let value = match address {
+            0xFF04..=0xFF07 => self.timer.read(address),
+            // SGBのマルチプレイヤーではP14/P15が両方1のときコントローラー番号が読める
+            0xFF00 => match self.sgb.as_ref().and_then(|sgb| sgb.joypad_id()) {
+                Some(id) if self.io[0x00] & 0x30 == 0x30 => 0x30 | id,
+                _ => self.io[0x00] & 0x30 | !self.selected_buttons() & 0x0F,
+            },
+            0xFF40 => u8::from(self.gpu.control),
+            0xFF41 => u8::from(self.gpu.status),
+            0xFF42 => self.gpu.scy,
+            0xFF43 => self.gpu.scx,
+            0xFF44 => self.gpu.ly,
+            0xFF45 => self.gpu.lyc,
+            0xFF47 => self.gpu.bgp,
+            0xFF48 => self.gpu.obp0,
+            0xFF49 => self.gpu.obp1,
+            0xFF4A => self.gpu.wy,
+            0xFF4B => self.gpu.wx,
+            _ => self.io[address - IO_BEGIN],
+        };
+        value | mask
+    }
+
+    // P14/P15で選ばれているボタン (押されていればbitが立つ)
+    fn selected_buttons(&self) -> u8 {
+        let mut buttons = 0;
+        if self.io[0x00] & 0x10 == 0 {
+            buttons |= self.joypad & 0x0F;
+        }
+        if self.io[0x00] & 0x20 == 0 {
+            buttons |= self.joypad >> 4;
+        }
+        buttons
+    }
+
+    // 新しく押されたボタンがあればジョイパッド割り込みを要求する
+    pub fn set_joypad(&mut self, buttons: u8) {
+        if buttons & !self.joypad != 0 {
+            self.io[0x0F] |= 0x10;
+        }
+        self.joypad = buttons;
+    }
+
+    fn write_io(&mut self, address: usize, value: u8) {
+        if self.cgb_mode {
+            match address {
+                0xFF4D => {
+                    self.speed_switch = value & 0x01 != 0;
+                    return;
+                }
+                0xFF4F => {
+                    self.gpu.vram_bank = (value & 0x01) as usize;
+                    return;
+                }
+                0xFF51 => self.hdma.source = (self.hdma.source & 0x00FF) | (value as u16) << 8,
+                0xFF52 => self.hdma.source = (self.hdma.source & 0xFF00) | (value & 0xF0) as u16,
+                0xFF53 => self.hdma.destination = (self.hdma.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+                0xFF54 => self.hdma.destination = (self.hdma.destination & 0xFF00) | (value & 0xF0) as u16,
+                0xFF55 => {
+                    self.start_hdma(value);
+                    return;
+                }
+                0xFF68 => {
+                    self.gpu.bg_palette.write_spec(value);
+                    return;
+                }
+                0xFF69 => {
+                    self.gpu.bg_palette.write_data(value);
+                    return;
+                }
+                0xFF6A => {
+                    self.gpu.obj_palette.write_spec(value);
+                    return;
+                }
+                0xFF6B => {
+                    self.gpu.obj_palette.write_data(value);
+                    return;
+                }
+                0xFF6C => {
+                    self.gpu.obj_priority_by_x = value & 0x01 != 0;
+                    return;
+                }
+                0xFF70 => {
+                    self.wram_bank = (value & 0x07).max(1) as usize;
+                    self.bank_switched = true;
+                    return;
+                }
+                _ => {}
+            }
+        }
+        match address {
+            0xFF04..=0xFF07 => {
+                self.sync_timer();
+                if self.timer.write(address, value) {
+                    self.io[0x0F] |= 0x04;
+                }
+                self.sync_timer();
+            },
+            0xFF00 => {
+                let previous = self.io[0x00];
+                self.io[0x00] = value;
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(value, previous);
+                }
+            },
             0xFF40 => self.gpu.control = LcdControlregisters::from(value),
-            0xFF41 => self.gpu.status = LcdStatusregisters::from(value),
+            0xFF41 => {
+                // 下位3bit (一致フラグとモード) は読み出し専用
+                let current = u8::from(self.gpu.status);
+                self.gpu.status = LcdStatusregisters::from(value & 0x78 | current & 0x07);
+            },
             0xFF44 => { /* read only */ },
-            0xFF45 => self.gpu.lyc = value,
-            _ => self.memory[address] = value,
+            0xFF42 => self.gpu.scy = value,
+            0xFF43 => self.gpu.scx = value,
+            0xFF45 => {
+                self.sync_video();
+                self.gpu.lyc = value;
+            },
+            0xFF47 => self.gpu.bgp = value,
+            0xFF48 => self.gpu.obp0 = value,
+            0xFF49 => self.gpu.obp1 = value,
+            0xFF4A => self.gpu.wy = value,
+            0xFF4B => self.gpu.wx = value,
+            0xFF50 => {
+                // ブートROMの切り離し (一度外すと戻せない)
+                if value & 0x01 != 0 {
+                    self.boot_rom = None;
+                    self.bank_switched = true;
+                }
+            },
+            0xFF46 => {
+                // OAM DMA: 次のMサイクルから160Mサイクルかけて転送する
+                self.io[address - IO_BEGIN] = value;
+                self.oam_dma_source = (value as u16) << 8;
+                self.oam_dma_index = 0;
+            },
+            _ => self.io[address - IO_BEGIN] = value,
+        }
+    }
+
+    fn start_hdma(&mut self, value: u8) {
+        // HBlank転送中にbit7=0を書くと停止する
+        if self.hdma.hblank_active && value & 0x80 == 0 {
+            self.hdma.hblank_active = false;
+            self.hdma.remaining = value & 0x7F;
+            return;
+        }
+        self.hdma.remaining = value & 0x7F;
+        if value & 0x80 != 0 {
+            self.hdma.hblank_active = true;
+        } else {
+            // 汎用転送は一度に全て行い, その分CPUを止める
+            for _ in 0..=self.hdma.remaining {
+                self.hdma_block();
+            }
+            self.hdma.remaining = 0x7F;
+        }
+    }
+
+    // 16バイトをVRAMへ転送する (ウォッチポイントやCDLの読み出しには数えない)
+    fn hdma_block(&mut self) {
+        self.stall += if self.double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+        for _ in 0..0x10 {
+            let byte = self.peek(self.hdma.source);
+            if let (Some(cdl), Some(offset)) = (&self.cdl, self.rom_offset(self.hdma.source)) {
+                if self.hdma.destination & 0x1FFF < 0x1800 {
+                    cdl.log_graphics(offset);
+                }
+            }
+            self.gpu.write_vram((self.hdma.destination & 0x1FFF) as usize, byte);
+            self.hdma.source = self.hdma.source.wrapping_add(1);
+            self.hdma.destination = self.hdma.destination.wrapping_add(1);
+        }
+    }
+
+    // HDMAでCPUが止まるサイクル数 (命令の後にCPUがこの分だけ進める)
+    pub fn take_stall(&mut self) -> u16 {
+        std::mem::take(&mut self.stall)
+    }
+
+    pub fn oam_dma_active(&self) -> bool {
+        self.oam_dma_index < OAM_DMA_LENGTH
+    }
+
+    // 4サイクルごとに1バイト転送する
+    fn oam_dma(&mut self, cycles: u16) {
+        for _ in 0..cycles / 4 {
+            if !self.oam_dma_active() {
+                break;
+            }
+            let byte = self.peek(self.oam_dma_source + self.oam_dma_index);
+            self.gpu.write_oam(self.oam_dma_index as usize, byte);
+            self.oam_dma_index += 1;
+        }
+    }
+
+    // CPUのサイクル数だけ時刻を進め, 予定時刻になった部品だけを動かす
+    pub fn tick(&mut self, cycles: u16) {
+        if self.oam_dma_active() {
+            self.oam_dma(cycles);
+        }
+        if self.scheduler.advance(cycles as u64) {
+            while let Some(event) = self.scheduler.pop() {
+                match event {
+                    Event::Video => self.sync_video(),
+                    Event::Timer => self.sync_timer(),
+                }
+            }
+        }
+    }
+
+    // CPUがI/Oレジスタを読む前に呼ぶ: 値が時刻で変わる部品を現在まで進める
+    pub fn sync(&mut self, address: u16) {
+        match address {
+            0xFF04..=0xFF07 => self.sync_timer(),
+            0xFF41 | 0xFF44 => self.sync_video(),
+            _ => {}
+        }
+    }
+
+    // タイマーを現在時刻まで進め, 次のオーバーフローを予約する
+    fn sync_timer(&mut self) {
+        let elapsed = self.scheduler.now - self.timer_synced;
+        self.timer_synced = self.scheduler.now;
+        if self.timer.update(elapsed) {
+            self.io[0x0F] |= 0x04;
+        }
+        match self.timer.cycles_until_overflow() {
+            Some(cycles) => self.scheduler.schedule(Event::Timer, cycles),
+            None => self.scheduler.cancel(Event::Timer),
+        }
+    }
+
+    // PPUとカートリッジの時計を現在時刻まで進め, 次のモードの切り替わりを予約する
+    fn sync_video(&mut self) {
+        let elapsed = self.scheduler.now - self.video_synced;
+        self.video_synced = self.scheduler.now;
+
+        // 倍速モードではPPUはCPUの半分の速さで進む
+        let mut gpu_cycles = if self.double_speed {
+            let total = elapsed + self.gpu_cycle_remainder as u64;
+            self.gpu_cycle_remainder = (total % 2) as u16;
+            total / 2
+        } else {
+            elapsed
+        };
+        // 間が空いていても (セーブステートの読み込み直後など) モードの切り替わりごとに区切って進める
+        while gpu_cycles > 0 {
+            let cycles = gpu_cycles.min(self.gpu.cycles_until_mode_change() as u64) as u16;
+            gpu_cycles -= cycles as u64;
+            self.update_video(cycles);
+        }
+
+        let next = self.gpu.cycles_until_mode_change() as u64;
+        let next = if self.double_speed { next * 2 - self.gpu_cycle_remainder as u64 } else { next };
+        self.scheduler.schedule(Event::Video, next);
+    }
+
+    // モードが切り替わるまでの範囲でPPUを進める
+    fn update_video(&mut self, cycles: u16) {
+        // カートリッジの時計も実時間と同じ速さで進める
+        self.catridge.tick(cycles);
+
+        let was_vblank = self.gpu.ly >= 144;
+        self.gpu.update(cycles);
+        if !was_vblank && self.gpu.ly >= 144 {
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.vblank(&self.gpu);
+            }
+        }
+
+        if self.gpu.take_hblank() && self.hdma.hblank_active {
+            self.hdma_block();
+            if self.hdma.remaining == 0 {
+                self.hdma.hblank_active = false;
+                self.hdma.remaining = 0x7F;
+            } else {
+                self.hdma.remaining -= 1;
+            }
+        }
+    }
+
+    // STOP命令: KEY1で準備されていれば速度を切り替える
+    pub fn stop(&mut self) {
+        if self.cgb_mode && self.speed_switch {
+            // 切り替える前の速度で進めておく
+            self.sync_timer();
+            self.sync_video();
+            self.double_speed = !self.double_speed;
+            self.speed_switch = false;
+            self.timer.counter = 0;
+            self.sync_timer();
+            self.sync_video();
+        }
+    }
+
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        self.sync_timer();
+        self.sync_video();
+        w.bytes(&self.wram);
+        w.u8(self.wram_bank as u8);
+        w.bytes(&self.hram);
+        w.bytes(&self.io);
+        w.u8(self.ie);
+        w.u8(self.joypad);
+        w.bool(self.boot_rom.is_some());
+        w.bool(self.cgb_mode);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch);
+        w.u16(self.gpu_cycle_remainder);
+        w.u16(self.hdma.source);
+        w.u16(self.hdma.destination);
+        w.u8(self.hdma.remaining);
+        w.bool(self.hdma.hblank_active);
+        w.u16(self.oam_dma_source);
+        w.u16(self.oam_dma_index);
+        self.timer.save_state(w);
+        self.gpu.save_state(w);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
+        }
+        self.catridge.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.wram)?;
+        self.wram_bank = (r.u8()? & 0x07).max(1) as usize;
+        r.bytes_into(&mut self.hram)?;
+        r.bytes_into(&mut self.io)?;
+        self.ie = r.u8()?;
+        self.joypad = r.u8()?;
+        // ブートROMは一度外すと戻せない
+        if r.bool()? {
+            if self.boot_rom.is_none() {
+                return Err(String::from("save state was made while the boot rom was mapped"));
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        self.cgb_mode = r.bool()?;
+        self.gpu.cgb_mode = self.cgb_mode;
+        self.double_speed = r.bool()?;
+        self.speed_switch = r.bool()?;
+        self.gpu_cycle_remainder = r.u16()?;
+        self.hdma.source = r.u16()?;
+        self.hdma.destination = r.u16()?;
+        self.hdma.remaining = r.u8()?;
+        self.hdma.hblank_active = r.bool()?;
+        self.oam_dma_source = r.u16()?;
+        self.oam_dma_index = r.u16()?.min(OAM_DMA_LENGTH);
+        self.timer.load_state(r)?;
+        self.gpu.load_state(r)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(r)?;
+        }
+        self.catridge.load_state(r)?;
+        // 読み込んだ状態から予定を立て直す
+        self.timer_synced = self.scheduler.now;
+        self.video_synced = self.scheduler.now;
+        self.sync_timer();
+        self.sync_video();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_bus() -> MemoryBus {
+        let cartridge = Cartridge::from_bytes(vec![0; 0x8000]).unwrap();
+        MemoryBus::new(cartridge, Model::DMG, None)
+    }
+
+    // 0xE000-0xFDFFは0xC000-0xDDFFと同じ場所
+    #[test]
+    fn test_echo_ram() {
+        let mut bus = new_bus();
+        bus.write_byte(0xC123, 0x5A);
+        assert_eq!(bus.read_byte(0xE123), 0x5A);
+        bus.write_byte(0xFDFF, 0xA5);
+        assert_eq!(bus.read_byte(0xDDFF), 0xA5);
+        bus.write_byte(0xE000, 0x01);
+        assert_eq!(bus.read_byte(0xC000), 0x01);
+        // 0xDE00-0xDFFFは映らない
+        bus.write_byte(0xDE00, 0x77);
+        assert_eq!(bus.read_byte(0xFE00), 0x00);
+    }
+
+    // ROMへの書き込みは, 見えているバンクが変わったときだけブロックキャッシュに知らせる
+    #[test]
+    fn test_bank_switch_notification() {
+        // MBC1, 64KB
+        let mut raw = vec![0; 0x10000];
+        raw[0x0147] = 0x01;
+        raw[0x0148] = 0x01;
+        let mut bus = MemoryBus::new(Cartridge::from_bytes(raw).unwrap(), Model::DMG, None);
+        // RAMの有効化, 同じバンクの選び直し
+        bus.write_byte(0x0000, 0x0A);
+        bus.write_byte(0x2000, 0x01);
+        assert!(!bus.bank_switched);
+        bus.write_byte(0x2000, 0x02);
+        assert!(bus.bank_switched);
+    }
+
+    #[test]
+    fn test_unusable_area() {
+        let mut bus = new_bus();
+        bus.write_byte(0xFE9F, 0x11);
+        for address in [0xFEA0, 0xFEC0, 0xFEFF] {
+            bus.write_byte(address, 0x42);
+            assert_eq!(bus.read_byte(address), 0x00);
+        }
+        assert_eq!(bus.read_byte(0xFE9F), 0x11);
+    }
+
+    // 未使用のI/Oは書いても0xFFが読める
+    #[test]
+    fn test_unused_io() {
+        let mut bus = new_bus();
+        for address in [0xFF03, 0xFF08, 0xFF0E, 0xFF15, 0xFF1F, 0xFF27, 0xFF4C, 0xFF56, 0xFF7F] {
+            bus.write_byte(address, 0x00);
+            assert_eq!(bus.read_byte(address), 0xFF, "{:04X}", address);
+        }
+    }
+
+    // 使われていないbitは1が読める
+    #[test]
+    fn test_io_read_mask() {
+        let mut bus = new_bus();
+        // SC, TAC, IF, NR52
+        for (address, value, expected) in [
+            (0xFF02, 0x00, 0x7E),
+            (0xFF07, 0x00, 0xF8),
+            (0xFF07, 0x05, 0xFD),
+            (0xFF0F, 0x00, 0xE0),
+            (0xFF0F, 0x1F, 0xFF),
+            (0xFF26, 0x00, 0x70),
+            (0xFF11, 0xC0, 0xFF),
+            (0xFF11, 0x00, 0x3F),
+        ] {
+            bus.write_byte(address, value);
+            assert_eq!(bus.read_byte(address), expected, "{:04X} <- {:02X}", address, value);
         }
+        // STATのbit7は常に1, 下位3bitは書き込めない
+        let mode = bus.read_byte(0xFF41) & 0x07;
+        bus.write_byte(0xFF41, 0x00);
+        assert_eq!(bus.read_byte(0xFF41), 0x80 | mode);
+        bus.write_byte(0xFF41, 0xFF);
+        assert_eq!(bus.read_byte(0xFF41), 0xF8 | mode);
     }
 }
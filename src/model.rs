@@ -0,0 +1,44 @@
+// エミュレートするハードウェアの種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Model {
+    DMG0,
+    DMG,
+    MGB,
+    SGB,
+    SGB2,
+    CGB,
+    AGB,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_ascii_uppercase().as_str() {
+            "DMG0" => Some(Model::DMG0),
+            "DMG" => Some(Model::DMG),
+            "MGB" => Some(Model::MGB),
+            "SGB" => Some(Model::SGB),
+            "SGB2" => Some(Model::SGB2),
+            "CGB" => Some(Model::CGB),
+            "AGB" => Some(Model::AGB),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+
+    // ブートROMのサイズ (CGBは0x0000-0x00FFと0x0200-0x08FF)
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() {
+            0x900
+        } else {
+            0x100
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+
+use crate::model::Model;
+use crate::state::{StateReader, StateWriter};
+
+// 入力ムービー (.gbm)
+// ヘッダ: マジック, 形式のバージョン, ROMのチェックサム, 機種, 開始状態 (電源投入かセーブステート)
+// 以降は1フレーム1バイトのボタン入力 (MemoryBus::set_joypadと同じbit配置)
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const FORMAT_VERSION: u32 = 1;
+
+pub struct Movie {
+    pub checksum: Option<u32>, // 取り込んだムービーではわからない
+    pub model: Model,
+    pub start_state: Option<Vec<u8>>, // Noneなら電源投入から
+    pub inputs: Vec<u8>,
+    position: usize,
+}
+
+impl Movie {
+    pub fn new(checksum: Option<u32>, model: Model, start_state: Option<Vec<u8>>) -> Self {
+        Movie {
+            checksum,
+            model,
+            start_state,
+            inputs: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if data.starts_with(b"VBM\x1A") {
+            return import_vbm(&data).map_err(|e| format!("{}: {}", path, e));
+        }
+
+        let mut r = StateReader::new(&data);
+        let parse = |r: &mut StateReader| -> Result<Self, String> {
+            if r.bytes()? != MAGIC {
+                return Err(String::from("not a movie file"));
+            }
+            let version = r.u32()?;
+            if version != FORMAT_VERSION {
+                return Err(format!("movie format {} is not supported", version));
+            }
+            let checksum = r.u32()?;
+            let model = model_from_u8(r.u8()?)?;
+            let start_state = if r.bool()? { Some(r.bytes()?.to_vec()) } else { None };
+            let inputs = r.bytes()?.to_vec();
+            let mut movie = Movie::new(Some(checksum), model, start_state);
+            movie.inputs = inputs;
+            Ok(movie)
+        };
+        parse(&mut r).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut w = StateWriter::new();
+        w.bytes(MAGIC);
+        w.u32(FORMAT_VERSION);
+        w.u32(self.checksum.unwrap_or(0));
+        w.u8(self.model as u8);
+        w.bool(self.start_state.is_some());
+        if let Some(state) = &self.start_state {
+            w.bytes(state);
+        }
+        w.bytes(&self.inputs);
+        fs::write(path, &w.data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn record(&mut self, buttons: u8) {
+        self.inputs.push(buttons);
+    }
+
+    // 次のフレームの入力 (最後まで再生したらNone)
+    pub fn next(&mut self) -> Option<u8> {
+        let buttons = self.inputs.get(self.position).copied();
+        self.position += 1;
+        buttons
+    }
+}
+
+fn model_from_u8(value: u8) -> Result<Model, String> {
+    [Model::DMG0, Model::DMG, Model::MGB, Model::SGB, Model::SGB2, Model::CGB, Model::AGB]
+        .into_iter()
+        .find(|model| *model as u8 == value)
+        .ok_or_else(|| format!("unknown model {}", value))
+}
+
+// VisualBoyAdvanceの.vbm (電源投入から始まるもののみ)
+fn import_vbm(data: &[u8]) -> Result<Movie, String> {
+    if data.len() < 0x40 {
+        return Err(String::from("vbm header is truncated"));
+    }
+    let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+    let frames = word(0x0C);
+    if data[0x14] & 0x03 != 0 {
+        return Err(String::from("vbm movies starting from a save state or sram are not supported"));
+    }
+    // 使われているコントローラーの数だけ2バイトずつ並ぶ
+    let controllers = (data[0x15] & 0x0F).count_ones().max(1) as usize;
+    // bit1: GBC, bit2: SGB
+    let model = match data[0x16] {
+        flags if flags & 0x02 != 0 => Model::CGB,
+        flags if flags & 0x04 != 0 => Model::SGB,
+        _ => Model::DMG,
+    };
+    let offset = word(0x3C);
+
+    let mut movie = Movie::new(None, model, None);
+    for frame in 0..frames {
+        let o = offset + frame * controllers * 2;
+        let value = match data.get(o) {
+            Some(value) => *value,
+            None => break,
+        };
+        // VBM: A B Select Start 右 左 上 下
+        movie.record(value.rotate_left(4));
+    }
+    Ok(movie)
+}
@@ -0,0 +1,144 @@
+use std::fs;
+
+// PNGの書き出し (RGB 8bit)
+// 画像データはzlibの無圧縮ブロックで格納するので, CRC32とAdler-32だけ計算すればよい
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// 無圧縮ブロック1つの最大の長さ
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// rgbはwidth*height*3バイト, scale倍に拡大して書き出す
+pub fn save(path: &str, width: usize, height: usize, rgb: &[u8], scale: usize) -> Result<(), String> {
+    let data = encode(width, height, rgb, scale);
+    fs::write(path, data).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn encode(width: usize, height: usize, rgb: &[u8], scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let (out_width, out_height) = (width * scale, height * scale);
+
+    // 各行の先頭にフィルタの種類 (0: なし) を付ける
+    let mut raw = Vec::with_capacity((out_width * 3 + 1) * out_height);
+    for y in 0..out_height {
+        raw.push(0);
+        let row = &rgb[(y / scale) * width * 3..][..width * 3];
+        for pixel in row.chunks(3) {
+            for _ in 0..scale {
+                raw.extend_from_slice(pixel);
+            }
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(out_width as u32).to_be_bytes());
+    header.extend_from_slice(&(out_height as u32).to_be_bytes());
+    // ビット深度8, カラータイプ2 (RGB), 圧縮0, フィルタ0, インターレースなし
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// 長さ, 種類, データ, CRC (種類とデータ)
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // CM=8 (deflate), 32KBの窓, 圧縮なし (FCHECKで31の倍数にする)
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        out.push(last as u8); // BFINAL, BTYPE=00
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552バイトごとに剰余を取ればu32であふれない
+    for block in data.chunks(5552) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    // 160x144: 行ごとに481バイトで69264バイトになり, 無圧縮ブロック2つ (65535 + 3729) に分かれる
+    #[test]
+    fn test_encode_layout() {
+        let rgb: Vec<u8> = (0..160 * 144 * 3).map(|i| i as u8).collect();
+        let png = encode(160, 144, &rgb, 1);
+        assert_eq!(&png[..8], &SIGNATURE);
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (body, crc) = rest[4..].split_at(4 + length);
+            assert_eq!(crc32(body), u32::from_be_bytes(crc[..4].try_into().unwrap()));
+            chunks.push((&body[..4], &body[4..]));
+            rest = &crc[4..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|&(kind, _)| kind).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 160, 0, 0, 0, 144, 8, 2, 0, 0, 0]);
+
+        // zlibのヘッダ, ブロックの見出し2つ, Adler-32
+        let idat = chunks[1].1;
+        assert_eq!(idat.len(), 2 + 5 + 65535 + 5 + 3729 + 4);
+        assert_eq!(&idat[..7], [0x78, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        assert_eq!(&idat[2 + 5 + 65535..][..5], [0x01, 0x91, 0x0E, 0x6E, 0xF1]);
+        assert!(chunks[2].1.is_empty());
+        assert_eq!(png.len(), 8 + (12 + 13) + (12 + idat.len()) + 12);
+    }
+}
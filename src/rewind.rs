@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+
+// 巻き戻し用のセーブステートのリングバッファ
+// キーフレームごとにグループを作り, その間の状態はキーフレームとのXORを
+// ランレングス圧縮して持つ (ほとんど変化しないのでかなり小さくなる)
+
+const KEYFRAME_INTERVAL: usize = 60;
+
+struct Group {
+    keyframe: Vec<u8>,     // 圧縮済み (0とのXOR)
+    deltas: Vec<Vec<u8>>,  // キーフレームとのXORを圧縮したもの
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(|d| d.len()).sum::<usize>()
+    }
+}
+
+pub struct Rewind {
+    groups: VecDeque<Group>,
+    keyframe: Vec<u8>, // 最新グループのキーフレーム (展開済み)
+    interval: u32,     // 何フレームごとに記録するか
+    counter: u32,
+    size: usize,
+    max_size: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, max_size: usize) -> Self {
+        Rewind {
+            groups: VecDeque::new(),
+            keyframe: Vec::new(),
+            interval: interval.max(1),
+            counter: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    // 1フレームごとに呼ぶ: 記録するフレームならtrueを返す
+    pub fn tick(&mut self) -> bool {
+        self.counter += 1;
+        if self.counter >= self.interval {
+            self.counter = 0;
+            return true;
+        }
+        false
+    }
+
+    pub fn push(&mut self, state: &[u8]) {
+        let new_group = match self.groups.back() {
+            Some(group) => group.deltas.len() + 1 >= KEYFRAME_INTERVAL || self.keyframe.len() != state.len(),
+            None => true,
+        };
+        if new_group {
+            let keyframe = compress(state, &[]);
+            self.size += keyframe.len();
+            self.groups.push_back(Group {
+                keyframe,
+                deltas: Vec::new(),
+            });
+            self.keyframe = state.to_vec();
+        } else {
+            let delta = compress(state, &self.keyframe);
+            self.size += delta.len();
+            self.groups.back_mut().unwrap().deltas.push(delta);
+        }
+
+        // 上限を超えたら古いグループから捨てる (最新のグループは残す)
+        while self.size > self.max_size && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            self.size -= group.size();
+        }
+    }
+
+    // 最後に記録した状態を取り出す
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let group = self.groups.back_mut()?;
+        if let Some(delta) = group.deltas.pop() {
+            self.size -= delta.len();
+            return Some(decompress(&delta, &self.keyframe));
+        }
+
+        let group = self.groups.pop_back().unwrap();
+        self.size -= group.size();
+        let state = decompress(&group.keyframe, &[]);
+        // 一つ前のグループのキーフレームを展開しておく
+        self.keyframe = match self.groups.back() {
+            Some(previous) => decompress(&previous.keyframe, &[]),
+            None => Vec::new(),
+        };
+        Some(state)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*position) {
+        *position += 1;
+        value |= ((byte & 0x7F) as usize).checked_shl(shift).unwrap_or(0);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// baseとのXORを (0の長さ, そのままの長さ, データ) の繰り返しで表す
+fn compress(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| state[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    write_varint(&mut out, state.len());
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+
+        // 0が続く区間が短ければそのまま含める
+        let start = i;
+        while i < state.len() && (xor(i) != 0 || (i + 1 < state.len() && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+// 壊れたデータでも範囲外は読み書きしない (足りない部分はbaseのまま)
+fn decompress(data: &[u8], base: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(data, &mut position);
+    let mut state: Vec<u8> = (0..length).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+    let mut i = 0;
+    while i < length && position < data.len() {
+        i = i.saturating_add(read_varint(data, &mut position));
+        let literal = read_varint(data, &mut position).min(data.len() - position);
+        let end = position + literal;
+        for (target, byte) in state.iter_mut().skip(i).zip(&data[position..end]) {
+            *target ^= byte;
+        }
+        i = i.saturating_add(literal);
+        position = end;
+    }
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 再現できる疑似乱数のバイト列
+    fn random_bytes(length: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.max(1);
+        (0..length)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let base = random_bytes(1000, 1);
+        let mut cases = vec![
+            random_bytes(1000, 2),
+            base.clone(),
+            base.iter().map(|b| !b).collect(),
+            Vec::new(),
+            random_bytes(1500, 3),
+            base[..200].to_vec(),
+        ];
+        // 1バイトだけ違う, 間隔を空けて違う
+        let mut changed = base.clone();
+        changed[500] ^= 0x10;
+        cases.push(changed.clone());
+        changed[502] ^= 0x01;
+        changed[999] ^= 0x80;
+        cases.push(changed);
+
+        for state in &cases {
+            assert_eq!(&decompress(&compress(state, &base), &base), state);
+            assert_eq!(&decompress(&compress(state, &[]), &[]), state);
+        }
+        // 同じ状態なら長さと区間1つだけ
+        assert!(compress(&base, &base).len() <= 6);
+    }
+
+    #[test]
+    fn test_decompress_broken() {
+        let base = random_bytes(100, 4);
+        let data = compress(&random_bytes(100, 5), &base);
+        for length in 0..data.len() {
+            assert!(decompress(&data[..length], &base).len() <= 100);
+        }
+        // 長さより後ろを指す区間やあふれるvarint
+        let broken = [10, 200, 1, 3, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 1];
+        assert_eq!(decompress(&broken, &[]).len(), 10);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let count = KEYFRAME_INTERVAL * 2 + 5;
+        let states: Vec<Vec<u8>> = (0..count)
+            .map(|i| {
+                let mut state = random_bytes(256, 6);
+                state[i % 256] ^= 0xFF;
+                state[(i * 7) % 256] = i as u8;
+                state
+            })
+            .collect();
+        for state in &states {
+            rewind.push(state);
+        }
+        assert_eq!(rewind.groups.len(), 3);
+        assert_eq!(rewind.size, rewind.groups.iter().map(|g| g.size()).sum::<usize>());
+
+        // グループの境目をまたいで前のキーフレームに戻っても, 記録した順の逆に取り出せる
+        for state in states.iter().rev() {
+            assert_eq!(rewind.pop().as_ref(), Some(state));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.size, 0);
+
+        // 取り出した後にまた記録できる
+        rewind.push(&states[0]);
+        rewind.push(&states[1]);
+        assert_eq!(rewind.pop().as_ref(), Some(&states[1]));
+    }
+
+    #[test]
+    fn test_length_change() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        rewind.push(&random_bytes(100, 7));
+        rewind.push(&random_bytes(100, 8));
+        assert_eq!(rewind.groups.len(), 1);
+        // 長さが変わったら新しいキーフレームにする
+        let longer = random_bytes(120, 9);
+        rewind.push(&longer);
+        assert_eq!(rewind.groups.len(), 2);
+        assert_eq!(rewind.pop(), Some(longer));
+        assert_eq!(rewind.pop(), Some(random_bytes(100, 8)));
+        assert_eq!(rewind.pop(), Some(random_bytes(100, 7)));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let keyframe_size = compress(&random_bytes(1000, 10), &[]).len();
+        let mut rewind = Rewind::new(1, keyframe_size * 3);
+        for i in 0..KEYFRAME_INTERVAL * 10 {
+            rewind.push(&random_bytes(1000, i as u32 + 10));
+            assert!(rewind.size <= rewind.max_size || rewind.groups.len() == 1);
+            assert_eq!(rewind.size, rewind.groups.iter().map(|g| g.size()).sum::<usize>());
+        }
+        // 古いものは捨てられ, 最新の状態から戻れる
+        assert!(rewind.groups.len() < 10);
+        let last = KEYFRAME_INTERVAL * 10 - 1;
+        assert_eq!(rewind.pop(), Some(random_bytes(1000, last as u32 + 10)));
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut rewind = Rewind::new(3, 0);
+        let recorded: Vec<bool> = (0..6).map(|_| rewind.tick()).collect();
+        assert_eq!(recorded, [false, false, true, false, false, true]);
+    }
+}
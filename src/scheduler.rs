@@ -0,0 +1,119 @@
+// イベントスケジューラ
+// 各部品は次に状態が変わる時刻 (電源投入からのCPUサイクル数) を登録しておく
+// CPUはその時刻まで部品を進めずに実行し, 部品は時刻になったときか
+// CPUがレジスタを読み書きするときにまとめて進める
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Video, // PPUのモードの切り替わり (モード2/3の終わり, ラインの終わり)
+    Timer, // TIMAのオーバーフロー
+}
+
+const EVENTS: [Event; 2] = [Event::Video, Event::Timer];
+
+pub struct Scheduler {
+    pub now: u64, // CPUのクロックで数えた経過サイクル数
+    times: [u64; EVENTS.len()], // 各イベントの予定時刻 (予定がなければu64::MAX)
+    next: u64, // 最も早い予定時刻
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            now: 0,
+            times: [u64::MAX; EVENTS.len()],
+            next: u64::MAX,
+        }
+    }
+
+    // 今からcyclesサイクル後にイベントを起こす (前の予定は取り消す)
+    pub fn schedule(&mut self, event: Event, cycles: u64) {
+        self.times[event as usize] = self.now + cycles;
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.times[event as usize] = u64::MAX;
+        self.update_next();
+    }
+
+    // 時刻を進める: 予定時刻に達したイベントがあればtrue
+    pub fn advance(&mut self, cycles: u64) -> bool {
+        self.now += cycles;
+        self.now >= self.next
+    }
+
+    // 今からcyclesサイクル以内に予定があるか
+    pub fn due(&self, cycles: u64) -> bool {
+        self.now + cycles >= self.next
+    }
+
+    // 予定時刻に達したイベントを早いものから1つずつ取り出す
+    pub fn pop(&mut self) -> Option<Event> {
+        if self.now < self.next {
+            return None;
+        }
+        let i = (0..EVENTS.len()).min_by_key(|&i| self.times[i])?;
+        self.times[i] = u64::MAX;
+        self.update_next();
+        Some(EVENTS[i])
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.times.iter().copied().min().unwrap_or(u64::MAX);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 同じ時刻のイベントはEVENTSの順 (Video, Timer) に取り出す
+    #[test]
+    fn test_same_timestamp() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timer, 16);
+        scheduler.schedule(Event::Video, 16);
+        assert!(!scheduler.advance(12));
+        assert_eq!(scheduler.pop(), None);
+        assert!(scheduler.advance(4));
+        assert_eq!(scheduler.pop(), Some(Event::Video));
+        assert_eq!(scheduler.pop(), Some(Event::Timer));
+        assert_eq!(scheduler.pop(), None);
+        assert!(!scheduler.advance(1000));
+    }
+
+    // 時刻を過ぎていれば早いものから, 登録した順番には関係なく取り出す
+    #[test]
+    fn test_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Video, 80);
+        scheduler.schedule(Event::Timer, 20);
+        assert!(scheduler.advance(100));
+        assert_eq!(scheduler.pop(), Some(Event::Timer));
+        assert_eq!(scheduler.pop(), Some(Event::Video));
+        assert_eq!(scheduler.now, 100);
+    }
+
+    // 登録し直すと前の予定は取り消される
+    #[test]
+    fn test_reschedule() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timer, 16);
+        scheduler.schedule(Event::Timer, 1024);
+        assert!(!scheduler.advance(16));
+        assert!(scheduler.advance(1008));
+        assert_eq!(scheduler.pop(), Some(Event::Timer));
+
+        // 早めることもできる
+        scheduler.schedule(Event::Video, 456);
+        scheduler.schedule(Event::Video, 4);
+        assert!(scheduler.advance(4));
+        assert_eq!(scheduler.pop(), Some(Event::Video));
+
+        scheduler.schedule(Event::Timer, 8);
+        scheduler.cancel(Event::Timer);
+        assert!(!scheduler.advance(8));
+        assert_eq!(scheduler.pop(), None);
+    }
+}
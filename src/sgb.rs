@@ -0,0 +1,641 @@
+use crate::gpu::{rgb555_to_color, GPU};
+use crate::state::{StateReader, StateWriter};
+
+// Super Game Boyのコマンド (パケット先頭バイトの上位5bit)
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 224;
+// 枠の中のゲーム画面の位置
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// SGBのBIOSが最初に設定するパレット
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy)]
+enum Transfer {
+    Border(usize), // タイル0x00-0x7Fか0x80-0xFF
+    Map,
+    Palettes,
+}
+
+pub struct Sgb {
+    // パケット受信
+    packet: [u8; 16],
+    bit_count: usize,
+    receiving: bool,
+    ready: bool, // P14/P15が両方1に戻った (次のパルスを1bitとして読む)
+    command: Vec<u8>,
+
+    // マルチプレイヤー (MLT_REQ)
+    players: u8,
+    pub player: u8,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>, // PAL_TRNで転送される512パレット
+    attributes: [u8; 20 * 18], // 8x8ドットごとのパレット番号
+    mask: Mask,
+    pending_transfer: Option<Transfer>,
+
+    border_tiles: Vec<u8>, // SNES形式4bppのタイル256個
+    border_map: Vec<u8>,   // 32x28の16bitエントリ
+    border_palettes: [[u16; 16]; 4], // パレット4-7
+
+    screen: [u8; 160 * 144],
+    pub frame: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            packet: [0; 16],
+            bit_count: 0,
+            receiving: false,
+            ready: false,
+            command: Vec::new(),
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; 20 * 18],
+            mask: Mask::Cancel,
+            pending_transfer: None,
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 28 * 2],
+            border_palettes: [[0; 16]; 4],
+            screen: [0; 160 * 144],
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+
+    // 0xFF00への書き込み: P14/P15のパルスでパケットを1bitずつ送る
+    pub fn write_joypad(&mut self, value: u8, previous: u8) {
+        match value & 0x30 {
+            // リセットパルス: パケットの開始
+            0x00 => {
+                self.receiving = true;
+                self.ready = false;
+                self.bit_count = 0;
+                self.packet = [0; 16];
+            }
+            0x30 => {
+                self.ready = true;
+                // P15の立ち上がりで次のコントローラーに切り替わる
+                if !self.receiving && previous & 0x20 == 0 && self.players > 1 {
+                    self.player = (self.player + 1) % self.players;
+                }
+            }
+            bits if self.receiving && self.ready => {
+                self.ready = false;
+                // P15=0なら1, P14=0なら0
+                let bit = bits == 0x10;
+                if self.bit_count < 128 {
+                    if bit {
+                        self.packet[self.bit_count / 8] |= 1 << (self.bit_count % 8);
+                    }
+                    self.bit_count += 1;
+                } else {
+                    // 129bit目はストップビット
+                    self.receiving = false;
+                    self.receive_packet();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // コントローラー番号の読み出し (P14/P15が両方1のとき下位4bitに出る)
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.players > 1 {
+            Some(0x0F - self.player)
+        } else {
+            None
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let length = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= length * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.pending_transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => self.pending_transfer = Some(Transfer::Border((data[1] & 0x01) as usize)),
+            PCT_TRN => self.pending_transfer = Some(Transfer::Map),
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    0x03 => Mask::Color0,
+                    _ => Mask::Cancel,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // PAL01など: 色0は全パレット共通
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| data[1 + i * 2] as u16 | (data[2 + i * 2] as u16) << 8;
+        for palette in 0..4 {
+            self.palettes[palette][0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            // 内側か外側だけが指定されたときは境界線もその色にする
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some(border),
+                _ => None,
+            };
+            for y in 0..18 {
+                for x in 0..20 {
+                    let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                        if control & 0x01 != 0 { Some(inside) } else { None }
+                    } else if x < x1 || x > x2 || y < y1 || y > y2 {
+                        if control & 0x04 != 0 { Some(outside) } else { None }
+                    } else {
+                        border
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * 20 + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if number < 18 {
+                    for x in 0..20 {
+                        self.attributes[number * 20 + x] = palette;
+                    }
+                }
+            } else if number < 20 {
+                for y in 0..18 {
+                    self.attributes[y * 20 + number] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let position = data[2] as usize;
+        for y in 0..18 {
+            for x in 0..20 {
+                let i = if horizontal { y } else { x };
+                self.attributes[y * 20 + x] = if i < position {
+                    before
+                } else if i == position {
+                    on_line
+                } else {
+                    after
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = (data[1] as usize).min(19);
+        let mut y = (data[2] as usize).min(17);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(360);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.attributes[y * 20 + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == 18 {
+                    y = 0;
+                    x = (x + 1) % 20;
+                }
+            } else {
+                x += 1;
+                if x == 20 {
+                    x = 0;
+                    y = (y + 1) % 18;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let number = (data[1 + palette * 2] as usize | (data[2 + palette * 2] as usize) << 8) & 0x1FF;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+        }
+        // 色0はパレット0のものを共通で使う
+        for palette in 1..4 {
+            self.palettes[palette][0] = self.palettes[0][0];
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    // VBlankごとに呼ばれる: VRAM転送とゲーム画面の取り込み
+    pub fn vblank(&mut self, gpu: &GPU) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            let data = gpu.screen_tile_data();
+            match transfer {
+                Transfer::Border(half) => {
+                    self.border_tiles[half * 0x1000..half * 0x1000 + 0x1000].copy_from_slice(&data);
+                }
+                Transfer::Map => {
+                    // マップは32x32分送られるが, 使うのは上の28行
+                    let length = self.border_map.len();
+                    self.border_map.copy_from_slice(&data[..length]);
+                    for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                        for (c, color) in palette.iter_mut().enumerate() {
+                            let o = 0x800 + (i * 16 + c) * 2;
+                            *color = data[o] as u16 | (data[o + 1] as u16) << 8;
+                        }
+                    }
+                }
+                Transfer::Palettes => {
+                    for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                        *color = data[i * 2] as u16 | (data[i * 2 + 1] as u16) << 8;
+                    }
+                }
+            }
+        }
+        if self.mask != Mask::Freeze {
+            self.screen.copy_from_slice(&gpu.shades);
+        }
+        self.render();
+    }
+
+    // 枠の1ドット (SNESの4bppタイル, 色0は透明)
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let o = ((y / 8) * 32 + x / 8) * 2;
+        let entry = self.border_map[o] as u16 | (self.border_map[o + 1] as u16) << 8;
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let tx = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let ty = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let data = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let bit = 7 - tx;
+        let value = (data[ty * 2] >> bit) & 0x01
+            | ((data[ty * 2 + 1] >> bit) & 0x01) << 1
+            | ((data[16 + ty * 2] >> bit) & 0x01) << 2
+            | ((data[16 + ty * 2 + 1] >> bit) & 0x01) << 3;
+        if value == 0 || palette < 4 {
+            return None;
+        }
+        Some(self.border_palettes[palette - 4][value as usize])
+    }
+
+    fn render(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let inside = (SCREEN_X..SCREEN_X + 160).contains(&x) && (SCREEN_Y..SCREEN_Y + 144).contains(&y);
+                let color = if inside {
+                    let (gx, gy) = (x - SCREEN_X, y - SCREEN_Y);
+                    match self.mask {
+                        Mask::Black => 0x0000,
+                        Mask::Color0 => backdrop,
+                        _ => {
+                            let palette = self.attributes[(gy / 8) * 20 + gx / 8] as usize;
+                            self.palettes[palette][self.screen[gy * 160 + gx] as usize]
+                        }
+                    }
+                } else {
+                    self.border_pixel(x, y).unwrap_or(backdrop)
+                };
+                let o = (y * SCREEN_WIDTH + x) * 3;
+                self.frame[o..o + 3].copy_from_slice(&rgb555_to_color(color, false));
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.players);
+        w.u8(self.player);
+        for color in self.palettes.iter().flatten() {
+            w.u16(*color);
+        }
+        for color in &self.system_palettes {
+            w.u16(*color);
+        }
+        w.bytes(&self.attributes);
+        w.u8(self.mask as u8);
+        w.u8(match self.pending_transfer {
+            None => 0,
+            Some(Transfer::Border(half)) => 1 + half as u8,
+            Some(Transfer::Map) => 3,
+            Some(Transfer::Palettes) => 4,
+        });
+        w.bytes(&self.border_tiles);
+        w.bytes(&self.border_map);
+        for color in self.border_palettes.iter().flatten() {
+            w.u16(*color);
+        }
+        w.bytes(&self.screen);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.players = r.u8()?;
+        self.player = r.u8()?;
+        for color in self.palettes.iter_mut().flatten() {
+            *color = r.u16()?;
+        }
+        for color in self.system_palettes.iter_mut() {
+            *color = r.u16()?;
+        }
+        r.bytes_into(&mut self.attributes)?;
+        self.mask = match r.u8()? {
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => Mask::Cancel,
+        };
+        self.pending_transfer = match r.u8()? {
+            1 => Some(Transfer::Border(0)),
+            2 => Some(Transfer::Border(1)),
+            3 => Some(Transfer::Map),
+            4 => Some(Transfer::Palettes),
+            _ => None,
+        };
+        r.bytes_into(&mut self.border_tiles)?;
+        r.bytes_into(&mut self.border_map)?;
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = r.u16()?;
+        }
+        r.bytes_into(&mut self.screen)?;
+
+        // 受信途中のパケットは捨てる
+        self.receiving = false;
+        self.command.clear();
+        self.render();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 0xFF00への書き込みでパケットを送る (リセット, 128bit, ストップビット)
+    fn send(sgb: &mut Sgb, data: &[u8]) {
+        for packet in data.chunks(16) {
+            let mut writes = vec![0x00, 0x30];
+            for i in 0..128 {
+                let bit = packet.get(i / 8).is_some_and(|byte| byte >> (i % 8) & 0x01 != 0);
+                writes.push(if bit { 0x10 } else { 0x20 });
+                writes.push(0x30);
+            }
+            writes.extend_from_slice(&[0x20, 0x30]);
+            let mut previous = 0x30;
+            for value in writes {
+                sgb.write_joypad(value, previous);
+                previous = value;
+            }
+        }
+    }
+
+    // コマンドと長さ1の先頭バイト
+    fn command(code: u8) -> u8 {
+        code << 3 | 0x01
+    }
+
+    #[test]
+    fn test_palettes() {
+        let colors: [u16; 7] = [0x0001, 0x0102, 0x0203, 0x0304, 0x0405, 0x0506, 0x0607];
+        for (code, first, second) in [(PAL01, 0, 1), (PAL23, 2, 3), (PAL03, 0, 3), (PAL12, 1, 2)] {
+            let mut sgb = Sgb::new();
+            let mut packet = vec![command(code)];
+            for color in colors {
+                packet.extend_from_slice(&color.to_le_bytes());
+            }
+            send(&mut sgb, &packet);
+            assert_eq!(sgb.palettes[first], [colors[0], colors[1], colors[2], colors[3]], "{:02X}", code);
+            assert_eq!(sgb.palettes[second], [colors[0], colors[4], colors[5], colors[6]], "{:02X}", code);
+            // 色0は他のパレットにも入る
+            for palette in (0..4).filter(|&p| p != first && p != second) {
+                assert_eq!(sgb.palettes[palette], [colors[0], DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_attr_blk() {
+        let mut sgb = Sgb::new();
+        // 1つ目: 内側1, 境界線2 (2,3)-(5,7), 2つ目: 外側だけ3 (境界線も3) (0,0)-(9,17)
+        send(&mut sgb, &[command(ATTR_BLK), 2, 0x03, 0x09, 2, 3, 5, 7, 0x04, 0x30, 0, 0, 9, 17]);
+        let at = |x: usize, y: usize| sgb.attributes[y * 20 + x];
+        assert_eq!(at(3, 4), 1);
+        assert_eq!(at(4, 6), 1);
+        assert_eq!(at(2, 3), 2);
+        assert_eq!(at(5, 5), 2);
+        assert_eq!(at(3, 7), 2);
+        // 1つ目の外側は指定なし
+        assert_eq!(at(6, 5), 0);
+        assert_eq!(at(1, 1), 0);
+        // 2つ目の外側と境界線
+        assert_eq!(at(15, 9), 3);
+        assert_eq!(at(9, 5), 3);
+        assert_eq!(at(0, 0), 3);
+    }
+
+    #[test]
+    fn test_attr_lin() {
+        let mut sgb = Sgb::new();
+        // 横の5行目をパレット2, 縦の7列目をパレット3
+        send(&mut sgb, &[command(ATTR_LIN), 2, 0x80 | 2 << 5 | 5, 3 << 5 | 7]);
+        let at = |x: usize, y: usize| sgb.attributes[y * 20 + x];
+        assert_eq!(at(0, 5), 2);
+        assert_eq!(at(19, 5), 2);
+        assert_eq!(at(7, 0), 3);
+        assert_eq!(at(7, 5), 3);
+        assert_eq!(at(7, 17), 3);
+        assert_eq!(at(0, 4), 0);
+        assert_eq!(at(8, 6), 0);
+    }
+
+    #[test]
+    fn test_attr_div() {
+        let mut sgb = Sgb::new();
+        // 横に分ける: 9行目より上は1, 9行目は3, 下は2
+        send(&mut sgb, &[command(ATTR_DIV), 0x40 | 3 << 4 | 1 << 2 | 2, 9]);
+        for y in 0..18 {
+            let expected = match y {
+                0..=8 => 1,
+                9 => 3,
+                _ => 2,
+            };
+            assert!(sgb.attributes[y * 20..y * 20 + 20].iter().all(|&p| p == expected), "line {}", y);
+        }
+
+        // 縦に分ける
+        send(&mut sgb, &[command(ATTR_DIV), 1 << 4 | 2 << 2 | 3, 0]);
+        assert_eq!(sgb.attributes[5 * 20], 1);
+        assert_eq!(sgb.attributes[5 * 20 + 1], 3);
+    }
+
+    #[test]
+    fn test_attr_chr() {
+        let mut sgb = Sgb::new();
+        // (18, 0) から横に4つ: 行の終わりで次の行へ
+        send(&mut sgb, &[command(ATTR_CHR), 18, 0, 4, 0, 0, 0b00_01_10_11]);
+        assert_eq!(&sgb.attributes[18..22], [0, 1, 2, 3]);
+
+        // 縦に: (5, 16) から下へ, 列の終わりで次の列へ
+        send(&mut sgb, &[command(ATTR_CHR), 5, 16, 3, 0, 1, 0b11_10_01_00]);
+        assert_eq!(sgb.attributes[16 * 20 + 5], 3);
+        assert_eq!(sgb.attributes[17 * 20 + 5], 2);
+        assert_eq!(sgb.attributes[6], 1);
+    }
+
+    // 複数パケットのコマンドは全部揃ってから実行する
+    #[test]
+    fn test_multi_packet() {
+        let mut sgb = Sgb::new();
+        let mut data = vec![ATTR_LIN << 3 | 0x02, 16];
+        data.extend((0..16).map(|line| 0x80 | 1 << 5 | line));
+        send(&mut sgb, &data[..16]);
+        assert_eq!(sgb.attributes[0], 0);
+        send(&mut sgb, &data[16..]);
+        assert_eq!(sgb.attributes[0], 1);
+        assert_eq!(sgb.attributes[15 * 20], 1);
+        assert_eq!(sgb.attributes[16 * 20], 0);
+    }
+
+    #[test]
+    fn test_mask_en() {
+        let mut sgb = Sgb::new();
+        for (value, mask) in [(1, Mask::Freeze), (2, Mask::Black), (3, Mask::Color0), (0, Mask::Cancel)] {
+            send(&mut sgb, &[command(MASK_EN), value]);
+            assert!(sgb.mask == mask, "{}", value);
+        }
+    }
+
+    // MLT_REQ: P15の立ち上がりごとに次のコントローラーになる
+    #[test]
+    fn test_mlt_req() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.joypad_id(), None);
+        send(&mut sgb, &[command(MLT_REQ), 0x03]);
+        assert_eq!(sgb.joypad_id(), Some(0x0F));
+        for id in [0x0E, 0x0D, 0x0C, 0x0F] {
+            sgb.write_joypad(0x10, 0x30);
+            sgb.write_joypad(0x30, 0x10);
+            assert_eq!(sgb.joypad_id(), Some(id));
+        }
+        // P14だけでは切り替わらない
+        sgb.write_joypad(0x20, 0x30);
+        sgb.write_joypad(0x30, 0x20);
+        assert_eq!(sgb.joypad_id(), Some(0x0F));
+
+        send(&mut sgb, &[command(MLT_REQ), 0x00]);
+        assert_eq!(sgb.joypad_id(), None);
+    }
+
+    // 画面に並べたタイル (番号0-255) をVBlankで取り込む
+    fn transfer_gpu() -> GPU {
+        let mut gpu = GPU::new();
+        gpu.control = crate::gpu::LcdControlregisters::from(0x91);
+        for i in 0..0x1000 {
+            gpu.write_vram(i, (i * 7 + i / 256) as u8);
+        }
+        for i in 0..256 {
+            gpu.write_vram(0x1800 + (i / 20) * 32 + i % 20, i as u8);
+        }
+        gpu
+    }
+
+    #[test]
+    fn test_chr_trn_pct_trn() {
+        let gpu = transfer_gpu();
+        let data = gpu.screen_tile_data();
+        assert_eq!(&data[..0x1000], &(0..0x1000).map(|i| (i * 7 + i / 256) as u8).collect::<Vec<_>>()[..]);
+
+        let mut sgb = Sgb::new();
+        // タイル0x80-0xFF
+        send(&mut sgb, &[command(CHR_TRN), 0x01]);
+        sgb.vblank(&gpu);
+        assert!(sgb.border_tiles[..0x1000].iter().all(|&byte| byte == 0));
+        assert_eq!(&sgb.border_tiles[0x1000..], &data[..]);
+        // 転送は1回だけ
+        assert!(sgb.pending_transfer.is_none());
+
+        send(&mut sgb, &[command(PCT_TRN)]);
+        sgb.vblank(&gpu);
+        assert_eq!(&sgb.border_map[..], &data[..32 * 28 * 2]);
+        let color = |o: usize| data[o] as u16 | (data[o + 1] as u16) << 8;
+        assert_eq!(sgb.border_palettes[0][0], color(0x800));
+        assert_eq!(sgb.border_palettes[3][15], color(0x800 + (3 * 16 + 15) * 2));
+    }
+}
@@ -0,0 +1,190 @@
+// セーブステートのバイナリ形式
+// ヘッダ: マジック, 形式のバージョン, エミュレータのバージョン, ROMのチェックサム, 機種
+// 以降は各部品が決まった順番で書き込む (リトルエンディアン)
+
+pub const MAGIC: &[u8; 4] = b"GBST";
+pub const FORMAT_VERSION: u32 = 3;
+pub const EMULATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // 長さ付きのバイト列
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.position + length > self.data.len() {
+            return Err(String::from("save state is truncated"));
+        }
+        let slice = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    // 固定長の配列へ読み込む (長さが違えば互換性のない状態)
+    pub fn bytes_into(&mut self, target: &mut [u8]) -> Result<(), String> {
+        let data = self.bytes()?;
+        if data.len() != target.len() {
+            return Err(format!("save state field has {} bytes, expected {}", data.len(), target.len()));
+        }
+        target.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+// ROMの識別用チェックサム (FNV-1a)
+pub fn rom_checksum(rom: &[u8]) -> u32 {
+    rom.iter().fold(0x811C9DC5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+pub fn write_header(w: &mut StateWriter, checksum: u32, model: u8) {
+    w.data.extend_from_slice(MAGIC);
+    w.u32(FORMAT_VERSION);
+    w.bytes(EMULATOR_VERSION.as_bytes());
+    w.u32(checksum);
+    w.u8(model);
+}
+
+pub fn read_header(r: &mut StateReader, checksum: u32, model: u8) -> Result<(), String> {
+    if r.take(4)? != MAGIC {
+        return Err(String::from("not a save state"));
+    }
+    let version = r.u32()?;
+    let emulator = String::from_utf8_lossy(r.bytes()?).into_owned();
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "save state format {} (emulator {}) is not supported, expected format {}",
+            version, emulator, FORMAT_VERSION
+        ));
+    }
+    if r.u32()? != checksum {
+        return Err(String::from("save state was made with a different rom"));
+    }
+    if r.u8()? != model {
+        return Err(String::from("save state was made with a different model"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        let mut w = StateWriter::new();
+        write_header(&mut w, 0x12345678, 2);
+        w.data
+    }
+
+    #[test]
+    fn test_read_header() {
+        let data = header();
+        assert!(read_header(&mut StateReader::new(&data), 0x12345678, 2).is_ok());
+
+        let error = read_header(&mut StateReader::new(&data), 0x12345679, 2).unwrap_err();
+        assert!(error.contains("different rom"), "{}", error);
+        let error = read_header(&mut StateReader::new(&data), 0x12345678, 3).unwrap_err();
+        assert!(error.contains("different model"), "{}", error);
+
+        let mut old = data.clone();
+        old[4..8].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        let error = read_header(&mut StateReader::new(&old), 0x12345678, 2).unwrap_err();
+        assert!(error.contains("not supported"), "{}", error);
+
+        let mut broken = data.clone();
+        broken[0] = b'X';
+        let error = read_header(&mut StateReader::new(&broken), 0x12345678, 2).unwrap_err();
+        assert_eq!(error, "not a save state");
+        let error = read_header(&mut StateReader::new(&data[..data.len() - 1]), 0x12345678, 2).unwrap_err();
+        assert_eq!(error, "save state is truncated");
+    }
+
+    #[test]
+    fn test_reader() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789ABCDE);
+        w.u64(0x0123456789ABCDEF);
+        w.bytes(&[1, 2, 3]);
+        let mut r = StateReader::new(&w.data);
+        assert_eq!(r.u8(), Ok(0x12));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0x3456));
+        assert_eq!(r.u32(), Ok(0x789ABCDE));
+        assert_eq!(r.u64(), Ok(0x0123456789ABCDEF));
+        let mut target = [0; 3];
+        assert_eq!(r.bytes_into(&mut target), Ok(()));
+        assert_eq!(target, [1, 2, 3]);
+        assert!(r.u8().is_err());
+
+        // 長さの違う配列には読み込まない
+        assert!(StateReader::new(&w.data[16..]).bytes_into(&mut [0; 2]).is_err());
+    }
+}
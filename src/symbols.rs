@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+// シンボルファイル (RGBDSの.symと.map, no$gmb形式の.sym)
+// バンク:アドレスをキーにして名前を引く
+
+#[derive(Clone, Default)]
+pub struct Symbols {
+    names: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    // 読み込んだシンボルの数を返す
+    // .sym: "02:4000 Name" (;以降はコメント)
+    // .map: "ROMX bank #2:" の後の "$4000 = Name"
+    pub fn load(&mut self, path: &str) -> Result<usize, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let hex = |text: &str| u16::from_str_radix(text.trim().trim_start_matches('$'), 16).ok();
+        let mut count = 0;
+        let mut bank = 0;
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            if let Some(i) = line.to_ascii_lowercase().find("bank #") {
+                let digits: String = line[i + 6..].chars().take_while(|c| c.is_ascii_digit()).collect();
+                bank = digits.parse().unwrap_or(0);
+                continue;
+            }
+            let symbol = match line.split_once(" = ") {
+                Some((address, name)) if address.starts_with('$') => hex(address).map(|a| (bank, a, name)),
+                Some(_) => None,
+                None => {
+                    let mut words = line.split_whitespace();
+                    match (words.next().and_then(|w| w.split_once(':')), words.next()) {
+                        (Some((b, a)), Some(name)) => hex(b).zip(hex(a)).map(|(b, a)| (b, a, name)),
+                        _ => None,
+                    }
+                }
+            };
+            if let Some((bank, address, name)) = symbol {
+                self.insert(bank, address, name.trim());
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn insert(&mut self, bank: u16, address: u16, name: &str) {
+        // 同じ場所に複数あれば最初のものを表示に使う
+        self.names.entry((bank, address)).or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), (bank, address));
+    }
+
+    pub fn lookup(&self, bank: u16, address: u16) -> Option<&str> {
+        self.names.get(&(bank, address)).map(|name| name.as_str())
+    }
+
+    // 名前から (バンク, アドレス)
+    pub fn address_of(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).copied()
+    }
+
+    // 同じメモリ領域で直前にあるシンボル: "Name" か "Name+$12"
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        let ((_, start), name) = self
+            .names
+            .range((bank, region_start(address))..=(bank, address))
+            .next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+${:X}", name, offset),
+        })
+    }
+}
+
+// ROM0, ROMX, VRAM, SRAM, WRAM0, WRAMX, HRAMをまたいで探さない
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xFF80..=0xFFFE => 0xFF80,
+        _ => address,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(name: &str, text: &str) -> (Symbols, usize) {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        let mut symbols = Symbols::new();
+        let count = symbols.load(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+        (symbols, count)
+    }
+
+    #[test]
+    fn test_rgbds_sym() {
+        let (symbols, count) = load(
+            "game.sym",
+            "; File generated by rgblink\n\
+             00:0150 Main\n\
+             00:0150 Start\n\
+             02:4000 Bank2Func ; comment\n\
+             00:c000 wBuffer\n\
+             broken line\n",
+        );
+        assert_eq!(count, 4);
+        // 同じ場所は最初のものを表示に使う
+        assert_eq!(symbols.lookup(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.address_of("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup(2, 0x4000), Some("Bank2Func"));
+        assert_eq!(symbols.lookup(1, 0x4000), None);
+        assert_eq!(symbols.describe(2, 0x4010), Some(String::from("Bank2Func+$10")));
+        assert_eq!(symbols.describe(0, 0xC0FF), Some(String::from("wBuffer+$FF")));
+        // 別のメモリ領域のシンボルは使わない
+        assert_eq!(symbols.describe(0, 0x4000), None);
+        assert_eq!(symbols.describe(0, 0x0100), None);
+    }
+
+    #[test]
+    fn test_nogmb_sym() {
+        // no$gmb: [labels]の見出しとバンク4桁
+        let (symbols, count) = load("game_nogmb.sym", "[labels]\n0000:0150 Main\n0003:4567 Func\n");
+        assert_eq!(count, 2);
+        assert_eq!(symbols.lookup(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.address_of("Func"), Some((3, 0x4567)));
+    }
+
+    #[test]
+    fn test_map() {
+        let (symbols, count) = load(
+            "game.map",
+            "ROM0 bank #0:\n\
+             \x20 SECTION: $0000-$014f ($0150 bytes) [\"Header\"]\n\
+             \x20          $0150 = Main\n\
+             \x20   EMPTY: $3e00 bytes\n\
+             \n\
+             ROMX bank #12:\n\
+             \x20 SECTION: $4000-$40ff ($0100 bytes) [\"Bank12\"]\n\
+             \x20          $4000 = Bank12Func\n\
+             \x20          $4080 = Bank12Data\n\
+             \n\
+             WRAM0 bank #0:\n\
+             \x20          $c000 = wBuffer\n",
+        );
+        assert_eq!(count, 4);
+        assert_eq!(symbols.address_of("Main"), Some((0, 0x0150)));
+        // バンク番号は10進
+        assert_eq!(symbols.address_of("Bank12Func"), Some((12, 0x4000)));
+        assert_eq!(symbols.describe(12, 0x4081), Some(String::from("Bank12Data+$1")));
+        assert_eq!(symbols.address_of("wBuffer"), Some((0, 0xC000)));
+        assert_eq!(symbols.address_of("Header"), None);
+    }
+}
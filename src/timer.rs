@@ -0,0 +1,117 @@
+use crate::state::{StateReader, StateWriter};
+
+// DIV/TIMA/TMA/TAC (0xFF04-0xFF07)
+// CPUのクロックで動くので、倍速モードでは実時間に対して2倍の速さで進む
+pub struct Timer {
+    pub counter: u16, // 内部カウンタ (上位8bitがDIV)
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+        }
+    }
+
+    // TACで選ばれたカウンタのbit (これが1→0になるとTIMAが進む), 止まっていればNone
+    fn selected(&self) -> Option<u32> {
+        if self.tac & 0x04 == 0 {
+            return None;
+        }
+        Some(match self.tac & 0x03 {
+            0x00 => 9,
+            0x01 => 3,
+            0x02 => 5,
+            _ => 7,
+        })
+    }
+
+    fn selected_bit(&self) -> bool {
+        match self.selected() {
+            Some(bit) => self.counter >> bit & 0x01 != 0,
+            None => false,
+        }
+    }
+
+    fn increment(&mut self) -> bool {
+        let (value, overflow) = self.tima.overflowing_add(1);
+        self.tima = if overflow { self.tma } else { value };
+        overflow
+    }
+
+    // 割り込みが発生したらtrueを返す
+    // 立ち下がりはカウンタが2^(bit+1)の倍数をまたぐ回数なので, まとめて数える
+    pub fn update(&mut self, cycles: u64) -> bool {
+        let before = self.counter as u64;
+        let after = before + cycles;
+        self.counter = after as u16;
+        let bit = match self.selected() {
+            Some(bit) => bit,
+            None => return false,
+        };
+        let mut edges = (after >> (bit + 1)) - (before >> (bit + 1));
+        let mut interrupt = false;
+        while edges > 0 {
+            // オーバーフローするまでの分を一度に進める
+            let step = edges.min(0x100 - self.tima as u64);
+            self.tima = (self.tima as u64 + step - 1) as u8;
+            interrupt |= self.increment();
+            edges -= step;
+        }
+        interrupt
+    }
+
+    // 次にTIMAがオーバーフローするまでのサイクル数 (止まっていればNone)
+    pub fn cycles_until_overflow(&self) -> Option<u64> {
+        let period = 1u64 << (self.selected()? + 1);
+        let first = period - (self.counter as u64 & (period - 1));
+        Some(first + (0xFF - self.tima as u64) * period)
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: usize, value: u8) -> bool {
+        let before = self.selected_bit();
+        match address {
+            0xFF04 => self.counter = 0,
+            0xFF05 => self.tima = value,
+            0xFF06 => self.tma = value,
+            0xFF07 => self.tac = value & 0x07,
+            _ => {}
+        }
+        // DIVのリセットやTACの変更でも立ち下がりが起きる
+        if before && !self.selected_bit() {
+            return self.increment();
+        }
+        false
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.counter = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0x07;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::cpu::CPU;
+use crate::symbols::Symbols;
+
+// 実行した命令を1行ずつ書き出す (gameboy-doctorの形式)
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+// シンボルがあれば行末に " ; Name+$3" を付ける (gameboy-doctorと比べるときは付けない)
+
+pub struct Trace {
+    writer: BufWriter<File>,
+    range: Option<(u16, u16)>, // このPCの範囲だけ記録する
+    limit: Option<u64>,        // 最大行数
+    lines: u64,
+    pub symbols: Symbols,
+}
+
+impl Trace {
+    pub fn new(path: &str, range: Option<(u16, u16)>, limit: Option<u64>) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Trace {
+            writer: BufWriter::new(file),
+            range,
+            limit,
+            lines: 0,
+            symbols: Symbols::new(),
+        })
+    }
+
+    // 命令を実行する前に呼ぶ
+    pub fn log(&mut self, cpu: &CPU) {
+        if self.limit.is_some_and(|limit| self.lines >= limit) {
+            return;
+        }
+        let pc = cpu.pc;
+        if self.range.is_some_and(|(start, end)| pc < start || pc > end) {
+            return;
+        }
+
+        let register = |name: &str| cpu.register(name).unwrap_or(0);
+        let memory = |i: u16| cpu.bus.peek(pc.wrapping_add(i));
+        let label = match self.symbols.describe(cpu.bus.bank(pc), pc) {
+            Some(name) => format!(" ; {}", name),
+            None => String::new(),
+        };
+        let result = writeln!(
+            self.writer,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}{}",
+            register("A"),
+            register("F"),
+            register("B"),
+            register("C"),
+            register("D"),
+            register("E"),
+            register("H"),
+            register("L"),
+            cpu.sp,
+            pc,
+            memory(0),
+            memory(1),
+            memory(2),
+            memory(3),
+            label
+        );
+        if let Err(e) = result {
+            eprintln!("trace: {}", e);
+            self.limit = Some(self.lines);
+            return;
+        }
+        self.lines += 1;
+        if self.limit == Some(self.lines) {
+            self.flush();
+            println!("trace: wrote {} lines", self.lines);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            eprintln!("trace: {}", e);
+        }
+    }
+}
+
+// --trace-rangeの "START-END" (16進)
+pub fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let number = |text: &str| u16::from_str_radix(text.trim_start_matches("0x").trim_start_matches('$'), 16);
+    let (start, end) = text.split_once('-').ok_or_else(|| format!("invalid range: {} (START-END)", text))?;
+    match (number(start), number(end)) {
+        (Ok(start), Ok(end)) if start <= end => Ok((start, end)),
+        _ => Err(format!("invalid range: {} (START-END)", text)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::model::Model;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    // 起動直後のDMGでgameboy-doctorの最初の行と同じになる
+    #[test]
+    fn test_doctor_format() {
+        let mut raw = vec![0; 0x8000];
+        raw[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x13, 0x02]);
+        raw[0x014D] = 0x01; // ヘッダのチェックサムが0でなければF=B0
+        let mut cpu = CPU::new(Cartridge::from_bytes(raw).unwrap(), Model::DMG, None);
+
+        let path = temp_path("trace.log");
+        let symbols_path = temp_path("trace.sym");
+        let mut trace = Trace::new(&path, None, Some(3)).unwrap();
+        trace.log(&cpu);
+        cpu.step();
+        // シンボルがあれば行末に付ける
+        std::fs::write(&symbols_path, "00:0100 Entry\n").unwrap();
+        trace.symbols.load(&symbols_path).unwrap();
+        trace.log(&cpu);
+        trace.flush();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&symbols_path).unwrap();
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,00 ; Entry+$1",
+        ]);
+    }
+
+    #[test]
+    fn test_range_and_limit() {
+        let cpu = CPU::new(Cartridge::from_bytes(vec![0; 0x8000]).unwrap(), Model::DMG, None);
+        let path = temp_path("trace_range.log");
+        let mut trace = Trace::new(&path, Some((0x0150, 0x01FF)), Some(2)).unwrap();
+        // PC=0100は範囲外
+        trace.log(&cpu);
+        assert_eq!(trace.lines, 0);
+        trace.range = None;
+        for _ in 0..5 {
+            trace.log(&cpu);
+        }
+        assert_eq!(trace.lines, 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(parse_range("0150-01FF"), Ok((0x0150, 0x01FF)));
+        assert_eq!(parse_range("$4000-0x7FFF"), Ok((0x4000, 0x7FFF)));
+        assert!(parse_range("0200-0100").is_err());
+        assert!(parse_range("0150").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use sdl2::VideoSubsystem;
+
+use crate::gpu::GPU;
+
+// デバッグ用の追加ウィンドウ (タイル, BGマップ, OAM, パレット)
+// 毎フレームGPUの状態から描き直す
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Tiles,
+    Maps,
+    Objects,
+    Palettes,
+}
+
+impl Kind {
+    // --viewerの引数 (allなら全部)
+    pub fn from_name(name: &str) -> Option<Vec<Kind>> {
+        let kinds = match name.to_ascii_lowercase().as_str() {
+            "tiles" => vec![Kind::Tiles],
+            "maps" => vec![Kind::Maps],
+            "oam" => vec![Kind::Objects],
+            "palettes" => vec![Kind::Palettes],
+            "all" => vec![Kind::Tiles, Kind::Maps, Kind::Objects, Kind::Palettes],
+            _ => return None,
+        };
+        Some(kinds)
+    }
+}
+
+// OAMの表示: 1列20個で2列, 各行はオブジェクト (8x16) と "Y X タイル 属性" の16進
+const OBJECT_ROW_HEIGHT: usize = 18;
+const OBJECT_COLUMN_WIDTH: usize = 64;
+
+// パレットの表示: 左がBG, 右がOBJで8本ずつ, 1色8x8
+const SWATCH_SIZE: usize = 8;
+const PALETTE_COLUMN_WIDTH: usize = SWATCH_SIZE * 4 + 8;
+
+const BACKGROUND: [u8; 3] = [96, 96, 128];
+
+struct View<'a> {
+    kind: Kind,
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    width: usize,
+    height: usize,
+}
+
+pub struct Viewers<'a> {
+    views: Vec<View<'a>>,
+}
+
+impl<'a> Viewers<'a> {
+    // テクスチャはウィンドウごとに1回だけ作る (作成元はcreatorsに置き, 呼び出し側で持っておく)
+    pub fn new(
+        video: &VideoSubsystem,
+        kinds: &[Kind],
+        cgb_mode: bool,
+        creators: &'a mut Vec<TextureCreator<WindowContext>>,
+    ) -> Result<Self, String> {
+        let start = creators.len();
+        let mut windows: Vec<(Kind, Canvas<Window>, usize, usize)> = Vec::new();
+        for &kind in kinds {
+            if windows.iter().any(|&(k, ..)| k == kind) {
+                continue;
+            }
+            let (title, width, height, scale) = match kind {
+                Kind::Tiles => ("Tiles", if cgb_mode { 256 } else { 128 }, 192, 2),
+                Kind::Maps => ("BG maps (9800 / 9C00)", 256 * 2 + 8, 256, 2),
+                Kind::Objects => ("OAM (Y X tile flags)", OBJECT_COLUMN_WIDTH * 2, OBJECT_ROW_HEIGHT * 20, 2),
+                Kind::Palettes => ("Palettes (BG / OBJ)", PALETTE_COLUMN_WIDTH * 2, (SWATCH_SIZE + 2) * 8, 4),
+            };
+            let window = video
+                .window(title, (width * scale) as u32, (height * scale) as u32)
+                .build()
+                .map_err(|e| e.to_string())?;
+            let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+            canvas.set_scale(scale as f32, scale as f32)?;
+            creators.push(canvas.texture_creator());
+            windows.push((kind, canvas, width, height));
+        }
+
+        let creators: &'a [TextureCreator<WindowContext>] = creators;
+        let mut views = Vec::new();
+        for ((kind, canvas, width, height), creator) in windows.into_iter().zip(&creators[start..]) {
+            let texture = creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+                .map_err(|e| e.to_string())?;
+            views.push(View {
+                kind,
+                canvas,
+                texture,
+                width,
+                height,
+            });
+        }
+        Ok(Viewers { views })
+    }
+
+    // ウィンドウが閉じられたら表示をやめる
+    // テクスチャの作成元がウィンドウを持ち続けるので, 終了までは隠しておく
+    pub fn close(&mut self, window_id: u32) {
+        for view in self.views.iter_mut().filter(|v| v.canvas.window().id() == window_id) {
+            view.canvas.window_mut().hide();
+        }
+        self.views.retain(|v| v.canvas.window().id() != window_id);
+    }
+
+    pub fn update(&mut self, gpu: &GPU) {
+        for view in &mut self.views {
+            let mut image = Image::new(view.width, view.height);
+            match view.kind {
+                Kind::Tiles => {
+                    let (data, _) = gpu.render_tiles();
+                    image.data = data;
+                }
+                Kind::Maps => {
+                    for (i, map) in [0x1800, 0x1C00].into_iter().enumerate() {
+                        let data = gpu.render_bg_map(map);
+                        for y in 0..256 {
+                            for x in 0..256 {
+                                let o = (y * 256 + x) * 3;
+                                image.set(i * (256 + 8) + x, y, [data[o], data[o + 1], data[o + 2]]);
+                            }
+                        }
+                    }
+                }
+                Kind::Objects => draw_objects(&mut image, gpu),
+                Kind::Palettes => draw_palettes(&mut image, gpu),
+            }
+
+            view.texture.update(None, &image.data, view.width * 3).unwrap();
+            view.canvas.copy(&view.texture, None, None).unwrap();
+            view.canvas.present();
+        }
+    }
+}
+
+struct Image {
+    data: Vec<u8>,
+    width: usize,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        let data = BACKGROUND.repeat(width * height);
+        Image { data, width }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let o = (y * self.width + x) * 3;
+        self.data[o..o + 3].copy_from_slice(&color);
+    }
+
+    fn fill(&mut self, left: usize, top: usize, width: usize, height: usize, color: [u8; 3]) {
+        for y in top..top + height {
+            for x in left..left + width {
+                self.set(x, y, color);
+            }
+        }
+    }
+
+    // 3x5ドットの16進数字 (空白は飛ばす)
+    fn text(&mut self, left: usize, top: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match c.to_digit(16) {
+                Some(digit) => FONT[digit as usize],
+                None => continue,
+            };
+            for (y, row) in glyph.iter().enumerate() {
+                for x in 0..3 {
+                    if row & (4 >> x) != 0 {
+                        self.set(left + i * 4 + x, top + y, [255, 255, 255]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+const FONT: [[u8; 5]; 16] = [
+    [7, 5, 5, 5, 7], [2, 6, 2, 2, 7], [7, 1, 7, 4, 7], [7, 1, 7, 1, 7],
+    [5, 5, 7, 1, 1], [7, 4, 7, 1, 7], [7, 4, 7, 5, 7], [7, 1, 1, 1, 1],
+    [7, 5, 7, 5, 7], [7, 5, 7, 1, 7], [7, 5, 7, 5, 5], [6, 5, 6, 5, 6],
+    [7, 4, 4, 4, 7], [6, 5, 5, 5, 6], [7, 4, 7, 4, 7], [7, 4, 7, 4, 4],
+];
+
+fn draw_objects(image: &mut Image, gpu: &GPU) {
+    for i in 0..40 {
+        let left = (i / 20) * OBJECT_COLUMN_WIDTH + 1;
+        let top = (i % 20) * OBJECT_ROW_HEIGHT + 1;
+        for (y, row) in gpu.render_object(i).iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                if let Some(color) = pixel {
+                    image.set(left + x, top + y, *color);
+                }
+            }
+        }
+        let entry: Vec<String> = (0..4).map(|j| format!("{:02X}", gpu.read_oam(i * 4 + j))).collect();
+        image.text(left + 11, top + 5, &entry.join(" "));
+    }
+}
+
+fn draw_palettes(image: &mut Image, gpu: &GPU) {
+    for (column, obj) in [false, true].into_iter().enumerate() {
+        for palette in 0..8 {
+            let colors = match gpu.palette_colors(obj, palette) {
+                Some(colors) => colors,
+                None => continue,
+            };
+            for (i, color) in colors.into_iter().enumerate() {
+                let left = column * PALETTE_COLUMN_WIDTH + i * SWATCH_SIZE;
+                image.fill(left, palette * (SWATCH_SIZE + 2), SWATCH_SIZE, SWATCH_SIZE, color);
+            }
+        }
+    }
+}